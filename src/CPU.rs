@@ -1,12 +1,25 @@
+#![allow(clippy::upper_case_acronyms, clippy::enum_variant_names)]
+
+use std::io::Write;
+
+use crate::memory_bus::{Bus, MemoryBus};
+use crate::model::Model;
 
 const ZERO_FLAG_BYTE_POSITION: u8 = 7;
 const SUBTRACT_FLAG_BYTE_POSITION: u8 = 6;
 const HALF_CARRY_FLAG_BYTE_POSITION: u8 = 5;
 const CARRY_FLAG_BYTE_POSITION: u8 = 4;
 
-enum Instruction {
+/* Number of interrupt sources wired into IE and IF. */
+const INTERRUPT_COUNT: u8 = 5;
+
+/* M-cycles the CPU sits idle after STOP switches speed (8200 T-cycles). */
+const SPEED_SWITCH_CYCLES: u32 = 2050;
+
+pub enum Instruction {
     ADD(ArithmeticTarget),
     ADDHL(ArithmeticTarget),
+    ADDSP,
     ADC(ArithmeticTarget),
     SUB(ArithmeticTarget),
     SBC(ArithmeticTarget),
@@ -21,46 +34,333 @@ enum Instruction {
     RRA,
     RLA,
     RRCA,
-    RRLA,
+    RLCA,
     CPL,
+    DAA,
     BIT(u8, ArithmeticTarget),
     RESET(u8, ArithmeticTarget),
     SET(u8, ArithmeticTarget),
     SRL(ArithmeticTarget),
+    RR(ArithmeticTarget),
+    RL(ArithmeticTarget),
+    RRC(ArithmeticTarget),
+    RLC(ArithmeticTarget),
+    SRA(ArithmeticTarget),
+    SLA(ArithmeticTarget),
+    SWAP(ArithmeticTarget),
+    JP(JumpTest),
+    JPI,
+    JR(JumpTest),
+    LD(LoadType),
+    PUSH(StackTarget),
+    POP(StackTarget),
+    CALL(JumpTest),
+    RET(JumpTest),
+    RETI,
+    RST(u16),
+    NOP,
+    HALT,
+    STOP,
+    DI,
+    EI,
+}
+
+/*
+Operand of an 8 bit ALU, rotate or bit instruction, or of a 16 bit
+INC/DEC/ADD. HLI is the byte at (HL) and D8 is the immediate byte.
+*/
+#[derive(Copy, Clone)]
+pub enum ArithmeticTarget{
+    A, B, C, D, E, H, L, HLI, D8, BC, DE, HL, SP
+}
+
+#[derive(Copy, Clone)]
+pub enum JumpTest {
+    NotZero,
+    Zero,
+    NotCarry,
+    Carry,
+    Always,
+}
 
+#[derive(Copy, Clone)]
+pub enum LoadByteTarget {
+    A, B, C, D, E, H, L, HLI
+}
+
+#[derive(Copy, Clone)]
+pub enum LoadByteSource {
+    A, B, C, D, E, H, L, D8, HLI
+}
+
+#[derive(Copy, Clone)]
+pub enum LoadWordTarget {
+    BC, DE, HL, SP
+}
+
+/* Memory operands of LD A,(x) and LD (x),A. */
+#[derive(Copy, Clone)]
+pub enum Indirect {
+    BCIndirect,
+    DEIndirect,
+    HLIndirectPlus,
+    HLIndirectMinus,
+    WordIndirect,
+    LastByteIndirect,
+    ByteIndirect,
+}
 
+#[derive(Copy, Clone)]
+pub enum LoadType {
+    Byte(LoadByteTarget, LoadByteSource),
+    Word(LoadWordTarget),
+    AFromIndirect(Indirect),
+    IndirectFromA(Indirect),
+    SPFromHL,
+    HLFromSPN,
+    IndirectFromSP,
+}
 
+#[derive(Copy, Clone)]
+pub enum StackTarget {
+    AF, BC, DE, HL
 }
 
-enum ArithmeticTarget{
-    A, B, C, D, E, H, L, BC, DE, HL 
+/* Register operands in the order the opcode bit fields encode them. */
+const REGISTER_TARGETS: [ArithmeticTarget; 8] = [
+    ArithmeticTarget::B,
+    ArithmeticTarget::C,
+    ArithmeticTarget::D,
+    ArithmeticTarget::E,
+    ArithmeticTarget::H,
+    ArithmeticTarget::L,
+    ArithmeticTarget::HLI,
+    ArithmeticTarget::A,
+];
+
+const LOAD_TARGETS: [LoadByteTarget; 8] = [
+    LoadByteTarget::B,
+    LoadByteTarget::C,
+    LoadByteTarget::D,
+    LoadByteTarget::E,
+    LoadByteTarget::H,
+    LoadByteTarget::L,
+    LoadByteTarget::HLI,
+    LoadByteTarget::A,
+];
+
+const LOAD_SOURCES: [LoadByteSource; 8] = [
+    LoadByteSource::B,
+    LoadByteSource::C,
+    LoadByteSource::D,
+    LoadByteSource::E,
+    LoadByteSource::H,
+    LoadByteSource::L,
+    LoadByteSource::HLI,
+    LoadByteSource::A,
+];
+
+impl Instruction {
+    pub fn from_byte(byte: u8, prefixed: bool) -> Option<Instruction> {
+        if prefixed {
+            Instruction::from_byte_prefixed(byte)
+        } else {
+            Instruction::from_byte_not_prefixed(byte)
+        }
+    }
+
+    /* CB prefixed opcodes are fully regular: operation in bits 3-7, operand in bits 0-2. */
+    fn from_byte_prefixed(byte: u8) -> Option<Instruction> {
+        let target = REGISTER_TARGETS[(byte & 0x07) as usize];
+        let index = (byte >> 3) & 0x07;
+
+        let instruction = match byte >> 3 {
+            0x00 => Instruction::RLC(target),
+            0x01 => Instruction::RRC(target),
+            0x02 => Instruction::RL(target),
+            0x03 => Instruction::RR(target),
+            0x04 => Instruction::SLA(target),
+            0x05 => Instruction::SRA(target),
+            0x06 => Instruction::SWAP(target),
+            0x07 => Instruction::SRL(target),
+            0x08..=0x0F => Instruction::BIT(index, target),
+            0x10..=0x17 => Instruction::RESET(index, target),
+            _ => Instruction::SET(index, target),
+        };
+        Some(instruction)
+    }
+
+    fn from_byte_not_prefixed(byte: u8) -> Option<Instruction> {
+        let register = REGISTER_TARGETS[(byte & 0x07) as usize];
+
+        let instruction = match byte {
+            0x00 => Instruction::NOP,
+            0x10 => Instruction::STOP,
+            0x76 => Instruction::HALT,
+            0xF3 => Instruction::DI,
+            0xFB => Instruction::EI,
+
+            0x01 => Instruction::LD(LoadType::Word(LoadWordTarget::BC)),
+            0x11 => Instruction::LD(LoadType::Word(LoadWordTarget::DE)),
+            0x21 => Instruction::LD(LoadType::Word(LoadWordTarget::HL)),
+            0x31 => Instruction::LD(LoadType::Word(LoadWordTarget::SP)),
+
+            0x02 => Instruction::LD(LoadType::IndirectFromA(Indirect::BCIndirect)),
+            0x12 => Instruction::LD(LoadType::IndirectFromA(Indirect::DEIndirect)),
+            0x22 => Instruction::LD(LoadType::IndirectFromA(Indirect::HLIndirectPlus)),
+            0x32 => Instruction::LD(LoadType::IndirectFromA(Indirect::HLIndirectMinus)),
+            0x0A => Instruction::LD(LoadType::AFromIndirect(Indirect::BCIndirect)),
+            0x1A => Instruction::LD(LoadType::AFromIndirect(Indirect::DEIndirect)),
+            0x2A => Instruction::LD(LoadType::AFromIndirect(Indirect::HLIndirectPlus)),
+            0x3A => Instruction::LD(LoadType::AFromIndirect(Indirect::HLIndirectMinus)),
+
+            0xE0 => Instruction::LD(LoadType::IndirectFromA(Indirect::ByteIndirect)),
+            0xF0 => Instruction::LD(LoadType::AFromIndirect(Indirect::ByteIndirect)),
+            0xE2 => Instruction::LD(LoadType::IndirectFromA(Indirect::LastByteIndirect)),
+            0xF2 => Instruction::LD(LoadType::AFromIndirect(Indirect::LastByteIndirect)),
+            0xEA => Instruction::LD(LoadType::IndirectFromA(Indirect::WordIndirect)),
+            0xFA => Instruction::LD(LoadType::AFromIndirect(Indirect::WordIndirect)),
+
+            0x08 => Instruction::LD(LoadType::IndirectFromSP),
+            0xF8 => Instruction::LD(LoadType::HLFromSPN),
+            0xF9 => Instruction::LD(LoadType::SPFromHL),
+
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => {
+                let target = LOAD_TARGETS[(byte >> 3) as usize];
+                Instruction::LD(LoadType::Byte(target, LoadByteSource::D8))
+            }
+            0x40..=0x7F => {
+                let target = LOAD_TARGETS[((byte >> 3) & 0x07) as usize];
+                let source = LOAD_SOURCES[(byte & 0x07) as usize];
+                Instruction::LD(LoadType::Byte(target, source))
+            }
+
+            0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => {
+                Instruction::INC(REGISTER_TARGETS[(byte >> 3) as usize])
+            }
+            0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => {
+                Instruction::DEC(REGISTER_TARGETS[(byte >> 3) as usize])
+            }
+            0x03 => Instruction::INC(ArithmeticTarget::BC),
+            0x13 => Instruction::INC(ArithmeticTarget::DE),
+            0x23 => Instruction::INC(ArithmeticTarget::HL),
+            0x33 => Instruction::INC(ArithmeticTarget::SP),
+            0x0B => Instruction::DEC(ArithmeticTarget::BC),
+            0x1B => Instruction::DEC(ArithmeticTarget::DE),
+            0x2B => Instruction::DEC(ArithmeticTarget::HL),
+            0x3B => Instruction::DEC(ArithmeticTarget::SP),
+
+            0x09 => Instruction::ADDHL(ArithmeticTarget::BC),
+            0x19 => Instruction::ADDHL(ArithmeticTarget::DE),
+            0x29 => Instruction::ADDHL(ArithmeticTarget::HL),
+            0x39 => Instruction::ADDHL(ArithmeticTarget::SP),
+            0xE8 => Instruction::ADDSP,
+
+            0x80..=0x87 => Instruction::ADD(register),
+            0x88..=0x8F => Instruction::ADC(register),
+            0x90..=0x97 => Instruction::SUB(register),
+            0x98..=0x9F => Instruction::SBC(register),
+            0xA0..=0xA7 => Instruction::AND(register),
+            0xA8..=0xAF => Instruction::XOR(register),
+            0xB0..=0xB7 => Instruction::OR(register),
+            0xB8..=0xBF => Instruction::CP(register),
+            0xC6 => Instruction::ADD(ArithmeticTarget::D8),
+            0xCE => Instruction::ADC(ArithmeticTarget::D8),
+            0xD6 => Instruction::SUB(ArithmeticTarget::D8),
+            0xDE => Instruction::SBC(ArithmeticTarget::D8),
+            0xE6 => Instruction::AND(ArithmeticTarget::D8),
+            0xEE => Instruction::XOR(ArithmeticTarget::D8),
+            0xF6 => Instruction::OR(ArithmeticTarget::D8),
+            0xFE => Instruction::CP(ArithmeticTarget::D8),
+
+            0x07 => Instruction::RLCA,
+            0x0F => Instruction::RRCA,
+            0x17 => Instruction::RLA,
+            0x1F => Instruction::RRA,
+            0x27 => Instruction::DAA,
+            0x2F => Instruction::CPL,
+            0x37 => Instruction::SCF,
+            0x3F => Instruction::CCF,
+
+            0x18 => Instruction::JR(JumpTest::Always),
+            0x20 => Instruction::JR(JumpTest::NotZero),
+            0x28 => Instruction::JR(JumpTest::Zero),
+            0x30 => Instruction::JR(JumpTest::NotCarry),
+            0x38 => Instruction::JR(JumpTest::Carry),
+
+            0xC3 => Instruction::JP(JumpTest::Always),
+            0xC2 => Instruction::JP(JumpTest::NotZero),
+            0xCA => Instruction::JP(JumpTest::Zero),
+            0xD2 => Instruction::JP(JumpTest::NotCarry),
+            0xDA => Instruction::JP(JumpTest::Carry),
+            0xE9 => Instruction::JPI,
+
+            0xCD => Instruction::CALL(JumpTest::Always),
+            0xC4 => Instruction::CALL(JumpTest::NotZero),
+            0xCC => Instruction::CALL(JumpTest::Zero),
+            0xD4 => Instruction::CALL(JumpTest::NotCarry),
+            0xDC => Instruction::CALL(JumpTest::Carry),
+
+            0xC9 => Instruction::RET(JumpTest::Always),
+            0xC0 => Instruction::RET(JumpTest::NotZero),
+            0xC8 => Instruction::RET(JumpTest::Zero),
+            0xD0 => Instruction::RET(JumpTest::NotCarry),
+            0xD8 => Instruction::RET(JumpTest::Carry),
+            0xD9 => Instruction::RETI,
+
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => Instruction::RST((byte & 0x38) as u16),
+
+            0xC5 => Instruction::PUSH(StackTarget::BC),
+            0xD5 => Instruction::PUSH(StackTarget::DE),
+            0xE5 => Instruction::PUSH(StackTarget::HL),
+            0xF5 => Instruction::PUSH(StackTarget::AF),
+            0xC1 => Instruction::POP(StackTarget::BC),
+            0xD1 => Instruction::POP(StackTarget::DE),
+            0xE1 => Instruction::POP(StackTarget::HL),
+            0xF1 => Instruction::POP(StackTarget::AF),
+
+            /* 0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC and 0xFD lock up the CPU. */
+            _ => return None,
+        };
+        Some(instruction)
+    }
 }
 
 /*
 Registers struct to emulate the CPU registers
 of an 8 bit GameBoy.
-*/ 
-struct Registers{
-  a: u8,
-  b: u8,
-  c: u8,
-  d: u8,
-  e: u8,
-  f: FlagsRegister,
-  h: u8,
-  l: u8,
+*/
+pub struct Registers{
+  pub a: u8,
+  pub b: u8,
+  pub c: u8,
+  pub d: u8,
+  pub e: u8,
+  pub f: FlagsRegister,
+  pub h: u8,
+  pub l: u8,
 }
 
 #[derive(Copy, Clone)]
-struct FlagsRegister{
-    zero: bool,
-    subtract: bool,
-    half_carry: bool,
-    carry: bool
+pub struct FlagsRegister{
+    pub zero: bool,
+    pub subtract: bool,
+    pub half_carry: bool,
+    pub carry: bool
 }
 
-struct CPU{
-    registers: Registers
+pub struct CPU<B: Bus = MemoryBus>{
+    pub registers: Registers,
+    pub pc: u16,
+    pub sp: u16,
+    pub bus: B,
+    ime: bool,
+    ime_scheduled: bool,
+    is_halted: bool,
+    halt_bug: bool,
+    is_stopped: bool,
+    is_locked: bool,
+    trace: Option<Box<dyn Write>>,
 }
 
 
@@ -72,38 +372,38 @@ impl Registers{
     */
 
 
-    fn get_af(&self) -> u16 {
+    pub fn get_af(&self) -> u16 {
         ((self.a as u16) << 8) | (u8::from(self.f) as u16)
     }
 
-    fn set_af(&mut self, value: u16){
+    pub fn set_af(&mut self, value: u16){
         self.a = ((value & 0xFF00) >> 8) as u8;
         self.f = FlagsRegister::from((value & 0xFF) as u8);
     }
 
-    fn get_bc(&self) -> u16 {
+    pub fn get_bc(&self) -> u16 {
         ((self.b as u16) << 8) | (self.c as u16)
     }
 
-    fn set_bc(&mut self, value: u16){
+    pub fn set_bc(&mut self, value: u16){
         self.b = ((value & 0xFF00) >> 8) as u8;
         self.c = (value & 0xFF) as u8;
     }
 
-    fn get_de(&self) -> u16 {
+    pub fn get_de(&self) -> u16 {
         ((self.d as u16) << 8) | (self.e as u16)
     }
 
-    fn set_de(&mut self, value: u16){
+    pub fn set_de(&mut self, value: u16){
         self.d = ((value & 0xFF00) >> 8) as u8;
         self.e = (value & 0xFF) as u8;
     }
 
-    fn get_hl(&self) -> u16 {
+    pub fn get_hl(&self) -> u16 {
         ((self.h as u16) << 8) | (self.l as u16)
     }
 
-    fn set_hl(&mut self, value: u16){
+    pub fn set_hl(&mut self, value: u16){
         self.h = ((value & 0xFF00) >> 8) as u8;
         self.l = (value & 0xFF) as u8;
     }
@@ -130,376 +430,407 @@ impl std::convert::From<u8> for FlagsRegister{
 }
 
 impl CPU {
-    fn execute(&mut self, instruction: Instruction){
+    /* Registers start in the state the boot ROM of the bus's model leaves them in. */
+    pub fn new(bus: MemoryBus) -> CPU {
+        let registers = match (bus.model(), bus.is_cgb_mode()) {
+            (Model::Dmg, _) => Registers {
+                a: 0x01,
+                b: 0x00,
+                c: 0x13,
+                d: 0x00,
+                e: 0xD8,
+                f: FlagsRegister::from(0xB0),
+                h: 0x01,
+                l: 0x4D,
+            },
+            (Model::Cgb, true) => Registers {
+                a: 0x11,
+                b: 0x00,
+                c: 0x00,
+                d: 0xFF,
+                e: 0x56,
+                f: FlagsRegister::from(0x80),
+                h: 0x00,
+                l: 0x0D,
+            },
+            (Model::Cgb, false) => Registers {
+                a: 0x11,
+                b: 0x00,
+                c: 0x00,
+                d: 0x00,
+                e: 0x08,
+                f: FlagsRegister::from(0x80),
+                h: 0x00,
+                l: 0x7C,
+            },
+        };
+
+        CPU::with_state(bus, registers, 0x0100, 0xFFFE)
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    /* A CPU in an arbitrary state, with interrupts disabled and nothing pending. */
+    pub fn with_state(bus: B, registers: Registers, pc: u16, sp: u16) -> CPU<B> {
+        CPU {
+            registers,
+            pc,
+            sp,
+            bus,
+            ime: false,
+            ime_scheduled: false,
+            is_halted: false,
+            halt_bug: false,
+            is_stopped: false,
+            is_locked: false,
+            trace: None,
+        }
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn set_ime(&mut self, ime: bool) {
+        self.ime = ime;
+        self.ime_scheduled = false;
+    }
+
+    /*
+    Log every instruction before it runs, one line each in Gameboy
+    Doctor's format. Interrupt dispatch and halted cycles are not logged.
+    */
+    pub fn set_trace(&mut self, trace: Option<Box<dyn Write>>) {
+        self.trace = trace;
+    }
+
+    fn trace_instruction(&mut self) {
+        let trace = match &mut self.trace {
+            Some(trace) => trace,
+            None => return,
+        };
+
+        let registers = &self.registers;
+        let pc = self.pc;
+        let memory = |offset: u16| self.bus.peek_byte(pc.wrapping_add(offset));
+        let result = writeln!(
+            trace,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            registers.a,
+            u8::from(registers.f),
+            registers.b,
+            registers.c,
+            registers.d,
+            registers.e,
+            registers.h,
+            registers.l,
+            self.sp,
+            pc,
+            memory(0),
+            memory(1),
+            memory(2),
+            memory(3),
+        );
+        if let Err(err) = result {
+            eprintln!("Failed to write trace: {}", err);
+            self.trace = None;
+        }
+    }
+
+    /* True once an illegal opcode has hung the CPU; nothing but a reset brings it back. */
+    pub fn is_locked(&self) -> bool {
+        self.is_locked
+    }
+
+    /*
+    Run one instruction, or dispatch one interrupt, or idle
+    for one M-cycle while halted. The bus is ticked once
+    for every M-cycle the work takes.
+    */
+    pub fn step(&mut self) {
+        if self.is_locked {
+            self.bus.tick();
+            return;
+        }
+
+        if self.is_stopped {
+            if !self.bus.wakes_from_stop() {
+                self.bus.tick();
+                return;
+            }
+            self.is_stopped = false;
+        }
+
+        if self.is_halted {
+            if self.pending_interrupts() == 0 {
+                self.bus.tick();
+                return;
+            }
+            self.is_halted = false;
+        }
+
+        if self.ime && self.pending_interrupts() != 0 {
+            self.dispatch_interrupt();
+            return;
+        }
+
+        self.trace_instruction();
+        let ime_was_scheduled = self.ime_scheduled;
+
+        let mut instruction_byte = self.fetch_byte();
+        let prefixed = instruction_byte == 0xCB;
+        if prefixed {
+            instruction_byte = self.fetch_byte();
+        }
+
+        match Instruction::from_byte(instruction_byte, prefixed) {
+            Some(instruction) => self.execute(instruction),
+            None => self.is_locked = true,
+        }
+
+        /* EI takes effect after the instruction that follows it. */
+        if ime_was_scheduled && self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.bus.pending_interrupts()
+    }
+
+    /*
+    Push PC and jump to the highest priority pending interrupt.
+    The vector is chosen after the high byte of PC is pushed, so
+    a push that overwrites IE can redirect or cancel the dispatch.
+    */
+    fn dispatch_interrupt(&mut self) {
+        self.ime = false;
+        self.internal_cycle();
+        self.internal_cycle();
+
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, (self.pc >> 8) as u8);
+        let pending = self.pending_interrupts();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, self.pc as u8);
+
+        self.pc = 0x0000;
+        for bit in 0..INTERRUPT_COUNT {
+            if pending & (1 << bit) != 0 {
+                self.bus.acknowledge_interrupt(bit);
+                self.pc = 0x0040 + 8 * bit as u16;
+                break;
+            }
+        }
+        self.internal_cycle();
+    }
+
+    fn internal_cycle(&mut self) {
+        self.bus.tick();
+    }
+
+    fn read_cycle(&mut self, address: u16) -> u8 {
+        self.bus.tick();
+        self.bus.read_byte(address)
+    }
+
+    fn write_cycle(&mut self, address: u16, value: u8) {
+        self.bus.tick();
+        self.bus.write_byte(address, value);
+    }
+
+    /* Read the byte at PC and advance past it, unless the HALT bug repeats it. */
+    fn fetch_byte(&mut self) -> u8 {
+        let byte = self.read_cycle(self.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
+        byte
+    }
+
+    fn fetch_word(&mut self) -> u16 {
+        let low = self.fetch_byte() as u16;
+        let high = self.fetch_byte() as u16;
+        (high << 8) | low
+    }
+
+    fn push(&mut self, value: u16) {
+        self.internal_cycle();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, (value >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, value as u8);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let low = self.read_cycle(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        let high = self.read_cycle(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        (high << 8) | low
+    }
+
+    fn read_target(&mut self, target: ArithmeticTarget) -> u8 {
+        match target {
+            ArithmeticTarget::A => self.registers.a,
+            ArithmeticTarget::B => self.registers.b,
+            ArithmeticTarget::C => self.registers.c,
+            ArithmeticTarget::D => self.registers.d,
+            ArithmeticTarget::E => self.registers.e,
+            ArithmeticTarget::H => self.registers.h,
+            ArithmeticTarget::L => self.registers.l,
+            ArithmeticTarget::HLI => self.read_cycle(self.registers.get_hl()),
+            ArithmeticTarget::D8 => self.fetch_byte(),
+            _ => panic!("Not a valid register.")
+        }
+    }
+
+    fn write_target(&mut self, target: ArithmeticTarget, value: u8) {
+        match target {
+            ArithmeticTarget::A => self.registers.a = value,
+            ArithmeticTarget::B => self.registers.b = value,
+            ArithmeticTarget::C => self.registers.c = value,
+            ArithmeticTarget::D => self.registers.d = value,
+            ArithmeticTarget::E => self.registers.e = value,
+            ArithmeticTarget::H => self.registers.h = value,
+            ArithmeticTarget::L => self.registers.l = value,
+            ArithmeticTarget::HLI => self.write_cycle(self.registers.get_hl(), value),
+            _ => panic!("Not a valid register.")
+        }
+    }
+
+    fn read_word_target(&self, target: ArithmeticTarget) -> u16 {
+        match target {
+            ArithmeticTarget::BC => self.registers.get_bc(),
+            ArithmeticTarget::DE => self.registers.get_de(),
+            ArithmeticTarget::HL => self.registers.get_hl(),
+            ArithmeticTarget::SP => self.sp,
+            _ => panic!("Not a valid register.")
+        }
+    }
+
+    fn write_word_target(&mut self, target: ArithmeticTarget, value: u16) {
+        match target {
+            ArithmeticTarget::BC => self.registers.set_bc(value),
+            ArithmeticTarget::DE => self.registers.set_de(value),
+            ArithmeticTarget::HL => self.registers.set_hl(value),
+            ArithmeticTarget::SP => self.sp = value,
+            _ => panic!("Not a valid register.")
+        }
+    }
+
+    fn is_word_target(target: ArithmeticTarget) -> bool {
+        matches!(
+            target,
+            ArithmeticTarget::BC | ArithmeticTarget::DE | ArithmeticTarget::HL | ArithmeticTarget::SP
+        )
+    }
+
+    fn jump_condition(&self, test: JumpTest) -> bool {
+        match test {
+            JumpTest::NotZero => !self.registers.f.zero,
+            JumpTest::Zero => self.registers.f.zero,
+            JumpTest::NotCarry => !self.registers.f.carry,
+            JumpTest::Carry => self.registers.f.carry,
+            JumpTest::Always => true,
+        }
+    }
+
+    /* Apply a read-modify-write operation to an 8 bit operand. */
+    fn modify_target(&mut self, target: ArithmeticTarget, operation: fn(&mut Self, u8) -> u8) {
+        let value = self.read_target(target);
+        let new_value = operation(self, value);
+        self.write_target(target, new_value);
+    }
+
+    pub fn execute(&mut self, instruction: Instruction){
         match instruction {
             Instruction::ADD(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.add(value);
-                        self.registers.a = new_value;
-                    }
-                    _ => panic!("Not a valid register.")
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.add(value);
             }
 
             Instruction::ADDHL(target) => {
-                match target {
-                    ArithmeticTarget::BC => {
-                        let value = self.registers.get_bc();
-                        let new_value = self.addhl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    ArithmeticTarget::DE => {
-                        let value = self.registers.get_de();
-                        let new_value = self.addhl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    ArithmeticTarget::HL => {
-                        let value = self.registers.get_hl();
-                        let new_value = self.addhl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    _ => panic!("Not a valid register.")
-                }
+                let value = self.read_word_target(target);
+                let new_value = self.addhl(value);
+                self.internal_cycle();
+                self.registers.set_hl(new_value);
+            }
+
+            Instruction::ADDSP => {
+                let offset = self.fetch_byte();
+                self.sp = self.add_sp_offset(offset);
+                self.internal_cycle();
+                self.internal_cycle();
             }
 
             Instruction::ADC(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.adc(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.adc(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.adc(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.adc(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.adc(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.adc(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.adc(value);
-                        self.registers.a = new_value;
-                    }
-                    _ => panic!("Not a valid register.")
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.adc(value);
             }
 
             Instruction::SUB(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.sub(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.sub(value);
-                                 self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.sub(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.sub(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.sub(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.sub(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.sub(value);
-                        self.registers.a = new_value;
-                    }
-                    _ => panic!("Not a valid register.")
-
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.sub(value);
             }
 
             Instruction::SBC(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.sbc(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.sbc(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.sbc(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.sbc(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.sbc(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.sbc(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.sbc(value);
-                        self.registers.a = new_value;
-                    }
-                    _ => panic!("Not a valid register.")
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.sbc(value);
             }
 
             Instruction::AND(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;                    
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;                   
-                     }
-                    ArithmeticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.and(value);
-                        self.registers.a = new_value;                  
-                    }
-                    _ => panic!("Not a valid register.")
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.and(value);
             }
 
             Instruction::OR(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;                    
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;                   
-                     }
-                    ArithmeticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.or(value);
-                        self.registers.a = new_value;                  
-                    }
-                    _ => panic!("Not a valid register.")
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.or(value);
             }
 
             Instruction::XOR(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;                    
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;                   
-                     }
-                    ArithmeticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.xor(value);
-                        self.registers.a = new_value;                  
-                    }
-                    _ => panic!("Not a valid register.")
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.xor(value);
+            }
+
+            Instruction::CP(target) => {
+                let value = self.read_target(target);
+                self.cp(value);
             }
 
             Instruction::INC(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.inc(self.registers.a);
-                    }
-                    ArithmeticTarget::B => {
-                        self.inc(self.registers.b);
-                    }
-                    ArithmeticTarget::C => {
-                        self.inc(self.registers.c);
-                    }
-                    ArithmeticTarget::D => {
-                        self.inc(self.registers.d);
-                    }
-                    ArithmeticTarget::E => {
-                        self.inc(self.registers.e);
-                    }
-                    ArithmeticTarget::H => {
-                        self.inc(self.registers.h);
-                    }
-                    ArithmeticTarget::L => {
-                        self.inc(self.registers.l);
-                    }
-                    _ => panic!("Not a valid register.")
+                if Self::is_word_target(target) {
+                    let value = self.read_word_target(target).wrapping_add(1);
+                    self.internal_cycle();
+                    self.write_word_target(target, value);
+                } else {
+                    self.modify_target(target, Self::inc);
                 }
             }
 
             Instruction::DEC(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.dec(self.registers.a);
-                    }
-                    ArithmeticTarget::B => {
-                        self.dec(self.registers.b);
-                    }
-                    ArithmeticTarget::C => {
-                        self.dec(self.registers.c);
-                    }
-                    ArithmeticTarget::D => {
-                        self.dec(self.registers.d);
-                    }
-                    ArithmeticTarget::E => {
-                        self.dec(self.registers.e);
-                    }
-                    ArithmeticTarget::H => {
-                        self.dec(self.registers.h);
-                    }
-                    ArithmeticTarget::L => {
-                        self.dec(self.registers.l);
-                    }
-                    _ => panic!("Not a valid register.")
+                if Self::is_word_target(target) {
+                    let value = self.read_word_target(target).wrapping_sub(1);
+                    self.internal_cycle();
+                    self.write_word_target(target, value);
+                } else {
+                    self.modify_target(target, Self::dec);
                 }
             }
 
             Instruction::CCF => {
-                self.registers.f.carry = self.registers.f.carry;
+                self.registers.f.subtract = false;
+                self.registers.f.half_carry = false;
+                self.registers.f.carry = !self.registers.f.carry;
             }
 
             Instruction::SCF => {
+                self.registers.f.subtract = false;
+                self.registers.f.half_carry = false;
                 self.registers.f.carry = true;
             }
 
@@ -515,95 +846,238 @@ impl CPU {
                 self.registers.a = self.rrca(self.registers.a);
             }
 
-            Instruction::RRLA => {
-                self.registers.a = self.rrla(self.registers.a);
+            Instruction::RLCA => {
+                self.registers.a = self.rlca(self.registers.a);
             }
 
             Instruction::CPL => {
-                self.registers.a = self.registers.a ^ 0xFF;
+                self.cpl();
+            }
+
+            Instruction::DAA => {
+                self.daa();
             }
 
             Instruction::BIT(index, target) => {
+                let value = self.read_target(target);
+                self.bit(value, index);
+            }
+
+            Instruction::RESET(index, target) => {
+                let value = self.read_target(target);
+                let new_value = self.reset(value, index);
+                self.write_target(target, new_value);
+            }
+
+            Instruction::SET(index, target) => {
+                let value = self.read_target(target);
+                self.write_target(target, value | (0x1 << index));
+            }
+
+            Instruction::SRL(target) => self.modify_target(target, Self::srl),
+            Instruction::RR(target) => self.modify_target(target, Self::rr),
+            Instruction::RL(target) => self.modify_target(target, Self::rl),
+            Instruction::RRC(target) => self.modify_target(target, Self::rrc),
+            Instruction::RLC(target) => self.modify_target(target, Self::rlc),
+            Instruction::SRA(target) => self.modify_target(target, Self::sra),
+            Instruction::SLA(target) => self.modify_target(target, Self::sla),
+            Instruction::SWAP(target) => self.modify_target(target, Self::swap),
+
+            Instruction::JP(test) => {
+                let address = self.fetch_word();
+                if self.jump_condition(test) {
+                    self.internal_cycle();
+                    self.pc = address;
+                }
+            }
+
+            Instruction::JPI => {
+                self.pc = self.registers.get_hl();
+            }
+
+            Instruction::JR(test) => {
+                let offset = self.fetch_byte() as i8;
+                if self.jump_condition(test) {
+                    self.internal_cycle();
+                    self.pc = self.pc.wrapping_add(offset as u16);
+                }
+            }
+
+            Instruction::LD(load_type) => self.load(load_type),
+
+            Instruction::PUSH(target) => {
+                let value = match target {
+                    StackTarget::AF => self.registers.get_af(),
+                    StackTarget::BC => self.registers.get_bc(),
+                    StackTarget::DE => self.registers.get_de(),
+                    StackTarget::HL => self.registers.get_hl(),
+                };
+                self.push(value);
+            }
+
+            Instruction::POP(target) => {
+                let value = self.pop();
                 match target {
-                    ArithmeticTarget::A => {
-                        self.bit(self.registers.a, index);
-                    }
-                    ArithmeticTarget::B => {
-                        self.bit(self.registers.b, index);
-                    }
-                    ArithmeticTarget::C => {
-                        self.bit(self.registers.c, index);
-                    }
-                    ArithmeticTarget::D => {
-                        self.bit(self.registers.d, index);
-                    }
-                    ArithmeticTarget::E => {
-                        self.bit(self.registers.e, index);
-                    }
-                    ArithmeticTarget::H => {
-                        self.bit(self.registers.h, index);
-                    }
-                    ArithmeticTarget::L => {
-                        self.bit(self.registers.l, index);
+                    StackTarget::AF => self.registers.set_af(value),
+                    StackTarget::BC => self.registers.set_bc(value),
+                    StackTarget::DE => self.registers.set_de(value),
+                    StackTarget::HL => self.registers.set_hl(value),
+                }
+            }
+
+            Instruction::CALL(test) => {
+                let address = self.fetch_word();
+                if self.jump_condition(test) {
+                    self.push(self.pc);
+                    self.pc = address;
+                }
+            }
+
+            Instruction::RET(test) => {
+                if let JumpTest::Always = test {
+                    self.pc = self.pop();
+                    self.internal_cycle();
+                } else {
+                    self.internal_cycle();
+                    if self.jump_condition(test) {
+                        self.pc = self.pop();
+                        self.internal_cycle();
                     }
-                    _ => panic!("Not a valid register.")
                 }
             }
 
-            Instruction::RESET(index, target) => {
+            Instruction::RETI => {
+                self.pc = self.pop();
+                self.internal_cycle();
+                self.ime = true;
+            }
+
+            Instruction::RST(address) => {
+                self.push(self.pc);
+                self.pc = address;
+            }
+
+            Instruction::NOP => {}
+
+            Instruction::HALT => {
+                if !self.ime && self.pending_interrupts() != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.is_halted = true;
+                }
+            }
+
+            /*
+            With a CGB speed switch armed, STOP changes speed and pauses
+            the CPU instead of stopping it. Otherwise DIV is reset and
+            the CPU sleeps until a selected joypad line goes low.
+            */
+            Instruction::STOP => {
+                self.fetch_byte();
+                if self.bus.stop() {
+                    for _ in 0..SPEED_SWITCH_CYCLES {
+                        self.internal_cycle();
+                    }
+                } else {
+                    self.is_stopped = true;
+                }
+            }
+
+            Instruction::DI => {
+                self.ime = false;
+                self.ime_scheduled = false;
+            }
+
+            Instruction::EI => {
+                self.ime_scheduled = true;
+            }
+        }
+    }
+
+    fn load(&mut self, load_type: LoadType) {
+        match load_type {
+            LoadType::Byte(target, source) => {
+                let value = match source {
+                    LoadByteSource::A => self.registers.a,
+                    LoadByteSource::B => self.registers.b,
+                    LoadByteSource::C => self.registers.c,
+                    LoadByteSource::D => self.registers.d,
+                    LoadByteSource::E => self.registers.e,
+                    LoadByteSource::H => self.registers.h,
+                    LoadByteSource::L => self.registers.l,
+                    LoadByteSource::D8 => self.fetch_byte(),
+                    LoadByteSource::HLI => self.read_cycle(self.registers.get_hl()),
+                };
                 match target {
-                    ArithmeticTarget::A => {
-                        self.registers.a = self.registers.a & !(0x1 << index);
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.b = self.registers.b & !(0x1 << index);
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.c = self.registers.c & !(0x1 << index);
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.d = self.registers.d & !(0x1 << index);
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.e = self.registers.e & !(0x1 << index);
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.h = self.registers.h & !(0x1 << index);
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.l = self.registers.l & !(0x1 << index);
-                    }
-                    _ => panic!("Not a valid register.")
+                    LoadByteTarget::A => self.registers.a = value,
+                    LoadByteTarget::B => self.registers.b = value,
+                    LoadByteTarget::C => self.registers.c = value,
+                    LoadByteTarget::D => self.registers.d = value,
+                    LoadByteTarget::E => self.registers.e = value,
+                    LoadByteTarget::H => self.registers.h = value,
+                    LoadByteTarget::L => self.registers.l = value,
+                    LoadByteTarget::HLI => self.write_cycle(self.registers.get_hl(), value),
                 }
             }
 
-            Instruction::SET(index, target) => {
+            LoadType::Word(target) => {
+                let value = self.fetch_word();
                 match target {
-                    ArithmeticTarget::A => {
-                        self.registers.a = self.registers.a & (0x1 << index);
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.b = self.registers.b & (0x1 << index);
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.c = self.registers.c & (0x1 << index);
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.d = self.registers.d & (0x1 << index);
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.e = self.registers.e & (0x1 << index);
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.h = self.registers.h & (0x1 << index);
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.l = self.registers.l & (0x1 << index);
-                    }
-                    _ => panic!("Not a valid register.")
+                    LoadWordTarget::BC => self.registers.set_bc(value),
+                    LoadWordTarget::DE => self.registers.set_de(value),
+                    LoadWordTarget::HL => self.registers.set_hl(value),
+                    LoadWordTarget::SP => self.sp = value,
                 }
             }
-            _ => panic!("Not a valid instruction.")
+
+            LoadType::AFromIndirect(indirect) => {
+                let address = self.indirect_address(indirect);
+                self.registers.a = self.read_cycle(address);
+            }
+
+            LoadType::IndirectFromA(indirect) => {
+                let address = self.indirect_address(indirect);
+                self.write_cycle(address, self.registers.a);
+            }
+
+            LoadType::SPFromHL => {
+                self.sp = self.registers.get_hl();
+                self.internal_cycle();
+            }
+
+            LoadType::HLFromSPN => {
+                let offset = self.fetch_byte();
+                let value = self.add_sp_offset(offset);
+                self.internal_cycle();
+                self.registers.set_hl(value);
+            }
+
+            LoadType::IndirectFromSP => {
+                let address = self.fetch_word();
+                self.write_cycle(address, self.sp as u8);
+                self.write_cycle(address.wrapping_add(1), (self.sp >> 8) as u8);
+            }
+        }
+    }
+
+    /* Resolve the address of an LD A,(x) / LD (x),A operand, applying HL+/HL- side effects. */
+    fn indirect_address(&mut self, indirect: Indirect) -> u16 {
+        match indirect {
+            Indirect::BCIndirect => self.registers.get_bc(),
+            Indirect::DEIndirect => self.registers.get_de(),
+            Indirect::HLIndirectPlus => {
+                let hl = self.registers.get_hl();
+                self.registers.set_hl(hl.wrapping_add(1));
+                hl
+            }
+            Indirect::HLIndirectMinus => {
+                let hl = self.registers.get_hl();
+                self.registers.set_hl(hl.wrapping_sub(1));
+                hl
+            }
+            Indirect::WordIndirect => self.fetch_word(),
+            Indirect::LastByteIndirect => 0xFF00 | self.registers.c as u16,
+            Indirect::ByteIndirect => 0xFF00 | self.fetch_byte() as u16,
         }
     }
 
@@ -615,15 +1089,14 @@ impl CPU {
         self.registers.f.subtract = false;
         self.registers.f.half_carry = (self.registers.a & 0xF) + (value & 0xF) > 0xF;
         self.registers.f.carry = did_overflow;
-        
+
         new_value
     }
 
-    /* Add value u16 to register HL. */
+    /* Add value u16 to register HL. Leaves the zero flag alone. */
     fn addhl(&mut self, value: u16) -> u16 {
         let (new_value, did_overflow) = self.registers.get_hl().overflowing_add(value);
 
-        self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = (self.registers.get_hl() & 0xFFF) + (value & 0xFFF) > 0xFFF;
         self.registers.f.carry = did_overflow;
@@ -631,16 +1104,29 @@ impl CPU {
         new_value
     }
 
+    /* Add a signed byte to SP. Flags come from the unsigned low byte addition. */
+    fn add_sp_offset(&mut self, offset: u8) -> u16 {
+        let new_value = self.sp.wrapping_add(offset as i8 as u16);
+
+        self.registers.f.zero = false;
+        self.registers.f.subtract = false;
+        self.registers.f.half_carry = (self.sp & 0xF) + (offset as u16 & 0xF) > 0xF;
+        self.registers.f.carry = (self.sp & 0xFF) + offset as u16 > 0xFF;
+
+        new_value
+    }
+
     /* Add value u8 to register A and add the carry flag. */
     fn adc(&mut self, value: u8) -> u8 {
-        let (new_value, did_overflow) = self.registers.a.overflowing_add(value);
+        let carry = self.registers.f.carry as u8;
+        let new_value = self.registers.a.wrapping_add(value).wrapping_add(carry);
 
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
-        self.registers.f.half_carry = (self.registers.a & 0xF) + (value & 0xF) > 0xF;
-        self.registers.f.carry = did_overflow;
+        self.registers.f.half_carry = (self.registers.a & 0xF) + (value & 0xF) + carry > 0xF;
+        self.registers.f.carry = self.registers.a as u16 + value as u16 + carry as u16 > 0xFF;
 
-        new_value + (did_overflow as u8)
+        new_value
     }
 
     fn sub(&mut self, value: u8) -> u8 {
@@ -648,21 +1134,22 @@ impl CPU {
 
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = true;
-        self.registers.f.half_carry = (self.registers.a & 0xF) - (value & 0xF) > 0xF;
+        self.registers.f.half_carry = (self.registers.a & 0xF) < (value & 0xF);
         self.registers.f.carry = did_overflow;
 
         new_value
     }
 
     fn sbc(&mut self, value: u8) -> u8 {
-        let (new_value, did_overflow) = self.registers.a.overflowing_sub(value);
+        let carry = self.registers.f.carry as u8;
+        let new_value = self.registers.a.wrapping_sub(value).wrapping_sub(carry);
 
         self.registers.f.zero = new_value == 0;
-        self.registers.f.subtract = false;
-        self.registers.f.half_carry = (self.registers.a & 0xF) - (value & 0xF) > 0xF;
-        self.registers.f.carry = did_overflow;
+        self.registers.f.subtract = true;
+        self.registers.f.half_carry = (self.registers.a & 0xF) < (value & 0xF) + carry;
+        self.registers.f.carry = (self.registers.a as u16) < value as u16 + carry as u16;
 
-        new_value + (did_overflow as u8)
+        new_value
     }
 
     fn and(&mut self, value: u8) -> u8 {
@@ -688,7 +1175,7 @@ impl CPU {
     }
 
     fn xor(&mut self, value: u8) -> u8 {
-        let new_value = self.registers.a & value;
+        let new_value = self.registers.a ^ value;
 
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
@@ -703,12 +1190,12 @@ impl CPU {
 
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = true;
-        self.registers.f.half_carry = (self.registers.a & 0xF) - (value & 0xF) > 0xF;
+        self.registers.f.half_carry = (self.registers.a & 0xF) < (value & 0xF);
         self.registers.f.carry = did_overflow;
     }
 
     fn inc(&mut self, value: u8) -> u8 {
-        let new_value = value + 1;
+        let new_value = value.wrapping_add(1);
 
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
@@ -718,70 +1205,118 @@ impl CPU {
     }
 
     fn dec(&mut self, value: u8) -> u8 {
-        let new_value = value - 1;
+        let new_value = value.wrapping_sub(1);
 
         self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = true;
-        self.registers.f.half_carry = (value & 0xF) - 1 > 0xF;
+        self.registers.f.half_carry = value & 0xF == 0;
 
         new_value
     }
 
+    /* The accumulator rotates always clear the zero flag. */
     fn rra(&mut self, value: u8) -> u8 {
-        let least_bit = value & 0x1;
-        let new_value = (value >> 1) & ((self.registers.f.carry as u8) << 7);
-
+        let new_value = self.rr(value);
         self.registers.f.zero = false;
-        self.registers.f.subtract = false;
-        self.registers.f.half_carry = false;
-        self.registers.f.carry = least_bit != 0;
-
         new_value
     }
 
     fn rla(&mut self, value: u8) -> u8 {
-        let most_bit = value & (0x1 << 7);
-        let new_value = (value << 1) & (self.registers.f.carry as u8);
-        
+        let new_value = self.rl(value);
         self.registers.f.zero = false;
-        self.registers.f.subtract = false;
-        self.registers.f.half_carry = false;
-        self.registers.f.carry = (most_bit >> 7) != 0;
-
         new_value
     }
 
     fn rrca(&mut self, value: u8) -> u8 {
-        let least_bit = value & 0x1;
-        let new_value = (value >> 1) & (least_bit << 7);
+        let new_value = self.rrc(value);
+        self.registers.f.zero = false;
+        new_value
+    }
 
+    fn rlca(&mut self, value: u8) -> u8 {
+        let new_value = self.rlc(value);
         self.registers.f.zero = false;
+        new_value
+    }
+
+    /* Set flags for a CB shift or rotate. */
+    fn shift_flags(&mut self, new_value: u8, carry: bool) -> u8 {
+        self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = false;
         self.registers.f.half_carry = false;
-        self.registers.f.carry = least_bit != 0;
+        self.registers.f.carry = carry;
 
         new_value
     }
 
-    fn rrla(&mut self, value: u8) -> u8 {
-        let most_bit = value & (0x1 << 7);
-        let new_value = (value << 1) & (most_bit >> 7);
+    fn rr(&mut self, value: u8) -> u8 {
+        let new_value = (value >> 1) | ((self.registers.f.carry as u8) << 7);
+        self.shift_flags(new_value, value & 0x1 != 0)
+    }
 
-        self.registers.f.zero = false;
-        self.registers.f.subtract = false;
-        self.registers.f.half_carry = false;
-        self.registers.f.carry = (most_bit >> 7) != 0;
+    fn rl(&mut self, value: u8) -> u8 {
+        let new_value = (value << 1) | (self.registers.f.carry as u8);
+        self.shift_flags(new_value, value & 0x80 != 0)
+    }
 
-        new_value
+    fn rrc(&mut self, value: u8) -> u8 {
+        self.shift_flags(value.rotate_right(1), value & 0x1 != 0)
+    }
+
+    fn rlc(&mut self, value: u8) -> u8 {
+        self.shift_flags(value.rotate_left(1), value & 0x80 != 0)
+    }
+
+    fn sra(&mut self, value: u8) -> u8 {
+        self.shift_flags((value >> 1) | (value & 0x80), value & 0x1 != 0)
+    }
+
+    fn sla(&mut self, value: u8) -> u8 {
+        self.shift_flags(value << 1, value & 0x80 != 0)
+    }
+
+    fn srl(&mut self, value: u8) -> u8 {
+        self.shift_flags(value >> 1, value & 0x1 != 0)
+    }
+
+    fn swap(&mut self, value: u8) -> u8 {
+        self.shift_flags(value.rotate_left(4), false)
     }
 
     fn cpl(&mut self) {
-        let new_value = self.registers.a ^ 0xFF;
+        self.registers.a ^= 0xFF;
 
         self.registers.f.subtract = true;
         self.registers.f.half_carry = true;
+    }
+
+    /* Adjust A back into packed BCD after an addition or subtraction. */
+    fn daa(&mut self) {
+        let mut adjustment = 0;
+        let mut carry = self.registers.f.carry;
 
-        new_value;
+        if self.registers.f.subtract {
+            if self.registers.f.half_carry {
+                adjustment |= 0x06;
+            }
+            if carry {
+                adjustment |= 0x60;
+            }
+            self.registers.a = self.registers.a.wrapping_sub(adjustment);
+        } else {
+            if self.registers.f.half_carry || self.registers.a & 0x0F > 0x09 {
+                adjustment |= 0x06;
+            }
+            if carry || self.registers.a > 0x99 {
+                adjustment |= 0x60;
+                carry = true;
+            }
+            self.registers.a = self.registers.a.wrapping_add(adjustment);
+        }
+
+        self.registers.f.zero = self.registers.a == 0;
+        self.registers.f.half_carry = false;
+        self.registers.f.carry = carry;
     }
 
     fn bit(&mut self, value: u8, index: u8) {
@@ -793,10 +1328,164 @@ impl CPU {
     }
 
     fn reset(&mut self, value: u8, index: u8) -> u8{
-        let new_value = value & !(0x1 << index);
+        value & !(0x1 << index)
+    }
 
-        new_value
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlagsRegister, Registers, CPU};
+    use crate::memory_bus::{Bus, INTERRUPT_ENABLE_ADDRESS, INTERRUPT_FLAG_ADDRESS};
+
+    /* 64 KiB of RAM that counts M-cycles. */
+    struct CountingBus {
+        memory: Vec<u8>,
+        cycles: u32,
+    }
+
+    impl Bus for CountingBus {
+        fn tick(&mut self) {
+            self.cycles += 1;
+        }
+
+        fn read_byte(&mut self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+
+        fn write_byte(&mut self, address: u16, value: u8) {
+            self.memory[address as usize] = value;
+        }
+
+        fn peek_byte(&self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+
+        fn pending_interrupts(&self) -> u8 {
+            self.memory[INTERRUPT_ENABLE_ADDRESS as usize] & self.memory[INTERRUPT_FLAG_ADDRESS as usize] & 0x1F
+        }
+
+        fn acknowledge_interrupt(&mut self, bit: u8) {
+            self.memory[INTERRUPT_FLAG_ADDRESS as usize] &= !(1 << bit);
+        }
+
+        fn stop(&mut self) -> bool {
+            false
+        }
+
+        fn wakes_from_stop(&self) -> bool {
+            true
+        }
+    }
+
+    /* A CPU at 0x0100 running `program`, with the stack at 0xD000 and IE/IF as given. */
+    fn cpu(program: &[u8], enabled: u8, requested: u8) -> CPU<CountingBus> {
+        let mut memory = vec![0; 0x10000];
+        memory[0x0100..0x0100 + program.len()].copy_from_slice(program);
+        memory[INTERRUPT_ENABLE_ADDRESS as usize] = enabled;
+        memory[INTERRUPT_FLAG_ADDRESS as usize] = requested;
+        let registers = Registers {
+            a: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            f: FlagsRegister::from(0),
+            h: 0,
+            l: 0,
+        };
+        CPU::with_state(CountingBus { memory, cycles: 0 }, registers, 0x0100, 0xD000)
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        /* EI, NOP, NOP with V-Blank pending. */
+        let mut cpu = cpu(&[0xFB, 0x00, 0x00], 0x01, 0x01);
+        cpu.step();
+        assert!(!cpu.ime());
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0102, "the instruction after EI runs uninterrupted");
+        assert!(cpu.ime());
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0040);
+    }
+
+    #[test]
+    fn di_straight_after_ei_keeps_interrupts_off() {
+        let mut cpu = cpu(&[0xFB, 0xF3, 0x00], 0x01, 0x01);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert!(!cpu.ime());
+        assert_eq!(cpu.pc, 0x0103);
+    }
+
+    #[test]
+    fn dispatch_takes_five_cycles_and_picks_the_highest_priority() {
+        let mut cpu = cpu(&[0x00], 0x1F, 0x06);
+        cpu.set_ime(true);
+        cpu.step();
+
+        assert_eq!(cpu.bus.cycles, 5);
+        assert_eq!(cpu.pc, 0x0048, "LCD STAT beats the timer");
+        assert!(!cpu.ime());
+        assert_eq!(cpu.bus.memory[INTERRUPT_FLAG_ADDRESS as usize], 0x04);
+        assert_eq!(cpu.sp, 0xCFFE);
+        assert_eq!(cpu.bus.memory[0xCFFE..0xD000], [0x00, 0x01]);
+    }
+
+    #[test]
+    fn pushing_over_ie_cancels_the_dispatch() {
+        /* With SP at 0x0000 the high byte of PC (0x02) lands in IE, disabling V-Blank. */
+        let mut cpu = cpu(&[], 0x01, 0x01);
+        cpu.pc = 0x0200;
+        cpu.sp = 0x0000;
+        cpu.set_ime(true);
+        cpu.step();
+
+        assert_eq!(cpu.bus.cycles, 5);
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(cpu.bus.memory[INTERRUPT_FLAG_ADDRESS as usize], 0x01, "nothing was acknowledged");
+    }
+
+    #[test]
+    fn halt_bug_runs_the_next_byte_twice() {
+        /* HALT, INC A with an interrupt pending but IME off. */
+        let mut cpu = cpu(&[0x76, 0x3C], 0x01, 0x01);
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.registers.a, 2);
+        assert_eq!(cpu.pc, 0x0102);
     }
 
+    #[test]
+    fn halt_waits_for_an_interrupt_without_ime() {
+        let mut cpu = cpu(&[0x76, 0x3C], 0x01, 0x00);
+        cpu.step();
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert_eq!(cpu.pc, 0x0101, "still halted");
+        assert_eq!(cpu.bus.cycles, 11);
+
+        /* The request wakes the CPU, which carries on without dispatching. */
+        cpu.bus.memory[INTERRUPT_FLAG_ADDRESS as usize] = 0x01;
+        cpu.step();
+        assert_eq!(cpu.registers.a, 1);
+        assert_eq!(cpu.pc, 0x0102);
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn halt_with_ime_wakes_into_the_handler() {
+        let mut cpu = cpu(&[0x76, 0x3C], 0x04, 0x00);
+        cpu.set_ime(true);
+        cpu.step();
+        cpu.step();
+        cpu.bus.memory[INTERRUPT_FLAG_ADDRESS as usize] = 0x04;
+        cpu.step();
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(cpu.bus.memory[0xCFFE..0xD000], [0x01, 0x01], "returns to the instruction after HALT");
+    }
+}
@@ -0,0 +1,480 @@
+use std::io;
+
+mod noise;
+mod resampler;
+mod square;
+mod units;
+mod wave;
+
+pub use resampler::Resampler;
+
+use noise::NoiseChannel;
+use square::SquareChannel;
+use wave::WaveChannel;
+
+pub const NR10_ADDRESS: u16 = 0xFF10;
+pub const NR44_ADDRESS: u16 = 0xFF23;
+pub const NR50_ADDRESS: u16 = 0xFF24;
+pub const NR51_ADDRESS: u16 = 0xFF25;
+pub const NR52_ADDRESS: u16 = 0xFF26;
+pub const WAVE_RAM_START: u16 = 0xFF30;
+pub const WAVE_RAM_END: u16 = 0xFF3F;
+
+/* Registers 0xFF10-0xFF2F, kept as written so reads can hand them back. */
+const REGISTER_COUNT: usize = 0x20;
+
+/* Bits that always read back as 1, per register from NR10. NR52 is built separately. */
+const READ_MASKS: [u8; REGISTER_COUNT] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+const NR52_POWER: u8 = 1 << 7;
+
+/* Dots per output sample: the APU produces one stereo sample every 4 dots. */
+const DOTS_PER_SAMPLE: u32 = 4;
+
+/* Rate at which samples reach the sink, in Hz. */
+pub const APU_SAMPLE_RATE: u32 = 4_194_304 / DOTS_PER_SAMPLE;
+
+/* Charge factor of the output capacitor per sample (0.999958 per dot). */
+const HIGH_PASS_FACTOR: f32 = 0.999_832;
+
+/*
+Where the APU delivers its output. Samples are in -1.0..=1.0 at
+`APU_SAMPLE_RATE`; wrap the sink in a `Resampler` to get them at
+a sound card's rate.
+*/
+pub trait AudioSink {
+    fn push_sample(&mut self, left: f32, right: f32);
+
+    /* Push out anything buffered, e.g. finish a file. */
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Channel {
+    Square1,
+    Square2,
+    Wave,
+    Noise,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [Channel::Square1, Channel::Square2, Channel::Wave, Channel::Noise];
+
+    /* Channel by its number in the hardware docs, 1-4. */
+    pub fn from_number(number: u8) -> Option<Channel> {
+        match number {
+            1..=4 => Some(Channel::ALL[number as usize - 1]),
+            _ => None,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/* The output capacitor: removes the DC offset the DACs leave behind. */
+#[derive(Copy, Clone)]
+struct HighPass {
+    capacitors: [f32; 2],
+}
+
+impl HighPass {
+    fn new() -> HighPass {
+        HighPass { capacitors: [0.0; 2] }
+    }
+
+    fn filter(&mut self, input: [f32; 2]) -> [f32; 2] {
+        let mut output = input;
+        for (sample, capacitor) in output.iter_mut().zip(self.capacitors.iter_mut()) {
+            let level = *sample;
+            *sample = level - *capacitor;
+            *capacitor = level - *sample * HIGH_PASS_FACTOR;
+        }
+        output
+    }
+}
+
+/*
+Audio processing unit: two square channels (the first with a
+frequency sweep), the wave channel and the noise channel. The
+frame sequencer runs off DIV and clocks length counters at
+256 Hz, sweep at 128 Hz and envelopes at 64 Hz; NR51 routes each
+channel to the left and right outputs and NR50 scales them.
+
+Channels can be muted or soloed in the mix, and each one can also
+be tapped on its own (panned and scaled as in the mix, regardless
+of muting) for recording.
+*/
+pub struct Apu {
+    powered: bool,
+    square1: SquareChannel,
+    square2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    registers: [u8; REGISTER_COUNT],
+    sequencer_step: u8,
+    div_bit: bool,
+    sample_dots: u32,
+    high_pass: HighPass,
+    sink: Option<Box<dyn AudioSink>>,
+    muted: [bool; 4],
+    solo: Option<Channel>,
+    channel_high_pass: [HighPass; 4],
+    channel_sinks: [Option<Box<dyn AudioSink>>; 4],
+}
+
+impl Apu {
+    /* Registers start as the boot ROM leaves them after its chime. */
+    pub fn new() -> Apu {
+        let mut apu = Apu {
+            powered: true,
+            square1: SquareChannel::new(true),
+            square2: SquareChannel::new(false),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            registers: [0; REGISTER_COUNT],
+            sequencer_step: 0,
+            div_bit: false,
+            sample_dots: 0,
+            high_pass: HighPass::new(),
+            sink: None,
+            muted: [false; 4],
+            solo: None,
+            channel_high_pass: [HighPass::new(); 4],
+            channel_sinks: [None, None, None, None],
+        };
+        apu.write(0xFF11, 0x80);
+        apu.write(0xFF12, 0xF3);
+        apu.write(NR50_ADDRESS, 0x77);
+        apu.write(NR51_ADDRESS, 0xF3);
+        apu
+    }
+
+    pub fn set_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.sink = Some(sink);
+    }
+
+    /* Receive one channel's output on its own. */
+    pub fn set_channel_sink(&mut self, channel: Channel, sink: Box<dyn AudioSink>) {
+        self.channel_sinks[channel.index()] = Some(sink);
+    }
+
+    /* Flush every sink, reporting the first error. */
+    pub fn flush_sinks(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        for sink in self.sink.iter_mut().chain(self.channel_sinks.iter_mut().flatten()) {
+            let flushed = sink.flush();
+            if result.is_ok() {
+                result = flushed;
+            }
+        }
+        result
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel.index()] = muted;
+    }
+
+    /* With a channel soloed only it is mixed, whatever is muted. */
+    pub fn set_solo(&mut self, channel: Option<Channel>) {
+        self.solo = channel;
+    }
+
+    fn is_audible(&self, channel: usize) -> bool {
+        match self.solo {
+            Some(solo) => solo.index() == channel,
+            None => !self.muted[channel],
+        }
+    }
+
+    /*
+    Advance by a number of dots. `div_bit` is the DIV bit that
+    drives the frame sequencer (bit 4, or bit 5 in double speed);
+    the sequencer steps on its falling edge.
+    */
+    pub fn tick(&mut self, dots: u32, div_bit: bool) {
+        if self.powered && self.div_bit && !div_bit {
+            self.clock_sequencer();
+        }
+        self.div_bit = div_bit;
+
+        for _ in 0..dots {
+            if self.powered {
+                self.square1.step();
+                self.square2.step();
+                self.wave.step();
+                self.noise.step();
+            }
+
+            self.sample_dots += 1;
+            if self.sample_dots == DOTS_PER_SAMPLE {
+                self.sample_dots = 0;
+                self.emit_sample();
+            }
+        }
+    }
+
+    fn clock_sequencer(&mut self) {
+        if self.sequencer_step.is_multiple_of(2) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.sequencer_step % 4 == 2 {
+            self.square1.clock_sweep();
+        }
+        if self.sequencer_step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.sequencer_step = (self.sequencer_step + 1) % 8;
+    }
+
+    /* Each channel's DAC maps 0-15 onto 1.0..=-1.0; a DAC that is off contributes nothing. */
+    fn channel_outputs(&self) -> [f32; 4] {
+        let outputs = [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ];
+        outputs.map(|output| match output {
+            Some(digital) => 1.0 - digital as f32 / 7.5,
+            None => 0.0,
+        })
+    }
+
+    fn emit_sample(&mut self) {
+        if self.sink.is_none() && self.channel_sinks.iter().all(Option::is_none) {
+            return;
+        }
+
+        let panning = self.registers[(NR51_ADDRESS - NR10_ADDRESS) as usize];
+        let volume = self.registers[(NR50_ADDRESS - NR10_ADDRESS) as usize];
+        /* Four channels at full scale sum to 4.0; NR50 then picks 1/8 to 8/8 of that. */
+        let gains = [
+            (((volume >> 4) & 0x07) + 1) as f32 / 8.0 / 4.0,
+            ((volume & 0x07) + 1) as f32 / 8.0 / 4.0,
+        ];
+
+        let mut mixed = [0.0f32; 2];
+        for (channel, analog) in self.channel_outputs().into_iter().enumerate() {
+            let left = if panning & (0x10 << channel) != 0 { analog * gains[0] } else { 0.0 };
+            let right = if panning & (0x01 << channel) != 0 { analog * gains[1] } else { 0.0 };
+
+            if self.is_audible(channel) {
+                mixed[0] += left;
+                mixed[1] += right;
+            }
+            if let Some(sink) = &mut self.channel_sinks[channel] {
+                let [left, right] = self.channel_high_pass[channel].filter([left, right]);
+                sink.push_sample(left, right);
+            }
+        }
+
+        let [left, right] = self.high_pass.filter(mixed);
+        if let Some(sink) = &mut self.sink {
+            sink.push_sample(left, right);
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            NR52_ADDRESS => {
+                let power = if self.powered { NR52_POWER } else { 0 };
+                let channels = [
+                    self.square1.is_enabled(),
+                    self.square2.is_enabled(),
+                    self.wave.is_enabled(),
+                    self.noise.is_enabled(),
+                ];
+                let status = channels
+                    .iter()
+                    .enumerate()
+                    .fold(0, |status, (channel, &enabled)| status | ((enabled as u8) << channel));
+                0x70 | power | status
+            }
+            NR10_ADDRESS..=0xFF2F => {
+                let index = (address - NR10_ADDRESS) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+            WAVE_RAM_START..=WAVE_RAM_END => self.wave.read_ram((address - WAVE_RAM_START) as usize),
+            _ => 0xFF,
+        }
+    }
+
+    /* While NR52 has the APU powered down, only NR52 itself and wave RAM accept writes. */
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            NR52_ADDRESS => self.write_power(value & NR52_POWER != 0),
+            WAVE_RAM_START..=WAVE_RAM_END => self.wave.write_ram((address - WAVE_RAM_START) as usize, value),
+            _ if !self.powered => {}
+            NR10_ADDRESS..=NR44_ADDRESS => {
+                let index = (address - NR10_ADDRESS) as usize;
+                self.registers[index] = value;
+
+                /* Lengths get an extra clock when the next sequencer step will not clock them. */
+                let extra_length_clock = self.sequencer_step % 2 == 1;
+                let register = index % 5;
+                match index / 5 {
+                    0 => self.square1.write(register, value, extra_length_clock),
+                    1 => self.square2.write(register, value, extra_length_clock),
+                    2 => self.wave.write(register, value, extra_length_clock),
+                    _ => self.noise.write(register, value, extra_length_clock),
+                }
+            }
+            NR50_ADDRESS | NR51_ADDRESS => self.registers[(address - NR10_ADDRESS) as usize] = value,
+            _ => {}
+        }
+    }
+
+    /* Powering off clears every register and channel; powering on restarts the frame sequencer. */
+    fn write_power(&mut self, powered: bool) {
+        if self.powered && !powered {
+            self.square1 = SquareChannel::new(true);
+            self.square2 = SquareChannel::new(false);
+            self.wave.power_off();
+            self.noise = NoiseChannel::new();
+            self.registers = [0; REGISTER_COUNT];
+        } else if !self.powered && powered {
+            self.sequencer_step = 0;
+        }
+        self.powered = powered;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Apu, NR10_ADDRESS, NR50_ADDRESS, NR52_ADDRESS, WAVE_RAM_START};
+
+    const NR11_ADDRESS: u16 = 0xFF11;
+    const NR12_ADDRESS: u16 = 0xFF12;
+    const NR13_ADDRESS: u16 = 0xFF13;
+    const NR14_ADDRESS: u16 = 0xFF14;
+    const NR21_ADDRESS: u16 = 0xFF16;
+    const NR22_ADDRESS: u16 = 0xFF17;
+    const NR24_ADDRESS: u16 = 0xFF19;
+    const NR30_ADDRESS: u16 = 0xFF1A;
+    const NR33_ADDRESS: u16 = 0xFF1D;
+    const NR34_ADDRESS: u16 = 0xFF1E;
+
+    /* Step the frame sequencer by feeding it falling edges of the DIV bit. */
+    fn clock_sequencer(apu: &mut Apu, steps: u32) {
+        for _ in 0..steps {
+            apu.tick(0, true);
+            apu.tick(0, false);
+        }
+    }
+
+    fn channel_on(apu: &Apu, channel: u8) -> bool {
+        apu.read(NR52_ADDRESS) & (1 << channel) != 0
+    }
+
+    #[test]
+    fn length_counts_down_on_even_sequencer_steps() {
+        let mut apu = Apu::new();
+        apu.write(NR21_ADDRESS, 0x3E);
+        apu.write(NR22_ADDRESS, 0xF0);
+        apu.write(NR24_ADDRESS, 0xC0);
+        assert!(channel_on(&apu, 1));
+
+        clock_sequencer(&mut apu, 2);
+        assert!(channel_on(&apu, 1), "one length clock left");
+        clock_sequencer(&mut apu, 1);
+        assert!(!channel_on(&apu, 1));
+    }
+
+    #[test]
+    fn enabling_length_before_an_odd_step_clocks_it_early() {
+        let mut apu = Apu::new();
+        clock_sequencer(&mut apu, 1);
+        apu.write(NR21_ADDRESS, 0x3F);
+        apu.write(NR22_ADDRESS, 0xF0);
+        apu.write(NR24_ADDRESS, 0x80);
+        assert!(channel_on(&apu, 1));
+
+        /* The next step does not clock lengths, so enabling the counter takes its last clock now. */
+        apu.write(NR24_ADDRESS, 0x40);
+        assert!(!channel_on(&apu, 1));
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel_1() {
+        let mut apu = Apu::new();
+        apu.write(NR10_ADDRESS, 0x11);
+        apu.write(NR12_ADDRESS, 0xF0);
+        apu.write(NR13_ADDRESS, 0x00);
+        apu.write(NR14_ADDRESS, 0x84);
+        assert!(channel_on(&apu, 0));
+
+        /* Sweep clocks on step 2: 0x400 becomes 0x600, and the check for 0x900 fails. */
+        clock_sequencer(&mut apu, 2);
+        assert!(channel_on(&apu, 0));
+        clock_sequencer(&mut apu, 1);
+        assert!(!channel_on(&apu, 0));
+
+        /* With a shift set, the overflow check also runs on trigger. */
+        apu.write(NR13_ADDRESS, 0xFF);
+        apu.write(NR14_ADDRESS, 0x87);
+        assert!(!channel_on(&apu, 0));
+    }
+
+    #[test]
+    fn playing_wave_channel_only_exposes_the_current_byte() {
+        let mut apu = Apu::new();
+        for index in 0..16 {
+            apu.write(WAVE_RAM_START + index, index as u8 * 0x11);
+        }
+        apu.write(NR30_ADDRESS, 0x80);
+        apu.write(NR33_ADDRESS, 0xFF);
+        apu.write(NR34_ADDRESS, 0x87);
+
+        /* A period of 2 dots: after 4 the channel reads sample 2, in byte 1. */
+        apu.tick(4, false);
+        assert_eq!(apu.read(WAVE_RAM_START + 10), 0x11);
+        apu.write(WAVE_RAM_START + 15, 0x99);
+
+        apu.write(NR30_ADDRESS, 0x00);
+        assert!(!channel_on(&apu, 2));
+        assert_eq!(apu.read(WAVE_RAM_START + 1), 0x99);
+        assert_eq!(apu.read(WAVE_RAM_START + 15), 0xFF);
+    }
+
+    #[test]
+    fn power_off_clears_registers_and_ignores_writes() {
+        let mut apu = Apu::new();
+        apu.write(NR21_ADDRESS, 0x80);
+        apu.write(NR22_ADDRESS, 0xF0);
+        apu.write(NR24_ADDRESS, 0x80);
+        apu.write(WAVE_RAM_START, 0x5A);
+
+        apu.write(NR52_ADDRESS, 0x00);
+        assert_eq!(apu.read(NR52_ADDRESS), 0x70);
+        assert_eq!(apu.read(NR11_ADDRESS), 0x3F);
+        assert_eq!(apu.read(NR12_ADDRESS), 0x00);
+        assert_eq!(apu.read(NR50_ADDRESS), 0x00);
+
+        apu.write(NR50_ADDRESS, 0x77);
+        assert_eq!(apu.read(NR50_ADDRESS), 0x00);
+        apu.write(WAVE_RAM_START + 1, 0xA5);
+        assert_eq!(apu.read(WAVE_RAM_START), 0x5A, "wave RAM survives");
+        assert_eq!(apu.read(WAVE_RAM_START + 1), 0xA5, "and stays writable");
+
+        apu.write(NR52_ADDRESS, 0x80);
+        assert_eq!(apu.read(NR52_ADDRESS), 0xF0);
+        apu.write(NR50_ADDRESS, 0x77);
+        assert_eq!(apu.read(NR50_ADDRESS), 0x77);
+    }
+}
@@ -0,0 +1,106 @@
+use super::units::{Envelope, LengthCounter};
+
+/* Dots between LFSR clocks for each NR43 divisor code, before the shift. */
+const DIVISORS: [u16; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/* Channel 4: pseudo random noise from a 15 bit (or 7 bit) LFSR. */
+pub struct NoiseChannel {
+    enabled: bool,
+    lfsr: u16,
+    clock_shift: u8,
+    short_mode: bool,
+    divisor_code: u8,
+    timer: u32,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    pub fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            lfsr: 0x7FFF,
+            clock_shift: 0,
+            short_mode: false,
+            divisor_code: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn period(&self) -> u32 {
+        (DIVISORS[self.divisor_code as usize] as u32) << self.clock_shift
+    }
+
+    /* Write NR41-NR44, given as 1-4. */
+    pub fn write(&mut self, register: usize, value: u8, extra_length_clock: bool) {
+        match register {
+            1 => self.length.load(value & 0x3F),
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.clock_shift = value >> 4;
+                self.short_mode = value & 0x08 != 0;
+                self.divisor_code = value & 0x07;
+            }
+            4 => {
+                if self.length.set_enabled(value & 0x40 != 0, extra_length_clock) {
+                    self.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.lfsr = 0x7FFF;
+                    self.timer = self.period();
+                    self.envelope.trigger();
+                    self.length.trigger(extra_length_clock);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /*
+    Advance by one dot. Each LFSR clock shifts in the XOR of the
+    two low bits at bit 14, and in 7 bit mode at bit 6 as well.
+    */
+    pub fn step(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.short_mode {
+                self.lfsr = (self.lfsr & !0x40) | (feedback << 6);
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    /* DAC input 0-15, or None while the DAC is off. The output is high while LFSR bit 0 is clear. */
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        Some(if self.enabled && self.lfsr & 0x01 == 0 { self.envelope.volume() } else { 0 })
+    }
+}
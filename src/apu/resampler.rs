@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::io;
+
+use super::{AudioSink, APU_SAMPLE_RATE};
+
+/* Length of the band-limited impulse, in output samples. */
+const TAPS: usize = 16;
+
+/* Fractional positions the impulse is precomputed for. */
+const PHASES: usize = 64;
+
+/* Passband edge as a fraction of the output rate; just under Nyquist. */
+const CUTOFF: f64 = 0.45;
+
+/*
+Band-limited step synthesis. The APU's output only ever moves in
+steps, so rather than filtering every native sample this turns
+each change into a windowed-sinc impulse placed at its exact
+position on the output clock, accumulates those, and integrates
+the result when an output sample is complete. Square waves above
+the output's Nyquist frequency come out as a smooth tone instead
+of aliasing. Output lags the input by half the impulse length.
+*/
+pub struct Resampler {
+    sink: Box<dyn AudioSink>,
+    /* Output samples per APU sample. */
+    step: f64,
+    /* Position of the next APU sample, in output samples after the head of `deltas`. */
+    time: f64,
+    kernel: Vec<[f32; TAPS]>,
+    last: [f32; 2],
+    deltas: [VecDeque<f32>; 2],
+    levels: [f32; 2],
+}
+
+impl Resampler {
+    pub fn new(sink: Box<dyn AudioSink>, output_rate: u32) -> Resampler {
+        Resampler {
+            sink,
+            step: output_rate as f64 / APU_SAMPLE_RATE as f64,
+            time: 0.0,
+            kernel: build_kernel(),
+            last: [0.0; 2],
+            deltas: [VecDeque::from(vec![0.0; TAPS + 1]), VecDeque::from(vec![0.0; TAPS + 1])],
+            levels: [0.0; 2],
+        }
+    }
+
+    fn add_step(&mut self, channel: usize, delta: f32) {
+        let mut start = self.time.floor() as usize;
+        let mut phase = ((self.time - self.time.floor()) * PHASES as f64).round() as usize;
+        if phase == PHASES {
+            start += 1;
+            phase = 0;
+        }
+
+        let deltas = &mut self.deltas[channel];
+        for (tap, &weight) in self.kernel[phase].iter().enumerate() {
+            deltas[start + tap] += delta * weight;
+        }
+    }
+
+    fn emit(&mut self) {
+        for (deltas, level) in self.deltas.iter_mut().zip(self.levels.iter_mut()) {
+            *level += deltas.pop_front().unwrap_or(0.0);
+            deltas.push_back(0.0);
+        }
+        self.sink.push_sample(self.levels[0], self.levels[1]);
+    }
+}
+
+impl AudioSink for Resampler {
+    fn push_sample(&mut self, left: f32, right: f32) {
+        for (channel, value) in [left, right].into_iter().enumerate() {
+            let delta = value - self.last[channel];
+            if delta != 0.0 {
+                self.last[channel] = value;
+                self.add_step(channel, delta);
+            }
+        }
+
+        self.time += self.step;
+        while self.time >= 1.0 {
+            self.time -= 1.0;
+            self.emit();
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+/*
+Blackman windowed sinc impulses, one row per phase. Each row is
+normalised to sum to 1 so a step settles at exactly its height.
+*/
+fn build_kernel() -> Vec<[f32; TAPS]> {
+    let half = TAPS as f64 / 2.0;
+    (0..PHASES)
+        .map(|phase| {
+            let offset = phase as f64 / PHASES as f64;
+            let mut row = [0.0f64; TAPS];
+            for (tap, weight) in row.iter_mut().enumerate() {
+                let x = tap as f64 - half - offset + 1.0;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (2.0 * PI * CUTOFF * x).sin() / (PI * x) / (2.0 * CUTOFF)
+                };
+                let position = (x + half) / TAPS as f64;
+                let window = if (0.0..=1.0).contains(&position) {
+                    0.42 - 0.5 * (2.0 * PI * position).cos() + 0.08 * (4.0 * PI * position).cos()
+                } else {
+                    0.0
+                };
+                *weight = sinc * window;
+            }
+
+            let sum: f64 = row.iter().sum();
+            row.map(|weight| (weight / sum) as f32)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::{Resampler, APU_SAMPLE_RATE, TAPS};
+    use crate::apu::AudioSink;
+
+    const OUTPUT_RATE: u32 = 48_000;
+
+    /* Keeps the left channel of every sample it is given. */
+    struct Recorder(Rc<RefCell<Vec<f32>>>);
+
+    impl AudioSink for Recorder {
+        fn push_sample(&mut self, left: f32, _right: f32) {
+            self.0.borrow_mut().push(left);
+        }
+    }
+
+    /* Resample one second of APU output produced by `input` and return the left channel. */
+    fn resample(input: impl Fn(u32) -> f32) -> Vec<f32> {
+        let samples = Rc::new(RefCell::new(Vec::new()));
+        let mut resampler = Resampler::new(Box::new(Recorder(Rc::clone(&samples))), OUTPUT_RATE);
+        for index in 0..APU_SAMPLE_RATE {
+            let value = input(index);
+            resampler.push_sample(value, value);
+        }
+        samples.take()
+    }
+
+    #[test]
+    fn one_second_in_gives_one_second_out() {
+        let output = resample(|_| 0.5);
+        assert!((OUTPUT_RATE as usize - 1..=OUTPUT_RATE as usize).contains(&output.len()), "{}", output.len());
+        assert!(output[TAPS..].iter().all(|&sample| (sample - 0.5).abs() < 1e-4));
+    }
+
+    #[test]
+    fn a_step_settles_without_ringing_much() {
+        let output = resample(|index| if index < APU_SAMPLE_RATE / 2 { 0.0 } else { 1.0 });
+        let peak = output.iter().cloned().fold(f32::MIN, f32::max);
+        let trough = output.iter().cloned().fold(f32::MAX, f32::min);
+        /* A band-limited step always rings a little; the window keeps it to a few percent. */
+        assert!(peak < 1.05 && trough > -0.05, "overshoot {} / {}", peak, trough);
+        assert!((output[output.len() - 1] - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn tones_above_nyquist_do_not_alias() {
+        /* 65536 Hz: decimating naively would fold this down to a loud 17536 Hz tone. */
+        let output = resample(|index| if index % 16 < 8 { 0.5 } else { -0.5 });
+        let settled = &output[TAPS..];
+        let rms = (settled.iter().map(|sample| sample * sample).sum::<f32>() / settled.len() as f32).sqrt();
+        assert!(rms < 0.02, "rms {}", rms);
+    }
+}
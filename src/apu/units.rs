@@ -0,0 +1,168 @@
+/*
+Length counter shared by every channel. Counts down at 256 Hz
+while enabled and silences the channel when it runs out.
+*/
+pub struct LengthCounter {
+    counter: u16,
+    max: u16,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    pub fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            counter: 0,
+            max,
+            enabled: false,
+        }
+    }
+
+    /* NRx1 holds the length as `max - remaining`. */
+    pub fn load(&mut self, value: u8) {
+        self.counter = self.max - value as u16;
+    }
+
+    /* Returns true when this clock ran the counter out. */
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    /*
+    Update the enable bit from an NRx4 write. When the next frame
+    sequencer step will not clock lengths, enabling the counter
+    clocks it once right away. Returns true if that ran it out.
+    */
+    pub fn set_enabled(&mut self, enabled: bool, extra_clock: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enabled;
+        !was_enabled && extra_clock && self.clock()
+    }
+
+    /* A trigger with an expired counter reloads it, subject to the same extra clock. */
+    pub fn trigger(&mut self, extra_clock: bool) {
+        if self.counter == 0 {
+            self.counter = self.max;
+            if self.enabled && extra_clock {
+                self.counter -= 1;
+            }
+        }
+    }
+}
+
+/* Volume envelope of the square and noise channels, clocked at 64 Hz. */
+pub struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    /* NRx2: initial volume, direction and period. */
+    pub fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+
+    /* The DAC is powered whenever NRx2's top five bits are not all zero. */
+    pub fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Envelope, LengthCounter};
+
+    #[test]
+    fn envelope_steps_once_per_period_within_range() {
+        let mut envelope = Envelope::new();
+        envelope.write(0x22);
+        envelope.trigger();
+        assert_eq!(envelope.volume(), 2);
+
+        let mut volumes = Vec::new();
+        for _ in 0..6 {
+            envelope.clock();
+            volumes.push(envelope.volume());
+        }
+        assert_eq!(volumes, [2, 1, 1, 0, 0, 0]);
+
+        envelope.write(0xE9);
+        envelope.trigger();
+        envelope.clock();
+        envelope.clock();
+        assert_eq!(envelope.volume(), 15);
+    }
+
+    #[test]
+    fn envelope_period_zero_holds_the_volume() {
+        let mut envelope = Envelope::new();
+        envelope.write(0x70);
+        envelope.trigger();
+        envelope.clock();
+        assert_eq!(envelope.volume(), 7);
+        assert!(envelope.dac_enabled());
+
+        envelope.write(0x08);
+        assert!(envelope.dac_enabled(), "increase mode keeps the DAC on");
+        envelope.write(0x07);
+        assert!(!envelope.dac_enabled());
+    }
+
+    #[test]
+    fn trigger_reloads_an_expired_length() {
+        let mut length = LengthCounter::new(64);
+        length.load(63);
+        assert!(!length.clock(), "disabled counters do not count");
+        length.set_enabled(true, false);
+        assert!(length.clock());
+
+        length.trigger(true);
+        for _ in 0..62 {
+            assert!(!length.clock());
+        }
+        assert!(length.clock(), "the extra clock took one off the reload");
+    }
+}
@@ -0,0 +1,126 @@
+use super::units::LengthCounter;
+
+pub const WAVE_RAM_SIZE: usize = 16;
+
+/* Channel 3: plays 32 4-bit samples from wave RAM at one of four volume levels. */
+pub struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    volume_code: u8,
+    frequency: u16,
+    timer: u16,
+    position: u8,
+    sample: u8,
+    ram: [u8; WAVE_RAM_SIZE],
+    length: LengthCounter,
+}
+
+impl WaveChannel {
+    pub fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            ram: [0; WAVE_RAM_SIZE],
+            length: LengthCounter::new(256),
+        }
+    }
+
+    /* Powering the APU down resets everything except wave RAM. */
+    pub fn power_off(&mut self) {
+        *self = WaveChannel {
+            ram: self.ram,
+            ..WaveChannel::new()
+        };
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+
+    /* Write NR30-NR34, given as 0-4. */
+    pub fn write(&mut self, register: usize, value: u8, extra_length_clock: bool) {
+        match register {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value),
+            2 => self.volume_code = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | (((value & 0x07) as u16) << 8);
+                if self.length.set_enabled(value & 0x40 != 0, extra_length_clock) {
+                    self.enabled = false;
+                }
+                if value & 0x80 != 0 {
+                    self.enabled = self.dac_enabled;
+                    self.timer = self.period();
+                    self.position = 0;
+                    self.length.trigger(extra_length_clock);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /*
+    While the channel plays, the CPU can only reach the byte the
+    channel is currently reading, whatever address it uses.
+    */
+    pub fn read_ram(&self, index: usize) -> u8 {
+        if self.enabled {
+            self.ram[self.position as usize / 2]
+        } else {
+            self.ram[index]
+        }
+    }
+
+    pub fn write_ram(&mut self, index: usize, value: u8) {
+        if self.enabled {
+            self.ram[self.position as usize / 2] = value;
+        } else {
+            self.ram[index] = value;
+        }
+    }
+
+    /* Advance the frequency timer by one dot, moving to the next sample when it expires. */
+    pub fn step(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.position = (self.position + 1) & 0x1F;
+            let byte = self.ram[self.position as usize / 2];
+            self.sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /* DAC input 0-15, or None while the DAC is off. Volume codes 1-3 shift the sample by 0-2; 0 mutes. */
+    pub fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        if !self.enabled || self.volume_code == 0 {
+            return Some(0);
+        }
+        Some(self.sample >> (self.volume_code - 1))
+    }
+}
@@ -0,0 +1,340 @@
+use std::fmt;
+
+use crate::checksum::crc32;
+use crate::inflate::{inflate, InflateError};
+
+const ZIP_LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4B50;
+const ZIP_CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4B50;
+const ZIP_END_OF_DIRECTORY_SIGNATURE: u32 = 0x0605_4B50;
+const ZIP_END_OF_DIRECTORY_SIZE: usize = 22;
+
+const ZIP_METHOD_STORED: u16 = 0;
+const ZIP_METHOD_DEFLATE: u16 = 8;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const GZIP_FLAG_HEADER_CRC: u8 = 1 << 1;
+const GZIP_FLAG_EXTRA: u8 = 1 << 2;
+const GZIP_FLAG_NAME: u8 = 1 << 3;
+const GZIP_FLAG_COMMENT: u8 = 1 << 4;
+
+const ROM_EXTENSIONS: [&str; 2] = [".gb", ".gbc"];
+
+#[derive(Debug, PartialEq)]
+pub enum ArchiveError {
+    Truncated,
+    InvalidHeader,
+    UnsupportedCompression(u16),
+    Inflate(InflateError),
+    ChecksumMismatch { expected: u32, actual: u32 },
+    EntryNotFound(String),
+    NoRomEntry,
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::Truncated => write!(f, "archive ends unexpectedly"),
+            ArchiveError::InvalidHeader => write!(f, "archive header is corrupt"),
+            ArchiveError::UnsupportedCompression(method) => {
+                write!(f, "unsupported compression method {}", method)
+            }
+            ArchiveError::Inflate(err) => write!(f, "{}", err),
+            ArchiveError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "decompressed data has CRC32 {:08X}, archive expects {:08X}",
+                actual, expected
+            ),
+            ArchiveError::EntryNotFound(name) => write!(f, "archive has no entry named {}", name),
+            ArchiveError::NoRomEntry => write!(f, "archive contains no .gb or .gbc file"),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<InflateError> for ArchiveError {
+    fn from(err: InflateError) -> Self {
+        ArchiveError::Inflate(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ArchiveKind {
+    Zip,
+    Gzip,
+}
+
+impl ArchiveKind {
+    /* Identify an archive by its magic bytes. Plain ROMs return None. */
+    pub fn detect(data: &[u8]) -> Option<ArchiveKind> {
+        if data.len() >= 4 && read_u32(data, 0) == Some(ZIP_LOCAL_HEADER_SIGNATURE) {
+            Some(ArchiveKind::Zip)
+        } else if data.starts_with(&GZIP_MAGIC) {
+            Some(ArchiveKind::Gzip)
+        } else {
+            None
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/*
+Return the ROM bytes held in `data`. Zip archives yield the entry
+called `entry`, or the first .gb/.gbc entry when no name is given.
+Anything that is not an archive is returned unchanged.
+*/
+pub fn extract_rom(data: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>, ArchiveError> {
+    match ArchiveKind::detect(&data) {
+        Some(ArchiveKind::Zip) => extract_zip(&data, entry),
+        Some(ArchiveKind::Gzip) => extract_gzip(&data),
+        None => Ok(data),
+    }
+}
+
+struct ZipEntry<'a> {
+    name: &'a str,
+    method: u16,
+    crc: u32,
+    compressed_size: usize,
+    local_header_offset: usize,
+}
+
+fn is_rom_name(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    ROM_EXTENSIONS.iter().any(|extension| name.ends_with(extension))
+}
+
+fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry<'_>>, ArchiveError> {
+    /* The end of central directory record sits at the end, before an optional comment. */
+    let search_start = data.len().saturating_sub(ZIP_END_OF_DIRECTORY_SIZE + 0xFFFF);
+    let end = (search_start..=data.len().saturating_sub(ZIP_END_OF_DIRECTORY_SIZE))
+        .rev()
+        .find(|&offset| read_u32(data, offset) == Some(ZIP_END_OF_DIRECTORY_SIGNATURE))
+        .ok_or(ArchiveError::InvalidHeader)?;
+
+    let entry_count = read_u16(data, end + 10).ok_or(ArchiveError::Truncated)? as usize;
+    let mut offset = read_u32(data, end + 16).ok_or(ArchiveError::Truncated)? as usize;
+
+    let mut entries = Vec::with_capacity(entry_count);
+    for _ in 0..entry_count {
+        if read_u32(data, offset) != Some(ZIP_CENTRAL_HEADER_SIGNATURE) {
+            return Err(ArchiveError::InvalidHeader);
+        }
+
+        let field = |position: usize| read_u16(data, offset + position).ok_or(ArchiveError::Truncated);
+        let word = |position: usize| read_u32(data, offset + position).ok_or(ArchiveError::Truncated);
+
+        let name_length = field(28)? as usize;
+        let extra_length = field(30)? as usize;
+        let comment_length = field(32)? as usize;
+        let name_bytes = data
+            .get(offset + 46..offset + 46 + name_length)
+            .ok_or(ArchiveError::Truncated)?;
+
+        entries.push(ZipEntry {
+            name: std::str::from_utf8(name_bytes).map_err(|_| ArchiveError::InvalidHeader)?,
+            method: field(10)?,
+            crc: word(16)?,
+            compressed_size: word(20)? as usize,
+            local_header_offset: word(42)? as usize,
+        });
+
+        offset += 46 + name_length + extra_length + comment_length;
+    }
+
+    Ok(entries)
+}
+
+fn extract_zip(data: &[u8], entry: Option<&str>) -> Result<Vec<u8>, ArchiveError> {
+    let entries = zip_entries(data)?;
+
+    /* A requested name matches either the full path or just the file name. */
+    let selected = match entry {
+        Some(wanted) => entries
+            .iter()
+            .find(|candidate| candidate.name == wanted || candidate.name.rsplit('/').next() == Some(wanted))
+            .ok_or_else(|| ArchiveError::EntryNotFound(wanted.to_string()))?,
+        None => entries
+            .iter()
+            .find(|candidate| is_rom_name(candidate.name))
+            .ok_or(ArchiveError::NoRomEntry)?,
+    };
+
+    let header = selected.local_header_offset;
+    if read_u32(data, header) != Some(ZIP_LOCAL_HEADER_SIGNATURE) {
+        return Err(ArchiveError::InvalidHeader);
+    }
+    let name_length = read_u16(data, header + 26).ok_or(ArchiveError::Truncated)? as usize;
+    let extra_length = read_u16(data, header + 28).ok_or(ArchiveError::Truncated)? as usize;
+    let start = header + 30 + name_length + extra_length;
+    let compressed = data
+        .get(start..start + selected.compressed_size)
+        .ok_or(ArchiveError::Truncated)?;
+
+    let contents = match selected.method {
+        ZIP_METHOD_STORED => compressed.to_vec(),
+        ZIP_METHOD_DEFLATE => inflate(compressed)?.0,
+        method => return Err(ArchiveError::UnsupportedCompression(method)),
+    };
+
+    verify_crc(&contents, selected.crc)?;
+    Ok(contents)
+}
+
+fn extract_gzip(data: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    let method = *data.get(2).ok_or(ArchiveError::Truncated)?;
+    if method != ZIP_METHOD_DEFLATE as u8 {
+        return Err(ArchiveError::UnsupportedCompression(method as u16));
+    }
+    let flags = *data.get(3).ok_or(ArchiveError::Truncated)?;
+
+    /* Skip the optional header fields that follow the fixed ten bytes. */
+    let mut offset = 10;
+    if flags & GZIP_FLAG_EXTRA != 0 {
+        offset += 2 + read_u16(data, offset).ok_or(ArchiveError::Truncated)? as usize;
+    }
+    for flag in [GZIP_FLAG_NAME, GZIP_FLAG_COMMENT] {
+        if flags & flag != 0 {
+            let terminator = data
+                .get(offset..)
+                .and_then(|rest| rest.iter().position(|&byte| byte == 0))
+                .ok_or(ArchiveError::Truncated)?;
+            offset += terminator + 1;
+        }
+    }
+    if flags & GZIP_FLAG_HEADER_CRC != 0 {
+        offset += 2;
+    }
+
+    let stream = data.get(offset..).ok_or(ArchiveError::Truncated)?;
+    let (contents, consumed) = inflate(stream)?;
+
+    let trailer = offset + consumed;
+    let expected_crc = read_u32(data, trailer).ok_or(ArchiveError::Truncated)?;
+    verify_crc(&contents, expected_crc)?;
+    Ok(contents)
+}
+
+fn verify_crc(contents: &[u8], expected: u32) -> Result<(), ArchiveError> {
+    let actual = crc32(contents);
+    if actual != expected {
+        return Err(ArchiveError::ChecksumMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract_rom, ArchiveError};
+    use crate::checksum::crc32;
+
+    /* "hello, hello, hello!" as a fixed Huffman DEFLATE stream. */
+    const DEFLATED: [u8; 12] = [0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0xC8, 0x40, 0xA2, 0x14, 0x01];
+    const INFLATED: &[u8] = b"hello, hello, hello!";
+
+    fn gzip(crc: u32) -> Vec<u8> {
+        let mut data = vec![0x1F, 0x8B, 0x08, 0x08, 0, 0, 0, 0, 0x00, 0xFF];
+        data.extend_from_slice(b"game.gb\0");
+        data.extend_from_slice(&DEFLATED);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data.extend_from_slice(&(INFLATED.len() as u32).to_le_bytes());
+        data
+    }
+
+    /* A zip archive of stored entries, except names ending in .gz which hold DEFLATED. */
+    fn zip(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut directory = Vec::new();
+
+        for &(name, contents) in entries {
+            let (method, crc, stored): (u16, u32, &[u8]) = match name.strip_suffix(".gz") {
+                Some(_) => (8, crc32(INFLATED), &DEFLATED),
+                None => (0, crc32(contents), contents),
+            };
+            let name = name.trim_end_matches(".gz");
+            let offset = data.len() as u32;
+
+            data.extend_from_slice(&0x0403_4B50u32.to_le_bytes());
+            data.extend_from_slice(&[20, 0, 0, 0]);
+            data.extend_from_slice(&method.to_le_bytes());
+            data.extend_from_slice(&[0; 4]);
+            data.extend_from_slice(&crc.to_le_bytes());
+            data.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend_from_slice(&[0, 0]);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(stored);
+
+            directory.extend_from_slice(&0x0201_4B50u32.to_le_bytes());
+            directory.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            directory.extend_from_slice(&method.to_le_bytes());
+            directory.extend_from_slice(&[0; 4]);
+            directory.extend_from_slice(&crc.to_le_bytes());
+            directory.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&[0; 12]);
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+
+        let directory_offset = data.len() as u32;
+        data.extend_from_slice(&directory);
+        data.extend_from_slice(&0x0605_4B50u32.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        data.extend_from_slice(&directory_offset.to_le_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data
+    }
+
+    #[test]
+    fn plain_roms_pass_through() {
+        assert_eq!(extract_rom(vec![0x00, 0xC3, 0x50], None).unwrap(), [0x00, 0xC3, 0x50]);
+    }
+
+    #[test]
+    fn gzip_is_inflated_and_checked() {
+        assert_eq!(extract_rom(gzip(crc32(INFLATED)), None).unwrap(), INFLATED);
+
+        let actual = crc32(INFLATED);
+        assert_eq!(
+            extract_rom(gzip(actual ^ 1), None),
+            Err(ArchiveError::ChecksumMismatch { expected: actual ^ 1, actual })
+        );
+    }
+
+    #[test]
+    fn zip_picks_the_first_rom_entry() {
+        let archive = zip(&[
+            ("readme.txt", b"not a rom"),
+            ("roms/Game.GBC.gz", INFLATED),
+            ("other.gb", b"second rom"),
+        ]);
+
+        assert_eq!(extract_rom(archive.clone(), None).unwrap(), INFLATED);
+        assert_eq!(extract_rom(archive.clone(), Some("other.gb")).unwrap(), b"second rom");
+        assert_eq!(extract_rom(archive.clone(), Some("readme.txt")).unwrap(), b"not a rom");
+        assert_eq!(
+            extract_rom(archive, Some("missing.gb")),
+            Err(ArchiveError::EntryNotFound("missing.gb".to_string()))
+        );
+    }
+
+    #[test]
+    fn zip_without_a_rom_entry() {
+        let archive = zip(&[("readme.txt", b"not a rom"), ("notes.md", b"still not")]);
+        assert_eq!(extract_rom(archive, None), Err(ArchiveError::NoRomEntry));
+    }
+}
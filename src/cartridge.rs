@@ -0,0 +1,804 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::archive::{self, ArchiveError};
+use crate::patch::{self, PatchError};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+const MBC2_RAM_SIZE: usize = 0x200;
+
+const TITLE_START: usize = 0x134;
+const TITLE_END: usize = 0x144;
+const CGB_FLAG_ADDRESS: usize = 0x143;
+const CARTRIDGE_TYPE_ADDRESS: usize = 0x147;
+const ROM_SIZE_ADDRESS: usize = 0x148;
+const RAM_SIZE_ADDRESS: usize = 0x149;
+const HEADER_END: usize = 0x150;
+
+/* Size of the RTC block appended to MBC3 saves by BGB, VBA-M, SameBoy and mGBA. */
+const RTC_TRAILER_SIZE: usize = 48;
+
+/* M-cycles per emulated second at normal speed. */
+const CYCLES_PER_SECOND: u32 = 1 << 20;
+
+/*
+Dirty save RAM is flushed once this many M-cycles have passed
+without another write, so a burst of writes only hits the disk once.
+*/
+const AUTOSAVE_DELAY_CYCLES: u32 = CYCLES_PER_SECOND;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    TooSmall(usize),
+    UnsupportedType(u8),
+    Archive(ArchiveError),
+    Patch(PatchError),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(err) => write!(f, "{}", err),
+            CartridgeError::TooSmall(size) => {
+                write!(f, "ROM is {} bytes, too small to hold a cartridge header", size)
+            }
+            CartridgeError::UnsupportedType(code) => {
+                write!(f, "unsupported cartridge type 0x{:02X}", code)
+            }
+            CartridgeError::Archive(err) => write!(f, "failed to read archive: {}", err),
+            CartridgeError::Patch(err) => write!(f, "failed to apply patch: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<io::Error> for CartridgeError {
+    fn from(err: io::Error) -> Self {
+        CartridgeError::Io(err)
+    }
+}
+
+impl From<ArchiveError> for CartridgeError {
+    fn from(err: ArchiveError) -> Self {
+        CartridgeError::Archive(err)
+    }
+}
+
+impl From<PatchError> for CartridgeError {
+    fn from(err: PatchError) -> Self {
+        CartridgeError::Patch(err)
+    }
+}
+
+/* Optional steps applied while reading a cartridge from disk. */
+#[derive(Clone, Debug, Default)]
+pub struct LoadOptions {
+    /* Entry to load from a `.zip`; the first `.gb`/`.gbc` entry is used when unset. */
+    pub archive_entry: Option<String>,
+    /* IPS, UPS or BPS patch applied to the ROM before the header is parsed. */
+    pub patch: Option<PathBuf>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MbcKind {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+/*
+The battery save sits next to the ROM and is named after it, so
+`game.gb`, `game.gb.gz` and `game.zip` all save to `game.sav`.
+*/
+fn save_path_for(path: &Path) -> PathBuf {
+    let mut stem = path.to_path_buf();
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        if extension.eq_ignore_ascii_case("gz") || extension.eq_ignore_ascii_case("zip") {
+            stem.set_extension("");
+        }
+    }
+    stem.with_extension("sav")
+}
+
+/*
+Decoded cartridge header fields that the loader
+and the rest of the emulator care about.
+*/
+#[derive(Clone, Debug)]
+pub struct Header {
+    pub title: String,
+    pub cgb_flag: u8,
+    pub cartridge_type: u8,
+    pub mbc: MbcKind,
+    pub has_ram: bool,
+    pub has_battery: bool,
+    pub has_rtc: bool,
+    pub rom_banks: usize,
+    pub ram_size: usize,
+}
+
+impl Header {
+    /* Bit 7 of the CGB flag marks games that use CGB features (0x80) or require them (0xC0). */
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    fn parse(rom: &[u8]) -> Result<Header, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+
+        let cartridge_type = rom[CARTRIDGE_TYPE_ADDRESS];
+        let (mbc, has_ram, has_battery, has_rtc) = match cartridge_type {
+            0x00 => (MbcKind::RomOnly, false, false, false),
+            0x08 => (MbcKind::RomOnly, true, false, false),
+            0x09 => (MbcKind::RomOnly, true, true, false),
+            0x01 => (MbcKind::Mbc1, false, false, false),
+            0x02 => (MbcKind::Mbc1, true, false, false),
+            0x03 => (MbcKind::Mbc1, true, true, false),
+            0x05 => (MbcKind::Mbc2, true, false, false),
+            0x06 => (MbcKind::Mbc2, true, true, false),
+            0x0F => (MbcKind::Mbc3, false, true, true),
+            0x10 => (MbcKind::Mbc3, true, true, true),
+            0x11 => (MbcKind::Mbc3, false, false, false),
+            0x12 => (MbcKind::Mbc3, true, false, false),
+            0x13 => (MbcKind::Mbc3, true, true, false),
+            0x19 | 0x1C => (MbcKind::Mbc5, false, false, false),
+            0x1A | 0x1D => (MbcKind::Mbc5, true, false, false),
+            0x1B | 0x1E => (MbcKind::Mbc5, true, true, false),
+            code => return Err(CartridgeError::UnsupportedType(code)),
+        };
+
+        /* The ROM size byte is a shift amount on top of two 16 KiB banks. */
+        let rom_banks = 2usize << (rom[ROM_SIZE_ADDRESS] & 0x0F);
+
+        let ram_size = if mbc == MbcKind::Mbc2 {
+            MBC2_RAM_SIZE
+        } else if !has_ram {
+            0
+        } else {
+            match rom[RAM_SIZE_ADDRESS] {
+                0x01 => 0x800,
+                0x02 => RAM_BANK_SIZE,
+                0x03 => RAM_BANK_SIZE * 4,
+                0x04 => RAM_BANK_SIZE * 16,
+                0x05 => RAM_BANK_SIZE * 8,
+                _ => 0,
+            }
+        };
+
+        let title = rom[TITLE_START..TITLE_END]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .filter(|byte| byte.is_ascii_graphic() || **byte == b' ')
+            .map(|&byte| byte as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        Ok(Header {
+            title,
+            cgb_flag: rom[CGB_FLAG_ADDRESS],
+            cartridge_type,
+            mbc,
+            has_ram,
+            has_battery,
+            has_rtc,
+            rom_banks,
+            ram_size,
+        })
+    }
+}
+
+/*
+MBC3 real time clock. Registers are stored in the order
+they are selected through 0x4000: S, M, H, DL, DH.
+*/
+#[derive(Copy, Clone, Default)]
+struct Rtc {
+    registers: [u8; 5],
+    latched: [u8; 5],
+    latch_armed: bool,
+    cycles: u32,
+}
+
+const RTC_SECONDS: usize = 0;
+const RTC_MINUTES: usize = 1;
+const RTC_HOURS: usize = 2;
+const RTC_DAY_LOW: usize = 3;
+const RTC_DAY_HIGH: usize = 4;
+
+const RTC_HALT_BIT: u8 = 1 << 6;
+const RTC_DAY_CARRY_BIT: u8 = 1 << 7;
+
+impl Rtc {
+    fn is_halted(&self) -> bool {
+        self.registers[RTC_DAY_HIGH] & RTC_HALT_BIT != 0
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.is_halted() {
+            return;
+        }
+
+        self.cycles += cycles;
+        if self.cycles >= CYCLES_PER_SECOND {
+            let seconds = self.cycles / CYCLES_PER_SECOND;
+            self.cycles %= CYCLES_PER_SECOND;
+            self.advance(seconds as u64);
+        }
+    }
+
+    /* Move the clock forward by a number of whole seconds. */
+    fn advance(&mut self, seconds: u64) {
+        if self.is_halted() || seconds == 0 {
+            return;
+        }
+
+        let mut total = self.registers[RTC_SECONDS] as u64 + seconds;
+        self.registers[RTC_SECONDS] = (total % 60) as u8;
+        total = total / 60 + self.registers[RTC_MINUTES] as u64;
+        self.registers[RTC_MINUTES] = (total % 60) as u8;
+        total = total / 60 + self.registers[RTC_HOURS] as u64;
+        self.registers[RTC_HOURS] = (total % 24) as u8;
+        total = total / 24 + self.day_counter() as u64;
+
+        if total > 0x1FF {
+            self.registers[RTC_DAY_HIGH] |= RTC_DAY_CARRY_BIT;
+            total &= 0x1FF;
+        }
+        self.registers[RTC_DAY_LOW] = (total & 0xFF) as u8;
+        self.registers[RTC_DAY_HIGH] = (self.registers[RTC_DAY_HIGH] & !0x01) | ((total >> 8) as u8 & 0x01);
+    }
+
+    fn day_counter(&self) -> u16 {
+        ((self.registers[RTC_DAY_HIGH] as u16 & 0x01) << 8) | self.registers[RTC_DAY_LOW] as u16
+    }
+
+    /*
+    Writing 0x00 then 0x01 to 0x6000 copies the live clock into the
+    latched registers. Returns true if the latched values changed.
+    */
+    fn write_latch(&mut self, value: u8) -> bool {
+        let changed = self.latch_armed && value == 0x01 && self.latched != self.registers;
+        if changed {
+            self.latched = self.registers;
+        }
+        self.latch_armed = value == 0x00;
+        changed
+    }
+
+    fn read(&self, register: usize) -> u8 {
+        let value = self.latched[register];
+        match register {
+            RTC_DAY_HIGH => value | 0x3E,
+            _ => value,
+        }
+    }
+
+    fn write(&mut self, register: usize, value: u8) {
+        if register == RTC_SECONDS {
+            self.cycles = 0;
+        }
+        self.registers[register] = value;
+    }
+
+    /*
+    Serialize in the 48 byte format: five live registers and five
+    latched registers as little endian u32s, then a u64 unix timestamp.
+    */
+    fn to_trailer(self, timestamp: u64) -> [u8; RTC_TRAILER_SIZE] {
+        let mut trailer = [0; RTC_TRAILER_SIZE];
+        let values = self.registers.iter().chain(self.latched.iter());
+        for (index, &value) in values.enumerate() {
+            trailer[index * 4..index * 4 + 4].copy_from_slice(&(value as u32).to_le_bytes());
+        }
+        trailer[40..48].copy_from_slice(&timestamp.to_le_bytes());
+        trailer
+    }
+
+    fn from_trailer(trailer: &[u8]) -> (Rtc, u64) {
+        let mut rtc = Rtc::default();
+        for index in 0..10 {
+            let bytes = [trailer[index * 4], trailer[index * 4 + 1], trailer[index * 4 + 2], trailer[index * 4 + 3]];
+            let value = u32::from_le_bytes(bytes) as u8;
+            if index < 5 {
+                rtc.registers[index] = value;
+            } else {
+                rtc.latched[index - 5] = value;
+            }
+        }
+
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&trailer[40..48]);
+        (rtc, u64::from_le_bytes(timestamp))
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+pub struct Cartridge {
+    pub header: Header,
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    banking_mode: u8,
+    rtc: Rtc,
+    save_path: Option<PathBuf>,
+    /* Set when RAM or the RTC has changed since the save file was last written. */
+    save_dirty: bool,
+    cycles_since_write: u32,
+}
+
+impl Cartridge {
+    /*
+    Build a cartridge from ROM bytes already in memory.
+    Save RAM starts zeroed and is never written to disk.
+    */
+    pub fn new(rom: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        let header = Header::parse(&rom)?;
+        let ram = vec![0; header.ram_size];
+
+        Ok(Cartridge {
+            header,
+            rom,
+            ram,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            banking_mode: 0,
+            rtc: Rtc::default(),
+            save_path: None,
+            save_dirty: false,
+            cycles_since_write: 0,
+        })
+    }
+
+    /*
+    Wrap a headerless ROM image, such as the code of a GBS rip.
+    It is banked through 0x2000-0x3FFF like an MBC5 and gets 8 KiB
+    of RAM that needs no enabling.
+    */
+    pub fn from_image(title: String, rom: Vec<u8>) -> Cartridge {
+        let header = Header {
+            title,
+            cgb_flag: 0x00,
+            cartridge_type: 0x1A,
+            mbc: MbcKind::Mbc5,
+            has_ram: true,
+            has_battery: false,
+            has_rtc: false,
+            rom_banks: (rom.len() / ROM_BANK_SIZE).max(1),
+            ram_size: RAM_BANK_SIZE,
+        };
+
+        Cartridge {
+            header,
+            rom,
+            ram: vec![0; RAM_BANK_SIZE],
+            ram_enabled: true,
+            rom_bank: 1,
+            ram_bank: 0,
+            banking_mode: 0,
+            rtc: Rtc::default(),
+            save_path: None,
+            save_dirty: false,
+            cycles_since_write: 0,
+        }
+    }
+
+    /*
+    Load a ROM from disk. Battery backed cartridges pick up
+    the `.sav` file next to the ROM and keep it up to date.
+    `.zip` and `.gz` files are decompressed in memory.
+    */
+    pub fn load<P: AsRef<Path>>(path: P, options: &LoadOptions) -> Result<Cartridge, CartridgeError> {
+        let path = path.as_ref();
+        let mut rom = archive::extract_rom(fs::read(path)?, options.archive_entry.as_deref())?;
+
+        if let Some(patch_path) = &options.patch {
+            let patch_data = fs::read(patch_path)?;
+            rom = patch::apply(&rom, &patch_data)?;
+        }
+
+        let mut cartridge = Cartridge::new(rom)?;
+
+        if cartridge.header.has_battery {
+            cartridge.attach_save(save_path_for(path))?;
+        }
+
+        Ok(cartridge)
+    }
+
+    /*
+    Use `path` as the battery save for this cartridge,
+    loading its contents if the file already exists.
+    */
+    pub fn attach_save<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref().to_path_buf();
+
+        match fs::read(&path) {
+            Ok(data) => self.load_save_data(&data),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        self.save_path = Some(path);
+        Ok(())
+    }
+
+    /*
+    Raw RAM dump, optionally followed by the RTC trailer.
+    Short files only fill the start of RAM; extra bytes are ignored.
+    */
+    fn load_save_data(&mut self, data: &[u8]) {
+        let ram_length = self.ram.len().min(data.len());
+        self.ram[..ram_length].copy_from_slice(&data[..ram_length]);
+
+        if self.header.has_rtc && data.len() >= self.ram.len() + RTC_TRAILER_SIZE {
+            let trailer = &data[self.ram.len()..self.ram.len() + RTC_TRAILER_SIZE];
+            let (rtc, timestamp) = Rtc::from_trailer(trailer);
+            self.rtc = rtc;
+            self.rtc.advance(unix_time().saturating_sub(timestamp));
+        }
+    }
+
+    pub fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if self.header.has_rtc {
+            data.extend_from_slice(&self.rtc.to_trailer(unix_time()));
+        }
+        data
+    }
+
+    /*
+    Note a change that belongs in the save file. Writes restart the
+    autosave delay; `settle` false leaves it running, for changes such
+    as RTC latches that games can make every frame.
+    */
+    fn mark_dirty(&mut self, settle: bool) {
+        if !self.header.has_battery {
+            return;
+        }
+        self.save_dirty = true;
+        if settle {
+            self.cycles_since_write = 0;
+        }
+    }
+
+    /* Write save RAM to the attached `.sav` file if anything changed. */
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        if !self.save_dirty {
+            return Ok(());
+        }
+
+        if let Some(path) = &self.save_path {
+            fs::write(path, self.save_data())?;
+        }
+        self.save_dirty = false;
+        self.cycles_since_write = 0;
+        Ok(())
+    }
+
+    /* Advance the RTC and flush save RAM once writes have settled. */
+    pub fn tick(&mut self, cycles: u32) {
+        if self.header.has_rtc {
+            self.rtc.tick(cycles);
+        }
+
+        if self.save_dirty {
+            self.cycles_since_write = self.cycles_since_write.saturating_add(cycles);
+            if self.cycles_since_write >= AUTOSAVE_DELAY_CYCLES {
+                if let Err(err) = self.flush_save() {
+                    eprintln!("Failed to write save file: {}", err);
+                }
+            }
+        }
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        (self.rom.len() / ROM_BANK_SIZE).max(1)
+    }
+
+    fn read_rom_bank(&self, bank: usize, address: u16) -> u8 {
+        let bank = bank % self.rom_bank_count();
+        let offset = bank * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1));
+        self.rom.get(offset).copied().unwrap_or(0xFF)
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+
+        let bank = match self.header.mbc {
+            MbcKind::Mbc1 if self.banking_mode == 1 => self.ram_bank as usize & 0x03,
+            MbcKind::Mbc3 | MbcKind::Mbc5 => self.ram_bank as usize,
+            _ => 0,
+        };
+        let offset = bank * RAM_BANK_SIZE + (address as usize - 0xA000);
+        Some(offset % self.ram.len())
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x3FFF => {
+                let bank = match self.header.mbc {
+                    MbcKind::Mbc1 if self.banking_mode == 1 => (self.ram_bank as usize & 0x03) << 5,
+                    _ => 0,
+                };
+                self.read_rom_bank(bank, address)
+            }
+            0x4000..=0x7FFF => {
+                let bank = match self.header.mbc {
+                    MbcKind::RomOnly => 1,
+                    MbcKind::Mbc1 => ((self.ram_bank as usize & 0x03) << 5) | self.rom_bank as usize,
+                    _ => self.rom_bank as usize,
+                };
+                self.read_rom_bank(bank, address)
+            }
+            0xA000..=0xBFFF => self.read_ram(address),
+            _ => 0xFF,
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled && self.header.mbc != MbcKind::RomOnly {
+            return 0xFF;
+        }
+
+        match self.header.mbc {
+            MbcKind::Mbc2 => 0xF0 | self.ram[address as usize & (MBC2_RAM_SIZE - 1)],
+            MbcKind::Mbc3 if self.ram_bank >= 0x08 => {
+                if self.header.has_rtc && self.ram_bank <= 0x0C {
+                    self.rtc.read(self.ram_bank as usize - 0x08)
+                } else {
+                    0xFF
+                }
+            }
+            _ => match self.ram_offset(address) {
+                Some(offset) => self.ram[offset],
+                None => 0xFF,
+            },
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x7FFF => self.write_control(address, value),
+            0xA000..=0xBFFF => self.write_ram(address, value),
+            _ => {}
+        }
+    }
+
+    /* Writes to the ROM area drive the memory bank controller. */
+    fn write_control(&mut self, address: u16, value: u8) {
+        match self.header.mbc {
+            MbcKind::RomOnly => {}
+            MbcKind::Mbc1 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+                0x2000..=0x3FFF => self.rom_bank = (value & 0x1F).max(1) as u16,
+                0x4000..=0x5FFF => self.ram_bank = value & 0x03,
+                _ => self.banking_mode = value & 0x01,
+            },
+            MbcKind::Mbc2 => {
+                if address <= 0x3FFF {
+                    if address & 0x0100 == 0 {
+                        self.ram_enabled = value & 0x0F == 0x0A;
+                    } else {
+                        self.rom_bank = (value & 0x0F).max(1) as u16;
+                    }
+                }
+            }
+            MbcKind::Mbc3 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+                0x2000..=0x3FFF => self.rom_bank = (value & 0x7F).max(1) as u16,
+                0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+                _ => {
+                    if self.header.has_rtc && self.rtc.write_latch(value) {
+                        self.mark_dirty(false);
+                    }
+                }
+            },
+            MbcKind::Mbc5 => match address {
+                0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+                0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+                0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((value as u16 & 0x01) << 8),
+                0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+                _ => {}
+            },
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled && self.header.mbc != MbcKind::RomOnly {
+            return;
+        }
+
+        match self.header.mbc {
+            MbcKind::Mbc2 => {
+                self.ram[address as usize & (MBC2_RAM_SIZE - 1)] = value & 0x0F;
+            }
+            /* RTC registers, including the halt bit in DH, are saved in the trailer. */
+            MbcKind::Mbc3 if self.ram_bank >= 0x08 => {
+                if !self.header.has_rtc || self.ram_bank > 0x0C {
+                    return;
+                }
+                self.rtc.write(self.ram_bank as usize - 0x08, value);
+            }
+            _ => match self.ram_offset(address) {
+                Some(offset) => self.ram[offset] = value,
+                None => return,
+            },
+        }
+
+        self.mark_dirty(true);
+    }
+}
+
+/* Flush on exit so progress since the last autosave is not lost. */
+impl Drop for Cartridge {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush_save() {
+            eprintln!("Failed to write save file: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::{Cartridge, AUTOSAVE_DELAY_CYCLES, RAM_BANK_SIZE, ROM_BANK_SIZE, RTC_HALT_BIT, RTC_TRAILER_SIZE};
+
+    /* A cartridge whose every ROM bank starts with its bank number, low byte then high byte. */
+    fn cartridge(cartridge_type: u8, rom_size_code: u8, ram_size_code: u8) -> Cartridge {
+        let banks = 2usize << rom_size_code;
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE..bank * ROM_BANK_SIZE + 2].copy_from_slice(&(bank as u16).to_le_bytes());
+        }
+        rom[0x147] = cartridge_type;
+        rom[0x148] = rom_size_code;
+        rom[0x149] = ram_size_code;
+        Cartridge::new(rom).unwrap()
+    }
+
+    fn bank_at(cartridge: &Cartridge, address: u16) -> u16 {
+        u16::from_le_bytes([cartridge.read(address), cartridge.read(address + 1)])
+    }
+
+    #[test]
+    fn mbc1_bank_zero_selects_bank_one() {
+        let mut cartridge = cartridge(0x01, 0x05, 0x00);
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(bank_at(&cartridge, 0x4000), 1);
+        cartridge.write(0x2000, 0x05);
+        assert_eq!(bank_at(&cartridge, 0x4000), 5);
+
+        /* Banks 0x20, 0x40 and 0x60 are unreachable: the zero check only sees the low five bits. */
+        cartridge.write(0x2000, 0x00);
+        cartridge.write(0x4000, 0x01);
+        assert_eq!(bank_at(&cartridge, 0x4000), 0x21);
+    }
+
+    #[test]
+    fn mbc1_mode_one_banks_the_lower_area() {
+        let mut cartridge = cartridge(0x01, 0x05, 0x00);
+        cartridge.write(0x4000, 0x01);
+        assert_eq!(bank_at(&cartridge, 0x0000), 0);
+
+        cartridge.write(0x6000, 0x01);
+        assert_eq!(bank_at(&cartridge, 0x0000), 0x20);
+        assert_eq!(bank_at(&cartridge, 0x4000), 0x21);
+    }
+
+    #[test]
+    fn mbc1_mode_one_banks_ram() {
+        let mut cartridge = cartridge(0x03, 0x05, 0x03);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x4000, 0x02);
+        cartridge.write(0xA000, 0x42);
+
+        /* In mode 0 the RAM bank bits only reach the ROM, so that was bank 0. */
+        cartridge.write(0x6000, 0x01);
+        assert_eq!(cartridge.read(0xA000), 0x00);
+        cartridge.write(0x4000, 0x00);
+        assert_eq!(cartridge.read(0xA000), 0x42);
+    }
+
+    #[test]
+    fn mbc5_uses_a_nine_bit_bank_number() {
+        let mut cartridge = cartridge(0x19, 0x08, 0x00);
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(bank_at(&cartridge, 0x4000), 0, "MBC5 can map bank 0 high");
+
+        cartridge.write(0x2000, 0x34);
+        cartridge.write(0x3000, 0x01);
+        assert_eq!(bank_at(&cartridge, 0x4000), 0x134);
+        cartridge.write(0x3000, 0x00);
+        assert_eq!(bank_at(&cartridge, 0x4000), 0x34);
+    }
+
+    #[test]
+    fn mbc3_rtc_survives_a_save_round_trip() {
+        let mut cartridge = cartridge(0x10, 0x05, 0x03);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x2000, 0x00);
+        cartridge.write(0xA123, 0x99);
+
+        /* Halt the clock so no time passes between saving and loading. */
+        for (register, value) in [(0x0C, RTC_HALT_BIT), (0x08, 30), (0x09, 15), (0x0A, 7), (0x0B, 0x23)] {
+            cartridge.write(0x4000, register);
+            cartridge.write(0xA000, value);
+        }
+        cartridge.write(0x6000, 0x00);
+        cartridge.write(0x6000, 0x01);
+
+        let data = cartridge.save_data();
+        assert_eq!(data.len(), RAM_BANK_SIZE * 4 + RTC_TRAILER_SIZE);
+
+        let mut loaded = self::cartridge(0x10, 0x05, 0x03);
+        loaded.load_save_data(&data);
+        loaded.write(0x0000, 0x0A);
+        loaded.write(0x4000, 0x00);
+        assert_eq!(loaded.read(0xA123), 0x99);
+        for (register, value) in [(0x08, 30), (0x09, 15), (0x0A, 7), (0x0B, 0x23), (0x0C, RTC_HALT_BIT | 0x3E)] {
+            loaded.write(0x4000, register);
+            assert_eq!(loaded.read(0xA000), value);
+        }
+    }
+
+    #[test]
+    fn rtc_changes_mark_the_save_dirty() {
+        let mut cartridge = cartridge(0x10, 0x05, 0x03);
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0x4000, 0x0C);
+        cartridge.write(0xA000, RTC_HALT_BIT);
+        assert!(cartridge.save_dirty);
+
+        cartridge.save_dirty = false;
+        cartridge.write(0x6000, 0x00);
+        cartridge.write(0x6000, 0x01);
+        assert!(cartridge.save_dirty, "the latch picked up the halt bit");
+
+        cartridge.save_dirty = false;
+        cartridge.write(0x6000, 0x00);
+        cartridge.write(0x6000, 0x01);
+        assert!(!cartridge.save_dirty, "latching the same time again changes nothing");
+    }
+
+    #[test]
+    fn autosaves_once_writes_settle() {
+        let path = env::temp_dir().join(format!("cartridge-test-{}.sav", process::id()));
+        let mut cartridge = cartridge(0x03, 0x05, 0x02);
+        cartridge.attach_save(&path).unwrap();
+        cartridge.write(0x0000, 0x0A);
+        cartridge.write(0xA000, 0x5A);
+
+        cartridge.tick(AUTOSAVE_DELAY_CYCLES - 1);
+        assert!(!path.exists());
+        cartridge.tick(1);
+        let saved = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(saved.len(), RAM_BANK_SIZE);
+        assert_eq!(saved[0], 0x5A);
+    }
+}
@@ -0,0 +1,577 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::apu::Channel;
+use crate::cartridge::{Cartridge, LoadOptions};
+use crate::conformance;
+use crate::emulator::{Emulator, CYCLES_PER_FRAME, CYCLES_PER_SECOND};
+use crate::gbs::GbsPlayer;
+use crate::joypad::Button;
+use crate::link::{LinkedPair, TcpLink};
+use crate::model::Model;
+use crate::ppu::RenderMode;
+use crate::printer::Printer;
+use crate::serial::{CaptureLink, SerialLog};
+use crate::single_step;
+
+const USAGE: &str = "\
+usage:
+  emulator run <rom> [--frames N | --cycles N] [--input SCRIPT] [--model dmg|cgb] [--fifo]
+                     [--patch FILE] [--entry NAME]
+                     [--serial | --printer DIR | --link-host ADDR | --link-connect ADDR [--link-latency MS]]
+                     [--wav FILE.wav] [--channel-wav N:FILE.wav,...] [--rate HZ] [--mute N,...] [--solo N]
+                     [--screenshot FRAME:FILE.png,...] [--hash FRAME,...] [--expect-hash FRAME:HASH,...]
+                     [--trace FILE [--stub-ly]]
+  emulator link <first rom> <second rom> [--frames N] [--input SCRIPT] [--second-input SCRIPT]
+  emulator gbs <file.gbs> [--track N] [--seconds S] [--output FILE.wav] [--rate HZ]
+  emulator test-roms <directory>
+  emulator sm83-tests <directory>
+
+An input script is a comma separated list of FRAME:BUTTON[+BUTTON...][:HOLD],
+e.g. 120:start,300:a+b:10 presses Start at frame 120 and A with B at frame 300
+for 10 frames. Buttons are held for 5 frames unless HOLD says otherwise.
+Screenshots and hashes are taken once FRAME frames have run.
+--channel-wav records channel N (1-4) on its own, ignoring --mute and --solo.
+--link-latency is how long the side clocking a transfer waits for the peer's
+byte before reading 0xFF (default 20 ms). After a timeout it does not wait
+again until the peer sends something.
+--trace logs every instruction in Gameboy Doctor's format; add --stub-ly to
+make LY read 0x90 as the reference logs expect.";
+
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+const DEFAULT_GBS_SECONDS: u64 = 60;
+const DEFAULT_RUN_FRAMES: u64 = 600;
+const DEFAULT_HOLD_FRAMES: u64 = 5;
+
+/*
+Arguments after the subcommand: positional values plus
+`--name value` options, where names listed as switches
+take no value.
+*/
+struct Arguments {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+    switches: Vec<String>,
+}
+
+impl Arguments {
+    fn parse(args: &[String], switch_names: &[&str]) -> Result<Arguments, String> {
+        let mut arguments = Arguments {
+            positional: Vec::new(),
+            options: HashMap::new(),
+            switches: Vec::new(),
+        };
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.strip_prefix("--") {
+                Some(name) if switch_names.contains(&name) => arguments.switches.push(name.to_string()),
+                Some(name) => {
+                    let value = iter.next().ok_or_else(|| format!("--{} needs a value", name))?;
+                    arguments.options.insert(name.to_string(), value.clone());
+                }
+                None => arguments.positional.push(arg.clone()),
+            }
+        }
+        Ok(arguments)
+    }
+
+    fn positional(&self, index: usize, what: &str) -> Result<&str, String> {
+        self.positional
+            .get(index)
+            .map(String::as_str)
+            .ok_or_else(|| format!("missing {}\n{}", what, USAGE))
+    }
+
+    fn option<T: FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        match self.options.get(name) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("invalid value for --{}: {}", name, value)),
+            None => Ok(None),
+        }
+    }
+
+    fn switch(&self, name: &str) -> bool {
+        self.switches.iter().any(|switch| switch == name)
+    }
+}
+
+/* Run the subcommand named by the first argument. */
+pub fn run(args: &[String]) -> Result<(), String> {
+    match args.first().map(String::as_str) {
+        Some("run") => run_rom(&args[1..]),
+        Some("link") => run_linked(&args[1..]),
+        Some("gbs") => play_gbs(&args[1..]),
+        Some("test-roms") => run_test_roms(&args[1..]),
+        Some("sm83-tests") => run_sm83_tests(&args[1..]),
+        Some(command) => Err(format!("unknown command '{}'\n{}", command, USAGE)),
+        None => Err(USAGE.to_string()),
+    }
+}
+
+/* One change to a button's state, made at the start of a frame. */
+struct InputEvent {
+    frame: u64,
+    button: Button,
+    pressed: bool,
+}
+
+/* Turn an input script into press and release events in frame order. */
+fn parse_input_script(script: &str) -> Result<Vec<InputEvent>, String> {
+    /* (button, press frame, release frame) for every button of every entry. */
+    let mut holds = Vec::new();
+    for entry in script.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let invalid = || format!("invalid input '{}', expected FRAME:BUTTON[+BUTTON...][:HOLD]", entry);
+        let mut fields = entry.split(':');
+        let frame: u64 = fields.next().and_then(|frame| frame.parse().ok()).ok_or_else(invalid)?;
+        let buttons = fields.next().ok_or_else(invalid)?;
+        let hold = match fields.next() {
+            Some(hold) => hold.parse::<u64>().map_err(|_| invalid())?,
+            None => DEFAULT_HOLD_FRAMES,
+        };
+        if fields.next().is_some() {
+            return Err(invalid());
+        }
+
+        for name in buttons.split('+') {
+            let button = Button::from_name(name).ok_or_else(|| format!("unknown button '{}'", name))?;
+            holds.push((button, frame, frame.saturating_add(hold.max(1))));
+        }
+    }
+
+    /* Overlapping holds of one button merge, so it stays down until the latest release. */
+    holds.sort_by_key(|&(button, press, _)| (button as u8, press));
+    let mut merged: Vec<(Button, u64, u64)> = Vec::new();
+    for (button, press, release) in holds {
+        match merged.last_mut() {
+            Some((last, _, last_release)) if *last == button && press < *last_release => {
+                *last_release = (*last_release).max(release);
+            }
+            _ => merged.push((button, press, release)),
+        }
+    }
+
+    let mut events = Vec::new();
+    for (button, press, release) in merged {
+        events.push(InputEvent {
+            frame: press,
+            button,
+            pressed: true,
+        });
+        events.push(InputEvent {
+            frame: release,
+            button,
+            pressed: false,
+        });
+    }
+    /* Releases sort before presses on the same frame, so a button released and pressed again stays held. */
+    events.sort_by_key(|event| (event.frame, event.pressed));
+    Ok(events)
+}
+
+/* Screenshots to save and frame hashes to print or check, by frame number. */
+struct FrameCaptures {
+    screenshots: Vec<(u64, PathBuf)>,
+    hashes: Vec<u64>,
+    expected_hashes: Vec<(u64, u64)>,
+}
+
+impl FrameCaptures {
+    fn parse(arguments: &Arguments) -> Result<FrameCaptures, String> {
+        let mut captures = FrameCaptures {
+            screenshots: Vec::new(),
+            hashes: Vec::new(),
+            expected_hashes: Vec::new(),
+        };
+
+        if let Some(list) = arguments.option::<String>("screenshot")? {
+            for (frame, path) in parse_frame_pairs(&list, "FRAME:FILE.png")? {
+                captures.screenshots.push((frame, PathBuf::from(path)));
+            }
+        }
+        if let Some(list) = arguments.option::<String>("hash")? {
+            for frame in list.split(',') {
+                let frame = frame.trim().parse().map_err(|_| format!("invalid frame '{}'", frame))?;
+                captures.hashes.push(frame);
+            }
+        }
+        if let Some(list) = arguments.option::<String>("expect-hash")? {
+            for (frame, hash) in parse_frame_pairs(&list, "FRAME:HASH")? {
+                let digits = hash.trim_start_matches("0x");
+                let hash = u64::from_str_radix(digits, 16).map_err(|_| format!("invalid hash '{}'", hash))?;
+                captures.expected_hashes.push((frame, hash));
+            }
+        }
+        Ok(captures)
+    }
+
+    /* Take whatever is due once `frame` frames have run. Hash mismatches are added to `failures`. */
+    fn capture(&self, emulator: &Emulator, frame: u64, failures: &mut Vec<String>) -> Result<(), String> {
+        for (_, path) in self.screenshots.iter().filter(|(at, _)| *at == frame) {
+            emulator
+                .save_screenshot(path)
+                .map_err(|err| format!("{}: {}", path.display(), err))?;
+        }
+        if self.hashes.contains(&frame) {
+            println!("frame {}: {:016x}", frame, emulator.frame_hash());
+        }
+        for &(_, expected) in self.expected_hashes.iter().filter(|(at, _)| *at == frame) {
+            let actual = emulator.frame_hash();
+            if actual != expected {
+                failures.push(format!("frame {}: hash {:016x}, expected {:016x}", frame, actual, expected));
+            }
+        }
+        Ok(())
+    }
+
+    /* Complaints about anything asked for after the last frame that ran. */
+    fn missed(&self, last_frame: u64) -> Vec<String> {
+        let frames = self
+            .screenshots
+            .iter()
+            .map(|(frame, _)| *frame)
+            .chain(self.hashes.iter().copied())
+            .chain(self.expected_hashes.iter().map(|(frame, _)| *frame));
+        let mut missed: Vec<u64> = frames.filter(|&frame| frame > last_frame).collect();
+        missed.sort_unstable();
+        missed.dedup();
+        missed
+            .into_iter()
+            .map(|frame| format!("frame {} was never reached", frame))
+            .collect()
+    }
+}
+
+/* Split a comma separated list of FRAME:VALUE pairs. */
+fn parse_frame_pairs(list: &str, expected: &str) -> Result<Vec<(u64, String)>, String> {
+    list.split(',')
+        .map(|entry| {
+            let invalid = || format!("invalid entry '{}', expected {}", entry, expected);
+            let (frame, value) = entry.trim().split_once(':').ok_or_else(invalid)?;
+            let frame = frame.parse().map_err(|_| invalid())?;
+            Ok((frame, value.to_string()))
+        })
+        .collect()
+}
+
+enum RunLimit {
+    Frames(u64),
+    Cycles(u64),
+}
+
+/*
+Boot a cartridge with no display and run it for a number of frames
+or M-cycles. With `--cycles`, script frames are counted in slices of
+one frame's worth of cycles. Fails if the CPU locks up on an illegal
+opcode or a frame hash does not match, so CI can tell a broken ROM
+from one that ran its course.
+*/
+fn run_rom(args: &[String]) -> Result<(), String> {
+    let arguments = Arguments::parse(args, &["fifo", "serial", "stub-ly"])?;
+    let path = PathBuf::from(arguments.positional(0, "ROM file")?);
+
+    let options = LoadOptions {
+        archive_entry: arguments.option("entry")?,
+        patch: arguments.option("patch")?,
+    };
+    let cartridge = Cartridge::load(&path, &options).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let header = &cartridge.header;
+    println!(
+        "{}: cartridge type 0x{:02X}, {} ROM banks{}",
+        header.title,
+        header.cartridge_type,
+        header.rom_banks,
+        if header.has_ram { " + RAM" } else { "" }
+    );
+    let model = match arguments.option::<Model>("model")? {
+        Some(model) => model,
+        None => Model::for_header(&cartridge.header),
+    };
+
+    let limit = match (arguments.option::<u64>("frames")?, arguments.option::<u64>("cycles")?) {
+        (Some(_), Some(_)) => return Err("--frames and --cycles cannot be used together".to_string()),
+        (None, Some(cycles)) => RunLimit::Cycles(cycles),
+        (frames, None) => RunLimit::Frames(frames.unwrap_or(DEFAULT_RUN_FRAMES)),
+    };
+    let events = match arguments.option::<String>("input")? {
+        Some(script) => parse_input_script(&script)?,
+        None => Vec::new(),
+    };
+    let captures = FrameCaptures::parse(&arguments)?;
+
+    let mut emulator = Emulator::with_model(cartridge, model);
+    if arguments.switch("fifo") {
+        emulator.cpu.bus.ppu.set_render_mode(RenderMode::PixelFifo);
+    }
+    let serial_log = attach_serial(&mut emulator, &arguments)?;
+    attach_audio(&mut emulator, &arguments)?;
+    if let Some(trace) = arguments.option::<PathBuf>("trace")? {
+        emulator
+            .trace_to_file(&trace, arguments.switch("stub-ly"))
+            .map_err(|err| format!("{}: {}", trace.display(), err))?;
+    }
+
+    let mut frame = 0;
+    let mut next_event = 0;
+    let mut failures = Vec::new();
+    loop {
+        captures.capture(&emulator, frame, &mut failures)?;
+
+        match limit {
+            _ if emulator.cpu.is_locked() => break,
+            RunLimit::Frames(frames) if frame >= frames => break,
+            RunLimit::Cycles(cycles) if emulator.cycles() >= cycles => break,
+            _ => {}
+        }
+
+        apply_input(&mut emulator, &events, &mut next_event, frame);
+
+        match limit {
+            RunLimit::Frames(_) => emulator.run_frame(),
+            RunLimit::Cycles(cycles) => emulator.run_cycles((cycles - emulator.cycles()).min(CYCLES_PER_FRAME)),
+        }
+        frame += 1;
+    }
+
+    emulator.flush_audio().map_err(|err| format!("failed to write audio: {}", err))?;
+    if let Some(log) = serial_log {
+        println!("{}", String::from_utf8_lossy(&log.borrow()));
+    }
+    println!("ran {} frames, {} cycles", frame, emulator.cycles());
+
+    if emulator.cpu.is_locked() {
+        failures.push(format!("CPU locked up at 0x{:04X}", emulator.cpu.pc.wrapping_sub(1)));
+    }
+    failures.extend(captures.missed(frame));
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures.join("\n"))
+    }
+}
+
+/* Make the button changes due by `frame`, starting from the event at `next_event`. */
+fn apply_input(emulator: &mut Emulator, events: &[InputEvent], next_event: &mut usize, frame: u64) {
+    while let Some(event) = events.get(*next_event).filter(|event| event.frame <= frame) {
+        emulator.set_button(event.button, event.pressed);
+        *next_event += 1;
+    }
+}
+
+/*
+Run two cartridges joined by a link cable in one process and print
+each machine's final frame hash, so two-player exchanges can be
+checked headless. `--input` drives the first machine and
+`--second-input` the second.
+*/
+fn run_linked(args: &[String]) -> Result<(), String> {
+    let arguments = Arguments::parse(args, &[])?;
+    let load = |index: usize, what: &str| -> Result<(PathBuf, Emulator), String> {
+        let path = PathBuf::from(arguments.positional(index, what)?);
+        let cartridge =
+            Cartridge::load(&path, &LoadOptions::default()).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok((path, Emulator::new(cartridge)))
+    };
+    let (first_path, first) = load(0, "first ROM file")?;
+    let (second_path, second) = load(1, "second ROM file")?;
+
+    let frames = arguments.option::<u64>("frames")?.unwrap_or(DEFAULT_RUN_FRAMES);
+    let mut scripts = Vec::new();
+    for name in ["input", "second-input"] {
+        scripts.push(match arguments.option::<String>(name)? {
+            Some(script) => parse_input_script(&script)?,
+            None => Vec::new(),
+        });
+    }
+
+    let mut pair = LinkedPair::new(first, second);
+    let mut next_events = [0, 0];
+    for frame in 0..frames {
+        apply_input(&mut pair.first, &scripts[0], &mut next_events[0], frame);
+        apply_input(&mut pair.second, &scripts[1], &mut next_events[1], frame);
+        pair.run_frame();
+    }
+
+    println!("ran {} frames", frames);
+    println!("{}: {:016x}", first_path.display(), pair.first.frame_hash());
+    println!("{}: {:016x}", second_path.display(), pair.second.frame_hash());
+    Ok(())
+}
+
+/* Plug in the serial partner the options ask for. Returns the log when serial output is to be printed. */
+fn attach_serial(emulator: &mut Emulator, arguments: &Arguments) -> Result<Option<SerialLog>, String> {
+    let printer = arguments.option::<PathBuf>("printer")?;
+    let host = arguments.option::<String>("link-host")?;
+    let connect = arguments.option::<String>("link-connect")?;
+    let latency = arguments.option::<u64>("link-latency")?;
+
+    let partners = [arguments.switch("serial"), printer.is_some(), host.is_some(), connect.is_some()];
+    if partners.iter().filter(|&&chosen| chosen).count() > 1 {
+        return Err("only one of --serial, --printer, --link-host and --link-connect can be used".to_string());
+    }
+    if latency.is_some() && host.is_none() && connect.is_none() {
+        return Err("--link-latency needs --link-host or --link-connect".to_string());
+    }
+
+    if arguments.switch("serial") {
+        let capture = CaptureLink::new();
+        let log = capture.log();
+        emulator.set_serial_link(Box::new(capture));
+        return Ok(Some(log));
+    }
+
+    if let Some(directory) = printer {
+        fs::create_dir_all(&directory).map_err(|err| format!("{}: {}", directory.display(), err))?;
+        emulator.set_serial_link(Box::new(Printer::new(directory)));
+        return Ok(None);
+    }
+
+    let mut link = if let Some(address) = host {
+        println!("waiting for a link partner on {}", address);
+        TcpLink::host(address.as_str()).map_err(|err| format!("{}: {}", address, err))?
+    } else if let Some(address) = connect {
+        TcpLink::connect(address.as_str()).map_err(|err| format!("{}: {}", address, err))?
+    } else {
+        return Ok(None);
+    };
+    if let Some(milliseconds) = latency {
+        link.set_latency_budget(Duration::from_millis(milliseconds));
+    }
+    emulator.set_serial_link(Box::new(link));
+    Ok(None)
+}
+
+fn attach_audio(emulator: &mut Emulator, arguments: &Arguments) -> Result<(), String> {
+    let rate = arguments.option::<u32>("rate")?.unwrap_or(DEFAULT_SAMPLE_RATE);
+    if let Some(output) = arguments.option::<PathBuf>("wav")? {
+        emulator
+            .record_wav(&output, rate)
+            .map_err(|err| format!("{}: {}", output.display(), err))?;
+    }
+    if let Some(list) = arguments.option::<String>("channel-wav")? {
+        for pair in list.split(',') {
+            let (number, output) = pair
+                .split_once(':')
+                .ok_or_else(|| format!("invalid channel recording '{}', expected N:FILE.wav", pair))?;
+            emulator
+                .record_channel_wav(parse_channel(number)?, output, rate)
+                .map_err(|err| format!("{}: {}", output, err))?;
+        }
+    }
+
+    if let Some(channels) = arguments.option::<String>("mute")? {
+        for number in channels.split(',') {
+            emulator.set_channel_muted(parse_channel(number)?, true);
+        }
+    }
+    if let Some(number) = arguments.option::<String>("solo")? {
+        emulator.set_channel_solo(Some(parse_channel(&number)?));
+    }
+    Ok(())
+}
+
+fn parse_channel(number: &str) -> Result<Channel, String> {
+    number
+        .trim()
+        .parse()
+        .ok()
+        .and_then(Channel::from_number)
+        .ok_or_else(|| format!("invalid channel '{}', expected 1-4", number))
+}
+
+/* Render one GBS track to a WAV file. Tracks are numbered from 1 as in player UIs. */
+fn play_gbs(args: &[String]) -> Result<(), String> {
+    let arguments = Arguments::parse(args, &[])?;
+    let path = PathBuf::from(arguments.positional(0, "GBS file")?);
+
+    let mut player = GbsPlayer::from_file(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let header = player.header().clone();
+
+    let track = arguments.option::<u8>("track")?.unwrap_or(header.first_song);
+    let seconds = arguments.option::<u64>("seconds")?.unwrap_or(DEFAULT_GBS_SECONDS);
+    let rate = arguments.option::<u32>("rate")?.unwrap_or(DEFAULT_SAMPLE_RATE);
+    let output = arguments
+        .option::<PathBuf>("output")?
+        .unwrap_or_else(|| path.with_extension("wav"));
+
+    player
+        .start_track(track.wrapping_sub(1))
+        .map_err(|_| format!("track {} out of range 1-{}", track, header.song_count))?;
+    player
+        .record_wav(&output, rate)
+        .map_err(|err| format!("{}: {}", output.display(), err))?;
+
+    println!("{} - {} ({}), track {}/{}", header.title, header.author, header.copyright, track, header.song_count);
+    player.run_cycles(seconds * CYCLES_PER_SECOND);
+    player
+        .flush_audio()
+        .map_err(|err| format!("{}: {}", output.display(), err))?;
+    println!("wrote {} seconds to {}", seconds, output.display());
+    Ok(())
+}
+
+/* Run a directory of Blargg, Mooneye and acid2 ROMs and print a pass/fail table. */
+fn run_test_roms(args: &[String]) -> Result<(), String> {
+    let arguments = Arguments::parse(args, &[])?;
+    let directory = PathBuf::from(arguments.positional(0, "test ROM directory")?);
+
+    let results = conformance::run_directory(&directory).map_err(|err| format!("{}: {}", directory.display(), err))?;
+    println!("{}", conformance::format_table(&results));
+
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    if failed > 0 {
+        return Err(format!("{} of {} test ROMs failed", failed, results.len()));
+    }
+    Ok(())
+}
+
+/* Run a directory of per-opcode SM83 JSON tests and report the first mismatch for each opcode. */
+fn run_sm83_tests(args: &[String]) -> Result<(), String> {
+    let arguments = Arguments::parse(args, &[])?;
+    let directory = PathBuf::from(arguments.positional(0, "SM83 test directory")?);
+
+    let results = single_step::run_directory(&directory).map_err(|err| format!("{}: {}", directory.display(), err))?;
+    println!("{}", single_step::format_report(&results));
+
+    let failed = results.iter().filter(|result| !result.passed()).count();
+    if failed > 0 {
+        return Err(format!("{} of {} opcodes failed", failed, results.len()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_input_script, Button};
+
+    #[test]
+    fn input_script_releases_before_pressing_again() {
+        let events = parse_input_script("0:a:5,5:a").unwrap();
+        let order: Vec<(u64, bool)> = events.iter().map(|event| (event.frame, event.pressed)).collect();
+        assert_eq!(order, [(0, true), (5, false), (5, true), (10, false)]);
+    }
+
+    #[test]
+    fn input_script_merges_overlapping_holds() {
+        let events = parse_input_script("10:a:20,15:a:5,12:b+a:2").unwrap();
+        let a: Vec<(u64, bool)> = events
+            .iter()
+            .filter(|event| event.button == Button::A)
+            .map(|event| (event.frame, event.pressed))
+            .collect();
+        assert_eq!(a, [(10, true), (30, false)]);
+        assert_eq!(events.iter().filter(|event| event.button == Button::B).count(), 2);
+    }
+
+    #[test]
+    fn input_script_hold_saturates() {
+        let events = parse_input_script(&format!("{}:start:10", u64::MAX - 1)).unwrap();
+        assert_eq!(events[1].frame, u64::MAX);
+        assert!(!events[1].pressed);
+    }
+}
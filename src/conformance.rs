@@ -0,0 +1,428 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cartridge::Cartridge;
+use crate::emulator::{Emulator, CYCLES_PER_FRAME};
+use crate::png::read_png;
+use crate::ppu::{LCDC_ADDRESS, LCDC_LCD_ENABLE, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::serial::{CaptureLink, SerialLog};
+
+/* Emulated time each kind of test ROM gets before it counts as hung. */
+const BLARGG_TIMEOUT_FRAMES: u64 = 60 * 120;
+const MOONEYE_TIMEOUT_FRAMES: u64 = 60 * 30;
+const ACID2_TIMEOUT_FRAMES: u64 = 60 * 10;
+
+/* `LD B,B`, which Mooneye and acid2 ROMs execute once they are done. */
+const BREAKPOINT_OPCODE: u8 = 0x40;
+
+/* Registers B, C, D, E, H and L of a passing Mooneye test. */
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+/* Blargg ROMs also report through cartridge RAM: a status byte, this signature, then the text. */
+const BLARGG_STATUS_ADDRESS: u16 = 0xA000;
+const BLARGG_SIGNATURE_ADDRESS: u16 = 0xA001;
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const BLARGG_TEXT_ADDRESS: u16 = 0xA004;
+const BLARGG_RUNNING: u8 = 0x80;
+
+/* Tiles per row of a background map. */
+const BLARGG_MAP_WIDTH: usize = 32;
+
+/* Where the font's space may sit: at its ASCII code, or first in tile data. */
+const BLARGG_FONT_SPACE_TILES: [u8; 2] = [0x20, 0x00];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Suite {
+    Blargg,
+    Mooneye,
+    Acid2,
+}
+
+impl Suite {
+    /*
+    Which suite a ROM belongs to, from where it sits: acid2 ROMs by
+    file name, Mooneye ROMs anywhere under a directory whose name
+    mentions mooneye, and everything else is taken to be Blargg's.
+    */
+    fn for_path(path: &Path) -> Suite {
+        let name = |component: &std::ffi::OsStr| component.to_string_lossy().to_ascii_lowercase();
+        if path.file_name().map(name).is_some_and(|file| file.contains("acid2")) {
+            Suite::Acid2
+        } else if path.iter().any(|component| name(component).contains("mooneye")) {
+            Suite::Mooneye
+        } else {
+            Suite::Blargg
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Suite::Blargg => "blargg",
+            Suite::Mooneye => "mooneye",
+            Suite::Acid2 => "acid2",
+        }
+    }
+}
+
+pub struct TestResult {
+    /* Relative to the directory that was run. */
+    pub rom: PathBuf,
+    pub suite: Suite,
+    /* None for a pass, otherwise what went wrong. */
+    pub failure: Option<String>,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/* Run every .gb and .gbc file under `directory`, in path order. */
+pub fn run_directory(directory: &Path) -> io::Result<Vec<TestResult>> {
+    let mut roms = Vec::new();
+    find_roms(directory, &mut roms)?;
+    roms.sort();
+
+    Ok(roms
+        .into_iter()
+        .map(|path| {
+            let suite = Suite::for_path(&path);
+            let failure = run_rom(&path, suite).err();
+            let rom = path.strip_prefix(directory).unwrap_or(&path).to_path_buf();
+            TestResult { rom, suite, failure }
+        })
+        .collect())
+}
+
+fn find_roms(directory: &Path, roms: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_roms(&path, roms)?;
+        } else if path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("gb") || extension.eq_ignore_ascii_case("gbc"))
+        {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+/* Run one ROM to a verdict. */
+pub fn run_rom(path: &Path, suite: Suite) -> Result<(), String> {
+    let cartridge = Cartridge::new(fs::read(path).map_err(|err| err.to_string())?).map_err(|err| err.to_string())?;
+    let mut emulator = Emulator::new(cartridge);
+
+    match suite {
+        Suite::Blargg => run_blargg(&mut emulator),
+        Suite::Mooneye => run_mooneye(&mut emulator),
+        Suite::Acid2 => run_acid2(&mut emulator, &path.with_extension("png")),
+    }
+}
+
+/*
+Blargg ROMs print "Passed" or "Failed" over serial, keep the same
+text in cartridge RAM, and draw it on screen. Serial and memory are
+checked first; the screen is the fallback for ROMs such as
+halt_bug.gb that have no cartridge RAM and print nothing over serial.
+*/
+fn run_blargg(emulator: &mut Emulator) -> Result<(), String> {
+    let capture = CaptureLink::new();
+    let log = capture.log();
+    emulator.set_serial_link(Box::new(capture));
+
+    for _ in 0..BLARGG_TIMEOUT_FRAMES {
+        emulator.run_frame();
+        let verdict = blargg_serial_verdict(&log)
+            .or_else(|| blargg_memory_verdict(emulator))
+            .or_else(|| blargg_screen_verdict(emulator));
+        if let Some(verdict) = verdict {
+            return verdict;
+        }
+        if emulator.cpu.is_locked() {
+            return Err("CPU locked up".to_string());
+        }
+    }
+    Err("timed out".to_string())
+}
+
+fn blargg_serial_verdict(log: &SerialLog) -> Option<Result<(), String>> {
+    blargg_text_verdict(&String::from_utf8_lossy(&log.borrow()))
+}
+
+/* Verdict from whatever a Blargg ROM has printed so far. */
+fn blargg_text_verdict(output: &str) -> Option<Result<(), String>> {
+    if output.contains("Passed") {
+        Some(Ok(()))
+    } else if output.contains("Failed") {
+        Some(Err(summarize(output)))
+    } else {
+        None
+    }
+}
+
+fn blargg_memory_verdict(emulator: &Emulator) -> Option<Result<(), String>> {
+    let bus = &emulator.cpu.bus;
+    let signature: Vec<u8> = (0..3).map(|offset| bus.read_unrestricted(BLARGG_SIGNATURE_ADDRESS + offset)).collect();
+    if signature != BLARGG_SIGNATURE {
+        return None;
+    }
+
+    match bus.read_unrestricted(BLARGG_STATUS_ADDRESS) {
+        BLARGG_RUNNING => None,
+        0x00 => Some(Ok(())),
+        status => {
+            let text: Vec<u8> = (BLARGG_TEXT_ADDRESS..0xC000)
+                .map(|address| bus.read_unrestricted(address))
+                .take_while(|&byte| byte != 0)
+                .collect();
+            Some(Err(format!("status {}: {}", status, summarize(&String::from_utf8_lossy(&text)))))
+        }
+    }
+}
+
+/*
+Blargg's text console loads its font as one run of tiles in ASCII
+order, so the background map reads back as text once the tile number
+of the space is known, without knowing what the glyphs look like.
+*/
+fn blargg_screen_verdict(emulator: &Emulator) -> Option<Result<(), String>> {
+    let bus = &emulator.cpu.bus;
+    if bus.read_unrestricted(LCDC_ADDRESS) & LCDC_LCD_ENABLE == 0 {
+        return None;
+    }
+
+    let map = bus.ppu.background_map();
+    BLARGG_FONT_SPACE_TILES
+        .iter()
+        .find_map(|&space| blargg_text_verdict(&map_text(map, space)))
+}
+
+/* A background map as lines of text, given the tile that holds the space. Other tiles become spaces. */
+fn map_text(map: &[u8], space: u8) -> String {
+    let mut text = String::new();
+    for row in map.chunks(BLARGG_MAP_WIDTH) {
+        for &tile in row {
+            let code = tile.wrapping_sub(space).wrapping_add(b' ');
+            text.push(if (b' '..0x7F).contains(&code) { code as char } else { ' ' });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/* Squeeze multi-line test output onto one table row. */
+fn summarize(output: &str) -> String {
+    output.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn run_mooneye(emulator: &mut Emulator) -> Result<(), String> {
+    run_to_breakpoint(emulator, MOONEYE_TIMEOUT_FRAMES)?;
+
+    let registers = &emulator.cpu.registers;
+    let actual = [registers.b, registers.c, registers.d, registers.e, registers.h, registers.l];
+    if actual == MOONEYE_PASS {
+        Ok(())
+    } else {
+        Err(format!("registers B-L {:?}, expected {:?}", actual, MOONEYE_PASS))
+    }
+}
+
+/* acid2 ROMs hit the breakpoint once the test image is on screen; compare it against `reference`. */
+fn run_acid2(emulator: &mut Emulator, reference: &Path) -> Result<(), String> {
+    let expected = read_png(reference).map_err(|err| format!("reference image {}: {}", reference.display(), err))?;
+    if (expected.width, expected.height) != (SCREEN_WIDTH, SCREEN_HEIGHT) {
+        return Err(format!("reference image is {}x{}", expected.width, expected.height));
+    }
+
+    run_to_breakpoint(emulator, ACID2_TIMEOUT_FRAMES)?;
+
+    let differing = emulator
+        .framebuffer()
+        .iter()
+        .zip(&expected.pixels)
+        .filter(|(actual, expected)| actual != expected)
+        .count();
+    if differing == 0 {
+        Ok(())
+    } else {
+        Err(format!("{} pixels differ from the reference", differing))
+    }
+}
+
+/* Step until an `LD B,B` has executed, or fail after `frames` frames' worth of cycles. */
+fn run_to_breakpoint(emulator: &mut Emulator, frames: u64) -> Result<(), String> {
+    let end = emulator.cycles() + frames * CYCLES_PER_FRAME;
+    while emulator.cycles() < end {
+        let pc = emulator.cpu.pc;
+        let opcode = emulator.cpu.bus.read_unrestricted(pc);
+        emulator.step();
+
+        /* PC moving past the opcode rules out an interrupt being dispatched instead. */
+        if opcode == BREAKPOINT_OPCODE && emulator.cpu.pc == pc.wrapping_add(1) {
+            return Ok(());
+        }
+        if emulator.cpu.is_locked() {
+            return Err("CPU locked up".to_string());
+        }
+    }
+    Err("timed out".to_string())
+}
+
+/* One row per ROM plus a count of passes and failures. */
+pub fn format_table(results: &[TestResult]) -> String {
+    let rom_width = results
+        .iter()
+        .map(|result| result.rom.display().to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("ROM".len());
+
+    let mut table = format!("{:<6}  {:<7}  {:<width$}  DETAIL\n", "RESULT", "SUITE", "ROM", width = rom_width);
+    for result in results {
+        let row = format!(
+            "{:<6}  {:<7}  {:<width$}  {}",
+            if result.passed() { "pass" } else { "FAIL" },
+            result.suite.name(),
+            result.rom.display(),
+            result.failure.as_deref().unwrap_or(""),
+            width = rom_width,
+        );
+        table += row.trim_end();
+        table.push('\n');
+    }
+
+    let passed = results.iter().filter(|result| result.passed()).count();
+    table += &format!("{} passed, {} failed", passed, results.len() - passed);
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+
+    use super::{
+        blargg_memory_verdict, blargg_screen_verdict, format_table, run_directory, Suite, TestResult,
+        BLARGG_RUNNING,
+    };
+    use crate::cartridge::Cartridge;
+    use crate::emulator::Emulator;
+    use crate::ppu::LCDC_ADDRESS;
+
+    /* An MBC1 cartridge with 8 KiB of RAM, enabled, and the CPU spinning at 0x150. */
+    fn emulator_with_cart_ram() -> Emulator {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x150..0x152].copy_from_slice(&[0x18, 0xFE]);
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let mut emulator = Emulator::new(Cartridge::new(rom).unwrap());
+        emulator.cpu.bus.write_byte(0x0000, 0x0A);
+        emulator
+    }
+
+    fn write_bytes(emulator: &mut Emulator, address: u16, bytes: &[u8]) {
+        for (offset, &byte) in bytes.iter().enumerate() {
+            emulator.cpu.bus.write_byte(address + offset as u16, byte);
+        }
+    }
+
+    #[test]
+    fn memory_verdict_needs_the_signature() {
+        let mut emulator = emulator_with_cart_ram();
+        assert_eq!(blargg_memory_verdict(&emulator), None);
+
+        write_bytes(&mut emulator, 0xA000, &[BLARGG_RUNNING, 0xDE, 0xB0, 0x61]);
+        assert_eq!(blargg_memory_verdict(&emulator), None, "still running");
+
+        write_bytes(&mut emulator, 0xA000, &[0x00]);
+        assert_eq!(blargg_memory_verdict(&emulator), Some(Ok(())));
+
+        write_bytes(&mut emulator, 0xA001, &[0xDE, 0xB0, 0x60]);
+        assert_eq!(blargg_memory_verdict(&emulator), None, "signature broken");
+    }
+
+    #[test]
+    fn memory_verdict_reports_the_status_and_text() {
+        let mut emulator = emulator_with_cart_ram();
+        write_bytes(&mut emulator, 0xA000, &[0x03, 0xDE, 0xB0, 0x61]);
+        write_bytes(&mut emulator, 0xA004, b"daa\n\nFailed #3\n\0junk");
+        assert_eq!(blargg_memory_verdict(&emulator), Some(Err("status 3: daa Failed #3".to_string())));
+    }
+
+    /* Put `lines` at the top left of the background map, one tile per character starting from `space`. */
+    fn draw_text(emulator: &mut Emulator, lines: &[&str], space: u8) {
+        let bus = &mut emulator.cpu.bus;
+        bus.write_byte(LCDC_ADDRESS, 0x00);
+        for (row, line) in lines.iter().enumerate() {
+            for (column, byte) in line.bytes().enumerate() {
+                bus.write_byte(0x9800 + (row * 32 + column) as u16, byte - b' ' + space);
+            }
+        }
+        bus.write_byte(LCDC_ADDRESS, 0x91);
+    }
+
+    #[test]
+    fn screen_verdict_reads_the_background_map() {
+        let mut emulator = emulator_with_cart_ram();
+        assert_eq!(blargg_screen_verdict(&emulator), None);
+
+        draw_text(&mut emulator, &["halt_bug", "", "Passed"], 0x20);
+        assert_eq!(blargg_screen_verdict(&emulator), Some(Ok(())));
+
+        let mut emulator = emulator_with_cart_ram();
+        draw_text(&mut emulator, &["halt_bug", "", "Failed"], 0x00);
+        assert_eq!(blargg_screen_verdict(&emulator), Some(Err("halt_bug Failed".to_string())));
+
+        /* Nothing on screen while the LCD is off. */
+        emulator.cpu.bus.write_byte(LCDC_ADDRESS, 0x00);
+        assert_eq!(blargg_screen_verdict(&emulator), None);
+    }
+
+    #[test]
+    fn table_lines_up_every_row() {
+        let results = [
+            TestResult {
+                rom: PathBuf::from("mooneye/di_timing.gb"),
+                suite: Suite::Mooneye,
+                failure: None,
+            },
+            TestResult {
+                rom: PathBuf::from("cpu.gb"),
+                suite: Suite::Blargg,
+                failure: Some("timed out".to_string()),
+            },
+        ];
+        assert_eq!(
+            format_table(&results),
+            "RESULT  SUITE    ROM                   DETAIL\n\
+             pass    mooneye  mooneye/di_timing.gb\n\
+             FAIL    blargg   cpu.gb                timed out\n\
+             1 passed, 1 failed"
+        );
+        assert_eq!(format_table(&[]), "RESULT  SUITE    ROM  DETAIL\n0 passed, 0 failed");
+    }
+
+    /*
+    Test ROMs are not distributed with the emulator. Point TEST_ROMS
+    at a local collection, or put one in ./test-roms; without either
+    there is nothing to run.
+    */
+    #[test]
+    fn test_roms() {
+        let directory = env::var_os("TEST_ROMS").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("test-roms"));
+        if !directory.is_dir() {
+            eprintln!("no test ROMs at {}, skipping", directory.display());
+            return;
+        }
+
+        let results = run_directory(&directory).expect("failed to read test ROM directory");
+        let table = format_table(&results);
+        println!("{}", table);
+        assert!(results.iter().all(|result| result.passed()), "test ROMs failed:\n{}", table);
+    }
+}
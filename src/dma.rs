@@ -0,0 +1,210 @@
+pub const OAM_SIZE: usize = 0xA0;
+
+/* M-cycles between writing 0xFF46 and the first byte being copied. */
+const OAM_DMA_STARTUP_DELAY: u8 = 1;
+
+/*
+OAM DMA engine. Writing a page number to 0xFF46 copies 160 bytes
+from `page << 8` into OAM, one byte per M-cycle. While a transfer
+runs the CPU is cut off from the bus the DMA is reading from.
+*/
+pub struct OamDma {
+    register: u8,
+    source: u16,
+    index: usize,
+    active: bool,
+    pending: Option<(u8, u8)>,
+    last_byte: u8,
+}
+
+impl OamDma {
+    pub fn new() -> OamDma {
+        OamDma {
+            register: 0xFF,
+            source: 0,
+            index: 0,
+            active: false,
+            pending: None,
+            last_byte: 0xFF,
+        }
+    }
+
+    pub fn read_register(&self) -> u8 {
+        self.register
+    }
+
+    /*
+    Start (or restart) a transfer. A transfer already in flight
+    keeps running until the new one takes over after the startup delay.
+    */
+    pub fn write_register(&mut self, value: u8) {
+        self.register = value;
+        self.pending = Some((value, OAM_DMA_STARTUP_DELAY));
+    }
+
+    /* True while OAM is being written and the CPU bus is contended. */
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn source(&self) -> u16 {
+        self.source
+    }
+
+    /* The byte most recently placed on the bus by the transfer. */
+    pub fn last_byte(&self) -> u8 {
+        self.last_byte
+    }
+
+    /*
+    Advance one M-cycle. Returns the (source address, OAM index)
+    of the byte that should be copied this cycle, if any.
+    */
+    pub fn tick(&mut self) -> Option<(u16, usize)> {
+        /* The bus stays contended for the whole cycle that copies the last byte. */
+        if self.active && self.index == OAM_SIZE {
+            self.active = false;
+        }
+
+        if let Some((page, delay)) = self.pending {
+            if delay > 0 {
+                self.pending = Some((page, delay - 1));
+            } else {
+                self.pending = None;
+                self.active = true;
+                self.index = 0;
+                /* Pages 0xE0-0xFF read from the echo of work RAM. */
+                let page = if page >= 0xE0 { page - 0x20 } else { page };
+                self.source = (page as u16) << 8;
+            }
+        }
+
+        if !self.active {
+            return None;
+        }
+
+        let transfer = (self.source + self.index as u16, self.index);
+        self.index += 1;
+        Some(transfer)
+    }
+
+    pub fn set_last_byte(&mut self, value: u8) {
+        self.last_byte = value;
+    }
+}
+
+pub const HDMA1_ADDRESS: u16 = 0xFF51;
+pub const HDMA2_ADDRESS: u16 = 0xFF52;
+pub const HDMA3_ADDRESS: u16 = 0xFF53;
+pub const HDMA4_ADDRESS: u16 = 0xFF54;
+pub const HDMA5_ADDRESS: u16 = 0xFF55;
+
+pub const VRAM_DMA_BLOCK_SIZE: u16 = 0x10;
+
+const HDMA5_HBLANK_MODE: u8 = 1 << 7;
+
+/*
+CGB VRAM DMA. Writing HDMA5 either copies everything at once
+(general purpose DMA) or arms an HBlank DMA that copies one
+16 byte block at the start of every HBlank. The bus does the
+copying; this only tracks addresses and how many blocks are left.
+*/
+pub struct VramDma {
+    source: u16,
+    /* Offset into VRAM, 0x0000-0x1FF0. */
+    destination: u16,
+    remaining: u8,
+    hblank_active: bool,
+    general_pending: bool,
+}
+
+impl VramDma {
+    pub fn new() -> VramDma {
+        VramDma {
+            source: 0,
+            destination: 0,
+            remaining: 0,
+            hblank_active: false,
+            general_pending: false,
+        }
+    }
+
+    /*
+    Only HDMA5 is readable. Its low bits hold the remaining length
+    minus one, so a finished transfer reads 0xFF; bit 7 is clear
+    while an HBlank DMA is still running.
+    */
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            HDMA5_ADDRESS => {
+                let length = self.remaining.wrapping_sub(1) & 0x7F;
+                if self.hblank_active {
+                    length
+                } else {
+                    HDMA5_HBLANK_MODE | length
+                }
+            }
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            HDMA1_ADDRESS => self.source = (self.source & 0x00FF) | ((value as u16) << 8),
+            HDMA2_ADDRESS => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            HDMA3_ADDRESS => self.destination = (self.destination & 0x00FF) | (((value & 0x1F) as u16) << 8),
+            HDMA4_ADDRESS => self.destination = (self.destination & 0x1F00) | (value & 0xF0) as u16,
+            HDMA5_ADDRESS => {
+                /* Clearing bit 7 while an HBlank DMA runs cancels it; the remaining length stays readable. */
+                if self.hblank_active && value & HDMA5_HBLANK_MODE == 0 {
+                    self.hblank_active = false;
+                    return;
+                }
+                self.remaining = (value & 0x7F) + 1;
+                if value & HDMA5_HBLANK_MODE != 0 {
+                    self.hblank_active = true;
+                } else {
+                    self.general_pending = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn is_hblank_active(&self) -> bool {
+        self.hblank_active
+    }
+
+    /* True once after HDMA5 requested a general purpose transfer. */
+    pub fn take_general_pending(&mut self) -> bool {
+        let pending = self.general_pending;
+        self.general_pending = false;
+        pending
+    }
+
+    /*
+    The (source, VRAM address) of the next 16 byte block, if any.
+    The transfer ends early if the destination runs off the end
+    of VRAM.
+    */
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        if self.remaining == 0 {
+            self.hblank_active = false;
+            return None;
+        }
+
+        let block = (self.source, 0x8000 + self.destination);
+        self.source = self.source.wrapping_add(VRAM_DMA_BLOCK_SIZE);
+        self.destination += VRAM_DMA_BLOCK_SIZE;
+        self.remaining -= 1;
+
+        if self.destination >= 0x2000 {
+            self.destination &= 0x1FFF;
+            self.remaining = 0;
+        }
+        if self.remaining == 0 {
+            self.hblank_active = false;
+        }
+        Some(block)
+    }
+}
@@ -0,0 +1,161 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use crate::apu::{AudioSink, Channel, Resampler};
+use crate::cartridge::Cartridge;
+use crate::checksum::fnv1a64;
+use crate::joypad::{Button, JOYPAD_INTERRUPT_BIT};
+use crate::memory_bus::MemoryBus;
+use crate::model::Model;
+use crate::png::write_png;
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::serial::SerialLink;
+use crate::wav::WavWriter;
+use crate::CPU::CPU;
+
+/* M-cycles in one 154 line frame at normal speed. */
+pub const CYCLES_PER_FRAME: u64 = 17556;
+
+/* M-cycles per emulated second at normal speed. */
+pub const CYCLES_PER_SECOND: u64 = 1 << 20;
+
+/*
+A complete Game Boy: the CPU with its bus and everything
+attached to it. Frontends drive the machine through this.
+*/
+pub struct Emulator {
+    pub cpu: CPU,
+}
+
+impl Emulator {
+    /* Emulate the console the cartridge was made for. */
+    pub fn new(cartridge: Cartridge) -> Emulator {
+        let model = Model::for_header(&cartridge.header);
+        Emulator::with_model(cartridge, model)
+    }
+
+    /* Emulate a specific console, e.g. to play a DMG game in CGB compatibility mode. */
+    pub fn with_model(cartridge: Cartridge, model: Model) -> Emulator {
+        Emulator {
+            cpu: CPU::new(MemoryBus::new(Some(cartridge), model)),
+        }
+    }
+
+    /* Execute one CPU step; the PPU and the rest of the bus advance with it. */
+    pub fn step(&mut self) {
+        self.cpu.step();
+    }
+
+    /*
+    Run until the PPU finishes a frame. With the LCD off no frame
+    ever completes, so give up after two frames' worth of cycles
+    (twice as many M-cycles in CGB double speed).
+    */
+    pub fn run_frame(&mut self) {
+        let start = self.cycles();
+        let speed = if self.cpu.bus.is_double_speed() { 2 } else { 1 };
+        while !self.cpu.bus.ppu.take_frame_ready() {
+            self.step();
+            if self.cycles() - start >= CYCLES_PER_FRAME * 2 * speed {
+                break;
+            }
+        }
+    }
+
+    /* Run for at least `cycles` M-cycles, stopping at the first instruction boundary after them. */
+    pub fn run_cycles(&mut self, cycles: u64) {
+        let end = self.cycles() + cycles;
+        while self.cycles() < end {
+            self.step();
+        }
+    }
+
+    /* Total M-cycles executed since power on. */
+    pub fn cycles(&self) -> u64 {
+        self.cpu.bus.cycles()
+    }
+
+    /* Press or release a button. A press seen on a selected row requests the joypad interrupt. */
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.cpu.bus.joypad.set_button(button, pressed) {
+            self.cpu.bus.request_interrupt(JOYPAD_INTERRUPT_BIT);
+        }
+    }
+
+    /*
+    Write a Gameboy Doctor style line to `path` before every
+    instruction. Reference logs are recorded with LY stubbed to 0x90,
+    so pass `stub_ly` when comparing against them.
+    */
+    pub fn trace_to_file<P: AsRef<Path>>(&mut self, path: P, stub_ly: bool) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        self.cpu.set_trace(Some(Box::new(file)));
+        self.cpu.bus.set_ly_stub(stub_ly);
+        Ok(())
+    }
+
+    /* Plug something into the link port. It starts out disconnected. */
+    pub fn set_serial_link(&mut self, link: Box<dyn SerialLink>) {
+        self.cpu.bus.serial.set_link(link);
+    }
+
+    pub fn framebuffer(&self) -> &[u32] {
+        self.cpu.bus.ppu.framebuffer()
+    }
+
+    /* Save the framebuffer as it stands to a PNG file. */
+    pub fn save_screenshot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        write_png(path, SCREEN_WIDTH, SCREEN_HEIGHT, self.framebuffer())
+    }
+
+    /*
+    Fingerprint of the framebuffer: FNV-1a over each pixel's red,
+    green and blue bytes in row order. It depends only on the
+    picture, so it can be compared against hashes from other runs.
+    */
+    pub fn frame_hash(&self) -> u64 {
+        let mut bytes = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+        for pixel in self.framebuffer() {
+            bytes.extend_from_slice(&pixel.to_be_bytes()[1..]);
+        }
+        fnv1a64(&bytes)
+    }
+
+    /*
+    Send audio to the frontend at `sample_rate` Hz (typically 44100
+    or 48000), band-limited on the way. Without a sink the APU still
+    runs but nothing is mixed.
+    */
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>, sample_rate: u32) {
+        self.cpu.bus.apu.set_sink(Box::new(Resampler::new(sink, sample_rate)));
+    }
+
+    /* Record the mixed output to a WAV file, in place of any other audio sink. */
+    pub fn record_wav<P: AsRef<Path>>(&mut self, path: P, sample_rate: u32) -> io::Result<()> {
+        let wav = WavWriter::create(path, sample_rate)?;
+        self.set_audio_sink(Box::new(wav), sample_rate);
+        Ok(())
+    }
+
+    /* Record one channel on its own to a WAV file, unaffected by mute and solo. */
+    pub fn record_channel_wav<P: AsRef<Path>>(&mut self, channel: Channel, path: P, sample_rate: u32) -> io::Result<()> {
+        let wav = WavWriter::create(path, sample_rate)?;
+        let resampled = Resampler::new(Box::new(wav), sample_rate);
+        self.cpu.bus.apu.set_channel_sink(channel, Box::new(resampled));
+        Ok(())
+    }
+
+    /* Finish any recordings; sinks also flush when the emulator is dropped. */
+    pub fn flush_audio(&mut self) -> io::Result<()> {
+        self.cpu.bus.apu.flush_sinks()
+    }
+
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        self.cpu.bus.apu.set_muted(channel, muted);
+    }
+
+    pub fn set_channel_solo(&mut self, channel: Option<Channel>) {
+        self.cpu.bus.apu.set_solo(channel);
+    }
+}
@@ -0,0 +1,358 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::apu::{AudioSink, Resampler, NR50_ADDRESS, NR51_ADDRESS, NR52_ADDRESS};
+use crate::cartridge::Cartridge;
+use crate::emulator::CYCLES_PER_FRAME;
+use crate::memory_bus::{MemoryBus, INTERRUPT_FLAG_ADDRESS};
+use crate::model::Model;
+use crate::ppu::LCDC_ADDRESS;
+use crate::timer::{TAC_ADDRESS, TIMA_ADDRESS, TIMER_INTERRUPT_BIT, TMA_ADDRESS};
+use crate::wav::WavWriter;
+use crate::CPU::CPU;
+
+const HEADER_SIZE: usize = 0x70;
+const MAGIC: &[u8; 3] = b"GBS";
+const SUPPORTED_VERSION: u8 = 1;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+
+/* Code must load above the vectors the player provides and below the end of ROM. */
+const MIN_LOAD_ADDRESS: u16 = 0x0400;
+const MAX_LOAD_ADDRESS: u16 = 0x7FFF;
+
+/*
+Routines are called with this as their return address. It holds
+`JR -2`, so nothing runs away if the CPU gets there early, and the
+player knows a routine is done once PC reaches it.
+*/
+const RETURN_ADDRESS: u16 = 0x00F0;
+
+/* TAC bit 2: play is driven by the timer interrupt rather than VBlank. */
+const TAC_TIMER_ENABLE: u8 = 1 << 2;
+
+/* TAC bit 7: the rip expects the CGB CPU in double speed, which also doubles the timer's rate. */
+const TAC_DOUBLE_SPEED: u8 = 1 << 7;
+
+/* The TAC bits the timer itself uses. */
+const TAC_TIMER_BITS: u8 = 0x07;
+
+#[derive(Debug)]
+pub enum GbsError {
+    Io(io::Error),
+    TooSmall(usize),
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    InvalidLoadAddress(u16),
+    NoSuchTrack(u8),
+}
+
+impl fmt::Display for GbsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GbsError::Io(err) => write!(f, "{}", err),
+            GbsError::TooSmall(size) => write!(f, "file is {} bytes, too small to hold a GBS header", size),
+            GbsError::InvalidMagic => write!(f, "not a GBS file"),
+            GbsError::UnsupportedVersion(version) => write!(f, "unsupported GBS version {}", version),
+            GbsError::InvalidLoadAddress(address) => write!(f, "invalid load address 0x{:04X}", address),
+            GbsError::NoSuchTrack(track) => write!(f, "no track {}", track),
+        }
+    }
+}
+
+impl std::error::Error for GbsError {}
+
+impl From<io::Error> for GbsError {
+    fn from(err: io::Error) -> Self {
+        GbsError::Io(err)
+    }
+}
+
+/* The fixed 0x70 byte header in front of the music code. */
+#[derive(Clone, Debug)]
+pub struct GbsHeader {
+    pub song_count: u8,
+    /* 1-based, as stored in the file. */
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    fn parse(data: &[u8]) -> Result<GbsHeader, GbsError> {
+        if data.len() < HEADER_SIZE {
+            return Err(GbsError::TooSmall(data.len()));
+        }
+        if &data[0..3] != MAGIC {
+            return Err(GbsError::InvalidMagic);
+        }
+        if data[3] != SUPPORTED_VERSION {
+            return Err(GbsError::UnsupportedVersion(data[3]));
+        }
+
+        let word = |offset: usize| u16::from_le_bytes([data[offset], data[offset + 1]]);
+        let text = |offset: usize| {
+            data[offset..offset + 32]
+                .iter()
+                .take_while(|&&byte| byte != 0)
+                .map(|&byte| byte as char)
+                .collect::<String>()
+                .trim_end()
+                .to_string()
+        };
+
+        let load_address = word(0x06);
+        if !(MIN_LOAD_ADDRESS..=MAX_LOAD_ADDRESS).contains(&load_address) {
+            return Err(GbsError::InvalidLoadAddress(load_address));
+        }
+
+        Ok(GbsHeader {
+            song_count: data[0x04],
+            first_song: data[0x05],
+            load_address,
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: data[0x0E],
+            timer_control: data[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+        })
+    }
+}
+
+/*
+Build the ROM the music code runs from: the code at its load
+address, RST vectors jumping to load address + vector as GBS
+requires, RETI on the interrupt vectors, and the idle loop at
+`RETURN_ADDRESS`.
+*/
+fn build_rom(header: &GbsHeader, code: &[u8]) -> Vec<u8> {
+    let load = header.load_address as usize;
+    let size = (load + code.len()).div_ceil(ROM_BANK_SIZE).max(2) * ROM_BANK_SIZE;
+    let mut rom = vec![0xFF; size];
+    rom[load..load + code.len()].copy_from_slice(code);
+
+    for vector in (0x00..0x40).step_by(8) {
+        let target = header.load_address.wrapping_add(vector as u16).to_le_bytes();
+        rom[vector..vector + 3].copy_from_slice(&[0xC3, target[0], target[1]]);
+    }
+    for vector in (0x40..=0x60).step_by(8) {
+        rom[vector] = 0xD9;
+    }
+    rom[RETURN_ADDRESS as usize..RETURN_ADDRESS as usize + 2].copy_from_slice(&[0x18, 0xFE]);
+    rom
+}
+
+/*
+Plays a GBS rip on the CPU and APU alone. The LCD stays off and
+there are no interrupts; instead the player calls the rip's init
+routine once per track and its play routine at the rate the header
+asks for, either every frame or on each timer overflow.
+*/
+pub struct GbsPlayer {
+    header: GbsHeader,
+    cpu: CPU,
+    /* True while init or play is still running. */
+    in_routine: bool,
+    cycles_since_play: u64,
+}
+
+impl GbsPlayer {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<GbsPlayer, GbsError> {
+        GbsPlayer::new(&fs::read(path)?)
+    }
+
+    pub fn new(data: &[u8]) -> Result<GbsPlayer, GbsError> {
+        let header = GbsHeader::parse(data)?;
+        let rom = build_rom(&header, &data[HEADER_SIZE..]);
+        let cartridge = Cartridge::from_image(header.title.clone(), rom);
+
+        let mut bus = MemoryBus::new(Some(cartridge), Model::Dmg);
+        if header.timer_control & TAC_DOUBLE_SPEED != 0 {
+            bus.switch_speed();
+        }
+
+        Ok(GbsPlayer {
+            header,
+            cpu: CPU::new(bus),
+            in_routine: false,
+            cycles_since_play: 0,
+        })
+    }
+
+    /* CPU M-cycles per normal speed M-cycle. */
+    fn speed(&self) -> u64 {
+        if self.cpu.bus.is_double_speed() {
+            2
+        } else {
+            1
+        }
+    }
+
+    pub fn header(&self) -> &GbsHeader {
+        &self.header
+    }
+
+    /* Send audio to the frontend at `sample_rate` Hz. */
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>, sample_rate: u32) {
+        self.cpu.bus.apu.set_sink(Box::new(Resampler::new(sink, sample_rate)));
+    }
+
+    pub fn record_wav<P: AsRef<Path>>(&mut self, path: P, sample_rate: u32) -> io::Result<()> {
+        let wav = WavWriter::create(path, sample_rate)?;
+        self.set_audio_sink(Box::new(wav), sample_rate);
+        Ok(())
+    }
+
+    pub fn flush_audio(&mut self) -> io::Result<()> {
+        self.cpu.bus.apu.flush_sinks()
+    }
+
+    /*
+    Reset the machine state the rip can see and call init for a
+    track (0-based). Sound is switched on at full volume first, as
+    GBS players are expected to do.
+    */
+    pub fn start_track(&mut self, track: u8) -> Result<(), GbsError> {
+        if track >= self.header.song_count {
+            return Err(GbsError::NoSuchTrack(track));
+        }
+
+        let bus = &mut self.cpu.bus;
+        bus.write_unrestricted(LCDC_ADDRESS, 0x00);
+        bus.write_unrestricted(NR52_ADDRESS, 0x80);
+        bus.write_unrestricted(NR51_ADDRESS, 0xFF);
+        bus.write_unrestricted(NR50_ADDRESS, 0x77);
+        bus.write_unrestricted(TMA_ADDRESS, self.header.timer_modulo);
+        bus.write_unrestricted(TIMA_ADDRESS, self.header.timer_modulo);
+        bus.write_unrestricted(TAC_ADDRESS, self.header.timer_control & TAC_TIMER_BITS);
+        bus.write_unrestricted(INTERRUPT_FLAG_ADDRESS, 0x00);
+        bus.interrupt_enable = 0x00;
+
+        self.cpu.sp = self.header.stack_pointer;
+        self.cpu.registers.a = track;
+        self.call(self.header.init_address);
+        self.cycles_since_play = 0;
+        Ok(())
+    }
+
+    /* Push the return address and jump, as a CALL from the idle loop would. */
+    fn call(&mut self, address: u16) {
+        let [low, high] = RETURN_ADDRESS.to_le_bytes();
+        self.cpu.sp = self.cpu.sp.wrapping_sub(1);
+        self.cpu.bus.write_unrestricted(self.cpu.sp, high);
+        self.cpu.sp = self.cpu.sp.wrapping_sub(1);
+        self.cpu.bus.write_unrestricted(self.cpu.sp, low);
+        self.cpu.pc = address;
+        self.in_routine = true;
+    }
+
+    /* Whether it is time to call play: on timer overflow, or once per frame with the timer off. */
+    fn play_due(&mut self) -> bool {
+        if self.header.timer_control & TAC_TIMER_ENABLE != 0 {
+            let timer_bit = 1 << TIMER_INTERRUPT_BIT;
+            let due = self.cpu.bus.interrupt_flag & timer_bit != 0;
+            self.cpu.bus.interrupt_flag &= !timer_bit;
+            due
+        } else {
+            self.cycles_since_play >= CYCLES_PER_FRAME * self.speed()
+        }
+    }
+
+    /*
+    Run for at least `cycles` normal speed M-cycles; a double speed
+    rip gets twice as many CPU cycles in that time. While no routine
+    is running the machine idles; play is called whenever it is due,
+    and a play that overruns simply delays the next one.
+    */
+    pub fn run_cycles(&mut self, cycles: u64) {
+        let end = self.cpu.bus.cycles() + cycles * self.speed();
+        while self.cpu.bus.cycles() < end {
+            let start = self.cpu.bus.cycles();
+
+            if self.in_routine {
+                self.cpu.step();
+                if self.cpu.pc == RETURN_ADDRESS {
+                    self.in_routine = false;
+                }
+            } else {
+                self.cpu.bus.tick();
+            }
+
+            self.cycles_since_play += self.cpu.bus.cycles() - start;
+            if !self.in_routine && self.play_due() {
+                self.cycles_since_play = 0;
+                self.call(self.header.play_address);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GbsPlayer, HEADER_SIZE};
+    use crate::emulator::CYCLES_PER_SECOND;
+
+    /* A one track rip at 0x0400 whose init returns at once and whose play counts its calls at 0xC000. */
+    fn rip(timer_modulo: u8, timer_control: u8) -> Vec<u8> {
+        let mut data = vec![0; HEADER_SIZE];
+        data[0..4].copy_from_slice(b"GBS\x01");
+        data[0x04] = 1;
+        data[0x05] = 1;
+        data[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes());
+        data[0x08..0x0A].copy_from_slice(&0x0400u16.to_le_bytes());
+        data[0x0A..0x0C].copy_from_slice(&0x0401u16.to_le_bytes());
+        data[0x0C..0x0E].copy_from_slice(&0xFFFEu16.to_le_bytes());
+        data[0x0E] = timer_modulo;
+        data[0x0F] = timer_control;
+        /* RET; LD HL,0xC000; INC (HL); RET */
+        data.extend_from_slice(&[0xC9, 0x21, 0x00, 0xC0, 0x34, 0xC9]);
+        data
+    }
+
+    /* How many times play runs in one emulated second. */
+    fn plays_per_second(timer_modulo: u8, timer_control: u8) -> u8 {
+        let mut player = GbsPlayer::new(&rip(timer_modulo, timer_control)).unwrap();
+        player.start_track(0).unwrap();
+        player.run_cycles(CYCLES_PER_SECOND);
+        player.cpu.bus.read_unrestricted(0xC000)
+    }
+
+    /* The first overflow's phase depends on where the divider is, so allow for one call either way. */
+    fn assert_plays(timer_modulo: u8, timer_control: u8, expected: u8) {
+        let plays = plays_per_second(timer_modulo, timer_control);
+        assert!(plays.abs_diff(expected) <= 1, "{} plays, expected {}", plays, expected);
+    }
+
+    #[test]
+    fn play_follows_the_timer_period() {
+        /* 4096 Hz divided by 256 - 0xC0 = 64 ticks per overflow. */
+        assert_plays(0xC0, 0x04, 64);
+        /* 16384 Hz divided by 256 - 0x00 = 256 ticks per overflow. */
+        assert_plays(0x00, 0x07, 64);
+        assert_plays(0x00, 0x04, 16);
+    }
+
+    #[test]
+    fn play_runs_every_frame_without_the_timer() {
+        assert_plays(0x00, 0x00, 60);
+    }
+
+    #[test]
+    fn double_speed_doubles_the_timer_rate() {
+        assert_plays(0xC0, 0x84, 128);
+        /* Frames are still frames. */
+        assert_plays(0x00, 0x80, 60);
+    }
+}
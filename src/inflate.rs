@@ -0,0 +1,341 @@
+use std::fmt;
+
+/*
+DEFLATE (RFC 1951) decoder used to read zipped and gzipped ROMs.
+Huffman codes are decoded canonically, one bit at a time, which
+is plenty fast for cartridge sized inputs.
+*/
+
+const MAX_BITS: usize = 15;
+const MAX_LITERAL_LENGTH_CODES: usize = 288;
+const MAX_DISTANCE_CODES: usize = 30;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/* Order in which code length code lengths are stored in a dynamic block header. */
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+#[derive(Debug, PartialEq)]
+pub enum InflateError {
+    UnexpectedEnd,
+    InvalidBlockType,
+    StoredLengthMismatch,
+    InvalidCode,
+    InvalidDistance,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            InflateError::UnexpectedEnd => "compressed data ends unexpectedly",
+            InflateError::InvalidBlockType => "invalid deflate block type",
+            InflateError::StoredLengthMismatch => "stored block length check failed",
+            InflateError::InvalidCode => "invalid Huffman code",
+            InflateError::InvalidDistance => "back reference before start of output",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl std::error::Error for InflateError {}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    bit_buffer: u32,
+    bit_count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, position: 0, bit_buffer: 0, bit_count: 0 }
+    }
+
+    /* Read `count` bits, least significant bit first. */
+    fn bits(&mut self, count: u32) -> Result<u32, InflateError> {
+        while self.bit_count < count {
+            let byte = *self.data.get(self.position).ok_or(InflateError::UnexpectedEnd)?;
+            self.position += 1;
+            self.bit_buffer |= (byte as u32) << self.bit_count;
+            self.bit_count += 8;
+        }
+
+        let value = self.bit_buffer & ((1u32 << count) - 1);
+        self.bit_buffer >>= count;
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    /* Drop any bits left in the current byte. */
+    fn align_to_byte(&mut self) {
+        self.bit_buffer = 0;
+        self.bit_count = 0;
+    }
+
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], InflateError> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + length)
+            .ok_or(InflateError::UnexpectedEnd)?;
+        self.position += length;
+        Ok(bytes)
+    }
+}
+
+/*
+Canonical Huffman table: how many codes exist of each length,
+and the symbols ordered by code.
+*/
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, InflateError> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+
+        /* Reject over-subscribed code sets; incomplete sets are allowed. */
+        let mut left: i32 = 1;
+        for &count in counts.iter().skip(1) {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(InflateError::InvalidCode);
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for length in 1..=MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+
+        counts[0] = 0;
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, InflateError> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+
+        for length in 1..=MAX_BITS {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - count < first {
+                return Ok(self.symbols[(index + (code - first)) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+
+        Err(InflateError::InvalidCode)
+    }
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; MAX_LITERAL_LENGTH_CODES];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+
+    let literal_length = Huffman::new(&lengths).expect("fixed literal/length table is valid");
+    let distance = Huffman::new(&[5u8; MAX_DISTANCE_CODES]).expect("fixed distance table is valid");
+    (literal_length, distance)
+}
+
+fn dynamic_tables(reader: &mut BitReader) -> Result<(Huffman, Huffman), InflateError> {
+    let literal_length_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    if literal_length_count > 286 || distance_count > MAX_DISTANCE_CODES {
+        return Err(InflateError::InvalidCode);
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for &position in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+        code_lengths[position] = reader.bits(3)? as u8;
+    }
+    let code_length_table = Huffman::new(&code_lengths)?;
+
+    let mut lengths = vec![0u8; literal_length_count + distance_count];
+    let mut index = 0;
+    while index < lengths.len() {
+        let symbol = code_length_table.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths[..index].last().ok_or(InflateError::InvalidCode)?;
+                (previous, 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+
+        if index + repeat > lengths.len() {
+            return Err(InflateError::InvalidCode);
+        }
+        lengths[index..index + repeat].fill(value);
+        index += repeat;
+    }
+
+    if lengths[256] == 0 {
+        return Err(InflateError::InvalidCode);
+    }
+
+    let literal_length = Huffman::new(&lengths[..literal_length_count])?;
+    let distance = Huffman::new(&lengths[literal_length_count..])?;
+    Ok((literal_length, distance))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    literal_length: &Huffman,
+    distance: &Huffman,
+) -> Result<(), InflateError> {
+    loop {
+        let symbol = literal_length.decode(reader)? as usize;
+        if symbol < 256 {
+            output.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+
+        let length_code = symbol - 257;
+        if length_code >= LENGTH_BASE.len() {
+            return Err(InflateError::InvalidCode);
+        }
+        let length = LENGTH_BASE[length_code] as usize + reader.bits(LENGTH_EXTRA[length_code] as u32)? as usize;
+
+        let distance_code = distance.decode(reader)? as usize;
+        if distance_code >= DISTANCE_BASE.len() {
+            return Err(InflateError::InvalidCode);
+        }
+        let back = DISTANCE_BASE[distance_code] as usize + reader.bits(DISTANCE_EXTRA[distance_code] as u32)? as usize;
+        if back > output.len() {
+            return Err(InflateError::InvalidDistance);
+        }
+
+        /* Copy byte by byte since the source may overlap what is being written. */
+        let start = output.len() - back;
+        for offset in 0..length {
+            output.push(output[start + offset]);
+        }
+    }
+}
+
+/*
+Decompress a raw DEFLATE stream. Returns the output and the number
+of input bytes consumed so container trailers can be read after it.
+*/
+pub fn inflate(data: &[u8]) -> Result<(Vec<u8>, usize), InflateError> {
+    let mut reader = BitReader::new(data);
+    let mut output = Vec::new();
+
+    loop {
+        let is_final = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align_to_byte();
+                let header = reader.read_bytes(4)?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                let complement = u16::from_le_bytes([header[2], header[3]]);
+                if length != !complement {
+                    return Err(InflateError::StoredLengthMismatch);
+                }
+                output.extend_from_slice(reader.read_bytes(length as usize)?);
+            }
+            1 => {
+                let (literal_length, distance) = fixed_tables();
+                inflate_block(&mut reader, &mut output, &literal_length, &distance)?;
+            }
+            2 => {
+                let (literal_length, distance) = dynamic_tables(&mut reader)?;
+                inflate_block(&mut reader, &mut output, &literal_length, &distance)?;
+            }
+            _ => return Err(InflateError::InvalidBlockType),
+        }
+
+        if is_final {
+            return Ok((output, reader.position));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inflate, InflateError};
+
+    #[test]
+    fn stored_blocks() {
+        let mut data = vec![0x00, 0x03, 0x00, 0xFC, 0xFF, b'a', b'b', b'c'];
+        data.extend_from_slice(&[0x01, 0x02, 0x00, 0xFD, 0xFF, b'd', b'e', 0xAA]);
+
+        let (output, consumed) = inflate(&data).unwrap();
+        assert_eq!(output, b"abcde");
+        assert_eq!(consumed, data.len() - 1);
+    }
+
+    #[test]
+    fn stored_length_must_match_its_complement() {
+        let data = [0x01, 0x03, 0x00, 0xFC, 0xFE, b'a', b'b', b'c'];
+        assert_eq!(inflate(&data), Err(InflateError::StoredLengthMismatch));
+    }
+
+    #[test]
+    fn fixed_huffman_block_with_back_references() {
+        let data = [0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0xC8, 0x40, 0xA2, 0x14, 0x01];
+
+        let (output, consumed) = inflate(&data).unwrap();
+        assert_eq!(output, b"hello, hello, hello!");
+        assert_eq!(consumed, data.len());
+    }
+
+    #[test]
+    fn dynamic_huffman_block() {
+        let data = [
+            0x05, 0xC1, 0x01, 0x01, 0x00, 0x00, 0x08, 0xC3, 0xA0, 0xAC, 0xEC, 0xF6, 0xCF, 0x20, 0x00, 0xA0, 0x6A,
+            0xBB, 0x07,
+        ];
+
+        let (output, _) = inflate(&data).unwrap();
+        assert_eq!(output, b"aaaaaaaaaaabbbbccd");
+    }
+
+    #[test]
+    fn rejects_reserved_block_type_and_short_input() {
+        assert_eq!(inflate(&[0x07]), Err(InflateError::InvalidBlockType));
+        assert_eq!(inflate(&[0xCB, 0x48, 0xCD]), Err(InflateError::UnexpectedEnd));
+        assert_eq!(inflate(&[]), Err(InflateError::UnexpectedEnd));
+    }
+}
@@ -0,0 +1,158 @@
+pub const JOYPAD_ADDRESS: u16 = 0xFF00;
+
+pub const JOYPAD_INTERRUPT_BIT: u8 = 4;
+
+/* P1 bits 4 and 5 select the direction and action rows; a row is selected while its bit is 0. */
+const SELECT_DIRECTIONS: u8 = 1 << 4;
+const SELECT_ACTIONS: u8 = 1 << 5;
+const SELECT_MASK: u8 = SELECT_DIRECTIONS | SELECT_ACTIONS;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    /* Button by name as used in input scripts, ignoring case. */
+    pub fn from_name(name: &str) -> Option<Button> {
+        match name.to_ascii_lowercase().as_str() {
+            "right" => Some(Button::Right),
+            "left" => Some(Button::Left),
+            "up" => Some(Button::Up),
+            "down" => Some(Button::Down),
+            "a" => Some(Button::A),
+            "b" => Some(Button::B),
+            "select" => Some(Button::Select),
+            "start" => Some(Button::Start),
+            _ => None,
+        }
+    }
+
+    /* Bit in `Joypad::pressed`: directions in the low nibble, actions in the high one, each in P1 line order. */
+    fn mask(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+/*
+The P1 register. Buttons pull their line low, so a pressed button
+reads as 0 in whichever selected row it belongs to. Any line going
+from high to low requests the joypad interrupt and ends STOP.
+*/
+pub struct Joypad {
+    select: u8,
+    pressed: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: SELECT_MASK,
+            pressed: 0,
+        }
+    }
+
+    /* Input lines P10-P13 as the CPU sees them, active low. */
+    fn lines(&self) -> u8 {
+        let mut low = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            low |= self.pressed & 0x0F;
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            low |= self.pressed >> 4;
+        }
+        !low & 0x0F
+    }
+
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    /* Returns true if changing the selection pulled a line low. */
+    pub fn write(&mut self, value: u8) -> bool {
+        let before = self.lines();
+        self.select = value & SELECT_MASK;
+        before & !self.lines() != 0
+    }
+
+    /* Returns true if the change pulled a line low, which requests the joypad interrupt. */
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> bool {
+        let before = self.lines();
+        if pressed {
+            self.pressed |= button.mask();
+        } else {
+            self.pressed &= !button.mask();
+        }
+        before & !self.lines() != 0
+    }
+
+    /* STOP ends once any selected line is held low. */
+    pub fn wakes_from_stop(&self) -> bool {
+        self.lines() != 0x0F
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Button, Joypad};
+
+    const SELECT_NONE: u8 = 0x30;
+    const SELECT_DIRECTIONS: u8 = 0x20;
+    const SELECT_ACTIONS: u8 = 0x10;
+
+    #[test]
+    fn select_bits_read_back_with_the_selected_row() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::Right, true);
+        joypad.set_button(Button::Start, true);
+        assert_eq!(joypad.read(), 0xFF, "nothing selected");
+
+        joypad.write(SELECT_DIRECTIONS);
+        assert_eq!(joypad.read(), 0xEE);
+        joypad.write(SELECT_ACTIONS);
+        assert_eq!(joypad.read(), 0xD7);
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xC6, "both rows are AND'ed together");
+
+        joypad.write(0xFF);
+        assert_eq!(joypad.read(), 0xFF, "only the select bits are writable");
+    }
+
+    #[test]
+    fn interrupt_only_on_a_falling_line() {
+        let mut joypad = Joypad::new();
+        joypad.write(SELECT_ACTIONS);
+        assert!(joypad.set_button(Button::A, true));
+        assert!(!joypad.set_button(Button::A, true));
+        assert!(!joypad.set_button(Button::A, false));
+
+        /* A press in the unselected row leaves every line high. */
+        assert!(!joypad.set_button(Button::Up, true));
+        assert!(!joypad.wakes_from_stop());
+
+        /* Selecting a row with a held button pulls a line low as well. */
+        assert!(joypad.write(SELECT_DIRECTIONS));
+        assert!(joypad.wakes_from_stop());
+        assert!(!joypad.write(SELECT_DIRECTIONS));
+        assert!(!joypad.write(SELECT_NONE));
+    }
+
+    #[test]
+    fn a_line_already_held_low_does_not_interrupt_again() {
+        let mut joypad = Joypad::new();
+        joypad.write(0x00);
+        /* Right and A both pull P10. */
+        assert!(joypad.set_button(Button::Right, true));
+        assert!(!joypad.set_button(Button::A, true));
+        assert!(!joypad.set_button(Button::Right, false));
+        assert!(!joypad.set_button(Button::A, false));
+        assert!(joypad.set_button(Button::A, true));
+    }
+}
@@ -0,0 +1,214 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::emulator::{Emulator, CYCLES_PER_FRAME};
+use crate::serial::SerialLink;
+
+mod tcp;
+
+pub use tcp::TcpLink;
+
+/*
+What is on the wire between the two plugs. A plug waiting on the
+external clock leaves the byte it would send in `offered`; when the
+other plug clocks a transfer it takes that byte and leaves its own
+in `delivered`, which the waiting side picks up on its next poll.
+*/
+struct Wire {
+    offered: [Option<u8>; 2],
+    delivered: [Option<u8>; 2],
+}
+
+/* One end of a `link_cable`. */
+pub struct CablePlug {
+    wire: Rc<RefCell<Wire>>,
+    side: usize,
+}
+
+/* A link cable with nothing on either end yet. */
+pub fn link_cable() -> (CablePlug, CablePlug) {
+    let wire = Rc::new(RefCell::new(Wire {
+        offered: [None; 2],
+        delivered: [None; 2],
+    }));
+    (
+        CablePlug {
+            wire: Rc::clone(&wire),
+            side: 0,
+        },
+        CablePlug { wire, side: 1 },
+    )
+}
+
+impl SerialLink for CablePlug {
+    /* A partner that is not waiting on our clock shifts nothing out, so the line reads high. */
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        let mut wire = self.wire.borrow_mut();
+        let other = 1 - self.side;
+        wire.offered[self.side] = None;
+        match wire.offered[other].take() {
+            Some(incoming) => {
+                wire.delivered[other] = Some(outgoing);
+                incoming
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        let mut wire = self.wire.borrow_mut();
+        match wire.delivered[self.side].take() {
+            Some(incoming) => {
+                wire.offered[self.side] = None;
+                Some(incoming)
+            }
+            None => {
+                wire.offered[self.side] = Some(outgoing);
+                None
+            }
+        }
+    }
+
+    /* A byte the partner already clocked in for the old transfer is dropped too. */
+    fn cancel_external(&mut self) {
+        let mut wire = self.wire.borrow_mut();
+        wire.offered[self.side] = None;
+        wire.delivered[self.side] = None;
+    }
+}
+
+/*
+Two Game Boys joined by a link cable. They run in lockstep: the
+one that is behind always steps next, so every transfer happens at
+the same point in both machines on every run.
+*/
+pub struct LinkedPair {
+    pub first: Emulator,
+    pub second: Emulator,
+    /* Elapsed time of each machine in half M-cycles, so double speed counts for half. */
+    times: [u64; 2],
+}
+
+impl LinkedPair {
+    pub fn new(mut first: Emulator, mut second: Emulator) -> LinkedPair {
+        let (first_plug, second_plug) = link_cable();
+        first.set_serial_link(Box::new(first_plug));
+        second.set_serial_link(Box::new(second_plug));
+        LinkedPair {
+            first,
+            second,
+            times: [0; 2],
+        }
+    }
+
+    /* Step whichever machine is behind by one instruction. */
+    pub fn step(&mut self) {
+        let (emulator, time) = if self.times[0] <= self.times[1] {
+            (&mut self.first, &mut self.times[0])
+        } else {
+            (&mut self.second, &mut self.times[1])
+        };
+
+        let start = emulator.cycles();
+        let half_cycles = if emulator.cpu.bus.is_double_speed() { 1 } else { 2 };
+        emulator.step();
+        *time += (emulator.cycles() - start) * half_cycles;
+    }
+
+    /* Run both machines for at least `cycles` M-cycles at normal speed. */
+    pub fn run_cycles(&mut self, cycles: u64) {
+        let end = self.times[0].min(self.times[1]) + cycles * 2;
+        while self.times[0].min(self.times[1]) < end {
+            self.step();
+        }
+    }
+
+    /* Run both machines for one frame's worth of time. */
+    pub fn run_frame(&mut self) {
+        self.run_cycles(CYCLES_PER_FRAME);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{link_cable, LinkedPair};
+    use crate::cartridge::Cartridge;
+    use crate::emulator::Emulator;
+    use crate::serial::SerialLink;
+
+    /* A ROM-only cartridge that jumps from the entry point to `program` at 0x150. */
+    fn emulator(program: &[u8]) -> Emulator {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x150..0x150 + program.len()].copy_from_slice(program);
+        Emulator::new(Cartridge::new(rom).unwrap())
+    }
+
+    /* Send 0x42 on the internal clock, wait for SC bit 7 to clear, then spin. */
+    const CLOCKING: [u8; 16] = [
+        0x3E, 0x42, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02, 0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xFA, 0x18, 0xFE,
+    ];
+
+    fn serial_data(emulator: &Emulator) -> u8 {
+        emulator.cpu.bus.read_unrestricted(0xFF01)
+    }
+
+    fn transfer_pending(emulator: &Emulator) -> bool {
+        emulator.cpu.bus.read_unrestricted(0xFF02) & 0x80 != 0
+    }
+
+    #[test]
+    fn exchanges_a_byte_in_lockstep() {
+        /* Wait for an external clock with 0x24 in SB. */
+        let waiting = emulator(&[0x3E, 0x24, 0xE0, 0x01, 0x3E, 0x80, 0xE0, 0x02, 0x18, 0xFE]);
+        let mut pair = LinkedPair::new(emulator(&CLOCKING), waiting);
+        pair.run_cycles(2000);
+
+        assert_eq!(serial_data(&pair.first), 0x24);
+        assert_eq!(serial_data(&pair.second), 0x42);
+        assert!(!transfer_pending(&pair.first));
+        assert!(!transfer_pending(&pair.second));
+    }
+
+    #[test]
+    fn cancelled_transfer_is_not_clocked_in() {
+        /* Start waiting for an external clock, then clear SC again before the partner clocks. */
+        let cancelled = emulator(&[
+            0x3E, 0x24, 0xE0, 0x01, 0x3E, 0x80, 0xE0, 0x02, 0x00, 0x00, 0x00, 0x00, 0xAF, 0xE0, 0x02, 0x18, 0xFE,
+        ]);
+        let mut pair = LinkedPair::new(emulator(&CLOCKING), cancelled);
+        pair.run_cycles(2000);
+
+        assert_eq!(serial_data(&pair.first), 0xFF);
+        assert_eq!(serial_data(&pair.second), 0x24);
+        assert!(!transfer_pending(&pair.first));
+    }
+
+    #[test]
+    fn rewriting_sc_mid_transfer_offers_the_new_byte() {
+        /* Wait for an external clock with 0x24, then restart the wait with 0x99 in SB. */
+        let restarted = emulator(&[
+            0x3E, 0x24, 0xE0, 0x01, 0x3E, 0x80, 0xE0, 0x02, 0x3E, 0x99, 0xE0, 0x01, 0x3E, 0x80, 0xE0, 0x02, 0x18,
+            0xFE,
+        ]);
+        let mut pair = LinkedPair::new(emulator(&CLOCKING), restarted);
+        pair.run_cycles(2000);
+
+        assert_eq!(serial_data(&pair.first), 0x99);
+        assert_eq!(serial_data(&pair.second), 0x42);
+        assert!(!transfer_pending(&pair.second));
+    }
+
+    #[test]
+    fn cancelling_drops_a_byte_already_delivered() {
+        let (mut waiting, mut clocking) = link_cable();
+        assert_eq!(waiting.poll_external(0x24), None);
+        assert_eq!(clocking.transfer(0x42), 0x24);
+
+        /* SC is rewritten before the waiting side polled for the byte. */
+        waiting.cancel_external();
+        assert_eq!(waiting.poll_external(0x99), None);
+        assert_eq!(clocking.transfer(0x55), 0x99);
+        assert_eq!(waiting.poll_external(0x99), Some(0x55));
+    }
+}
@@ -0,0 +1,385 @@
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::serial::SerialLink;
+
+const MAGIC: &[u8; 4] = b"GBLK";
+const PROTOCOL_VERSION: u8 = 1;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/* How long a clocking side waits for the peer's byte before the line reads high. */
+const DEFAULT_LATENCY_BUDGET: Duration = Duration::from_millis(20);
+const WAIT_INTERVAL: Duration = Duration::from_micros(100);
+
+/* While waiting on the external clock, only look at the socket every this many M-cycles. */
+const POLL_INTERVAL: u32 = 64;
+
+/* Every message is a kind byte, a little endian sequence number and a data byte. */
+const MESSAGE_SIZE: usize = 4;
+const MESSAGE_OFFER: u8 = 0x01;
+const MESSAGE_CLAIM: u8 = 0x02;
+const MESSAGE_RETRACT: u8 = 0x03;
+
+#[derive(Debug)]
+pub enum LinkError {
+    Io(io::Error),
+    BadHandshake,
+    VersionMismatch(u8),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::Io(err) => write!(f, "{}", err),
+            LinkError::BadHandshake => write!(f, "peer is not a link cable"),
+            LinkError::VersionMismatch(version) => write!(f, "peer speaks link protocol version {}", version),
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+impl From<io::Error> for LinkError {
+    fn from(err: io::Error) -> Self {
+        LinkError::Io(err)
+    }
+}
+
+/*
+A link cable to another emulator process over TCP.
+
+Each side runs at its own pace, so a transfer cannot wait for a
+round trip at the moment the clock ticks. Instead the side waiting
+on the external clock offers the byte it would shift out as soon as
+it starts waiting. Whichever side then clocks a transfer becomes
+the clock master for it: it claims the peer's offer, sending its
+own byte back, and the peer completes its transfer when the claim
+arrives. An offer is retracted once its side starts clocking on its
+own, rewrites SC or changes the byte it would send; in the last
+case a new offer replaces it.
+
+A claim can cross the retraction on the wire. The master has
+already completed its transfer by then, so a claim on the offer we
+retracted last is still honoured and completes our next transfer,
+and both sides count the same transfers. Claims on older offers
+are ignored.
+
+The waiting side never blocks. The clocking side waits up to the
+latency budget for an offer that may still be in flight, then reads
+the line as high as a disconnected port would. After one such
+timeout it stops waiting until the peer sends something again, so a
+peer that never offers costs one budget rather than one per byte.
+*/
+pub struct TcpLink {
+    /* None once the peer has gone; the port then behaves as if unplugged. */
+    stream: Option<TcpStream>,
+    received: Vec<u8>,
+    /* Messages the socket has not taken yet; the stream is nonblocking, so writes can be partial. */
+    unsent: Vec<u8>,
+    latency_budget: Duration,
+    next_sequence: u16,
+    own_offer: Option<(u16, u8)>,
+    /* Sequence of the offer we retracted last, which a late claim may still take. */
+    retracted: Option<u16>,
+    peer_offer: Option<(u16, u8)>,
+    /* Set when a transfer gave up waiting for an offer; cleared by any data from the peer. */
+    peer_quiet: bool,
+    /* The byte the peer clocked in against our offer, until the serial port picks it up. */
+    claimed: Option<u8>,
+    polls: u32,
+}
+
+impl TcpLink {
+    /* Wait for one peer to connect to `address`. */
+    pub fn host<A: ToSocketAddrs>(address: A) -> Result<TcpLink, LinkError> {
+        let listener = TcpListener::bind(address)?;
+        let (stream, _) = listener.accept()?;
+        TcpLink::from_stream(stream)
+    }
+
+    pub fn connect<A: ToSocketAddrs>(address: A) -> Result<TcpLink, LinkError> {
+        TcpLink::from_stream(TcpStream::connect(address)?)
+    }
+
+    fn from_stream(mut stream: TcpStream) -> Result<TcpLink, LinkError> {
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+        let mut hello = [0u8; 5];
+        hello[..4].copy_from_slice(MAGIC);
+        hello[4] = PROTOCOL_VERSION;
+        stream.write_all(&hello)?;
+
+        let mut peer = [0u8; 5];
+        stream.read_exact(&mut peer)?;
+        if &peer[..4] != MAGIC {
+            return Err(LinkError::BadHandshake);
+        }
+        if peer[4] != PROTOCOL_VERSION {
+            return Err(LinkError::VersionMismatch(peer[4]));
+        }
+
+        stream.set_read_timeout(None)?;
+        stream.set_nonblocking(true)?;
+
+        Ok(TcpLink {
+            stream: Some(stream),
+            received: Vec::new(),
+            unsent: Vec::new(),
+            latency_budget: DEFAULT_LATENCY_BUDGET,
+            next_sequence: 0,
+            own_offer: None,
+            retracted: None,
+            peer_offer: None,
+            peer_quiet: false,
+            claimed: None,
+            polls: 0,
+        })
+    }
+
+    /* Trade stalls on the clocking side for fewer bytes lost to a slow connection. */
+    pub fn set_latency_budget(&mut self, budget: Duration) {
+        self.latency_budget = budget;
+    }
+
+    fn send(&mut self, kind: u8, sequence: u16, byte: u8) {
+        let [low, high] = sequence.to_le_bytes();
+        self.unsent.extend_from_slice(&[kind, low, high, byte]);
+        self.flush();
+    }
+
+    /* Write as much of the queued output as the socket will take without blocking. */
+    fn flush(&mut self) {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        while !self.unsent.is_empty() {
+            match stream.write(&self.unsent) {
+                Ok(0) => {
+                    self.stream = None;
+                    break;
+                }
+                Ok(count) => {
+                    self.unsent.drain(..count);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.stream = None;
+                    break;
+                }
+            }
+        }
+    }
+
+    /* Read whatever the peer has sent so far and apply complete messages. */
+    fn receive(&mut self) {
+        self.flush();
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let mut buffer = [0u8; 256];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => {
+                    self.stream = None;
+                    break;
+                }
+                Ok(count) => {
+                    self.received.extend_from_slice(&buffer[..count]);
+                    self.peer_quiet = false;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    self.stream = None;
+                    break;
+                }
+            }
+        }
+
+        let complete = self.received.len() / MESSAGE_SIZE * MESSAGE_SIZE;
+        let messages: Vec<u8> = self.received.drain(..complete).collect();
+        for message in messages.chunks_exact(MESSAGE_SIZE) {
+            let sequence = u16::from_le_bytes([message[1], message[2]]);
+            let byte = message[3];
+            match message[0] {
+                MESSAGE_OFFER => self.peer_offer = Some((sequence, byte)),
+                MESSAGE_RETRACT if self.peer_offer.map(|(offered, _)| offered) == Some(sequence) => {
+                    self.peer_offer = None;
+                }
+                MESSAGE_CLAIM if self.own_offer.map(|(offered, _)| offered) == Some(sequence) => {
+                    self.own_offer = None;
+                    self.claimed = Some(byte);
+                }
+                MESSAGE_CLAIM if self.retracted == Some(sequence) => {
+                    /* The late claim stands in for whatever we are offering now. */
+                    self.retract();
+                    self.retracted = None;
+                    self.claimed = Some(byte);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn retract(&mut self) {
+        if let Some((sequence, _)) = self.own_offer.take() {
+            self.retracted = Some(sequence);
+            self.send(MESSAGE_RETRACT, sequence, 0);
+        }
+    }
+}
+
+impl SerialLink for TcpLink {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.cancel_external();
+
+        self.receive();
+        if !self.peer_quiet {
+            let deadline = Instant::now() + self.latency_budget;
+            while self.peer_offer.is_none() && self.stream.is_some() && Instant::now() < deadline {
+                thread::sleep(WAIT_INTERVAL);
+                self.receive();
+            }
+        }
+
+        match self.peer_offer.take() {
+            Some((sequence, incoming)) => {
+                self.send(MESSAGE_CLAIM, sequence, outgoing);
+                incoming
+            }
+            None => {
+                self.peer_quiet = true;
+                0xFF
+            }
+        }
+    }
+
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        if self.claimed.is_none() && self.own_offer.map(|(_, offered)| offered) != Some(outgoing) {
+            self.retract();
+            let sequence = self.next_sequence;
+            self.next_sequence = self.next_sequence.wrapping_add(1);
+            self.own_offer = Some((sequence, outgoing));
+            self.send(MESSAGE_OFFER, sequence, outgoing);
+        }
+
+        if !self.unsent.is_empty() {
+            self.flush();
+        }
+
+        self.polls = self.polls.wrapping_add(1);
+        if self.polls.is_multiple_of(POLL_INTERVAL) {
+            self.receive();
+        }
+        self.claimed.take()
+    }
+
+    fn cancel_external(&mut self) {
+        self.retract();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{TcpLink, POLL_INTERVAL};
+    use crate::serial::SerialLink;
+
+    /* Two links joined over loopback. */
+    fn linked_pair() -> (TcpLink, TcpLink) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || TcpLink::connect(address).unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        let host = TcpLink::from_stream(stream).unwrap();
+        (host, client.join().unwrap())
+    }
+
+    /* Give messages in flight time to arrive. */
+    fn settle() {
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    /* Keep polling as the serial port would while it waits on the external clock. */
+    fn wait_for_claim(link: &mut TcpLink, outgoing: u8) -> Option<u8> {
+        for poll in 1..=POLL_INTERVAL * 1000 {
+            if let Some(incoming) = link.poll_external(outgoing) {
+                return Some(incoming);
+            }
+            if poll % POLL_INTERVAL == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn a_claimed_offer_exchanges_both_bytes() {
+        let (mut waiting, mut clocking) = linked_pair();
+        clocking.set_latency_budget(Duration::from_secs(1));
+
+        assert_eq!(waiting.poll_external(0x24), None);
+        assert_eq!(clocking.transfer(0x42), 0x24);
+        assert_eq!(wait_for_claim(&mut waiting, 0x24), Some(0x42));
+    }
+
+    #[test]
+    fn changing_the_outgoing_byte_replaces_the_offer() {
+        let (mut waiting, mut clocking) = linked_pair();
+        assert_eq!(waiting.poll_external(0x24), None);
+        assert_eq!(waiting.poll_external(0x99), None);
+        settle();
+
+        assert_eq!(clocking.transfer(0x42), 0x99);
+        assert_eq!(wait_for_claim(&mut waiting, 0x99), Some(0x42));
+    }
+
+    #[test]
+    fn a_claim_that_crosses_the_retraction_completes_the_next_transfer() {
+        let (mut waiting, mut clocking) = linked_pair();
+        assert_eq!(waiting.poll_external(0x24), None);
+        settle();
+
+        /* The claim is on the wire when SC is rewritten. */
+        assert_eq!(clocking.transfer(0x42), 0x24);
+        waiting.cancel_external();
+        assert_eq!(wait_for_claim(&mut waiting, 0x55), Some(0x42));
+
+        /* The offer of 0x55 made in the meantime was taken back. */
+        settle();
+        clocking.receive();
+        assert_eq!(clocking.peer_offer, None);
+    }
+
+    #[test]
+    fn a_quiet_peer_costs_one_latency_budget() {
+        let (mut waiting, mut clocking) = linked_pair();
+        let budget = Duration::from_millis(200);
+        clocking.set_latency_budget(budget);
+
+        let start = Instant::now();
+        assert_eq!(clocking.transfer(0x42), 0xFF);
+        assert!(start.elapsed() >= budget);
+
+        let start = Instant::now();
+        assert_eq!(clocking.transfer(0x42), 0xFF);
+        assert!(start.elapsed() < budget / 2);
+
+        /* Hearing from the peer again brings the wait back. */
+        assert_eq!(waiting.poll_external(0x24), None);
+        settle();
+        assert_eq!(clocking.transfer(0x42), 0x24);
+    }
+}
@@ -1,5 +1,39 @@
-
+#[allow(non_snake_case)]
 mod CPU;
-fn main() {
-    println!("Hello, world!");
-}
\ No newline at end of file
+mod apu;
+mod archive;
+mod cartridge;
+mod checksum;
+mod cli;
+mod conformance;
+mod dma;
+mod emulator;
+mod gbs;
+mod inflate;
+mod joypad;
+mod json;
+mod link;
+mod memory_bus;
+mod model;
+mod patch;
+mod png;
+mod ppu;
+mod printer;
+mod serial;
+mod single_step;
+mod timer;
+mod wav;
+
+use std::env;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match cli::run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
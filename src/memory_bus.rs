@@ -0,0 +1,547 @@
+use crate::apu::{Apu, NR10_ADDRESS, WAVE_RAM_END};
+use crate::cartridge::Cartridge;
+use crate::dma::{OamDma, VramDma, HDMA1_ADDRESS, HDMA5_ADDRESS, VRAM_DMA_BLOCK_SIZE};
+use crate::joypad::{Joypad, JOYPAD_ADDRESS, JOYPAD_INTERRUPT_BIT};
+use crate::model::Model;
+use crate::ppu::{Mode, Ppu, BCPS_ADDRESS, LY_ADDRESS, OCPD_ADDRESS, VBK_ADDRESS};
+use crate::serial::{Serial, SB_ADDRESS, SC_ADDRESS, SERIAL_INTERRUPT_BIT};
+use crate::timer::{Timer, DIV_ADDRESS, TIMER_INTERRUPT_BIT};
+
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_BANKS: usize = 8;
+const HRAM_SIZE: usize = 0x7F;
+const IO_SIZE: usize = 0x80;
+
+pub const INTERRUPT_FLAG_ADDRESS: u16 = 0xFF0F;
+pub const INTERRUPT_ENABLE_ADDRESS: u16 = 0xFFFF;
+const OAM_DMA_ADDRESS: u16 = 0xFF46;
+const KEY1_ADDRESS: u16 = 0xFF4D;
+const SVBK_ADDRESS: u16 = 0xFF70;
+
+const KEY1_SWITCH_ARMED: u8 = 1 << 0;
+const KEY1_DOUBLE_SPEED: u8 = 1 << 7;
+
+#[derive(Copy, Clone, PartialEq)]
+enum BusRegion {
+    External,
+    Video,
+    Internal,
+}
+
+/* Which physical bus an address is decoded on; the OAM DMA contends with one of them. */
+fn bus_region(address: u16) -> BusRegion {
+    match address {
+        0x8000..=0x9FFF => BusRegion::Video,
+        0xFE00..=0xFFFF => BusRegion::Internal,
+        _ => BusRegion::External,
+    }
+}
+
+/*
+What the CPU sees of the machine around it. `MemoryBus` is the
+real thing; the SM83 test runner plugs in a flat 64 KiB bus.
+*/
+pub trait Bus {
+    /* Advance everything else by one M-cycle. Called before each access. */
+    fn tick(&mut self);
+    fn read_byte(&mut self, address: u16) -> u8;
+    fn write_byte(&mut self, address: u16, value: u8);
+    /* Read without ticking or contention, for traces and debuggers. */
+    fn peek_byte(&self, address: u16) -> u8;
+    /* Interrupts both requested in IF and enabled in IE. */
+    fn pending_interrupts(&self) -> u8;
+    /* Clear an interrupt's IF bit as it is dispatched. */
+    fn acknowledge_interrupt(&mut self, bit: u8);
+    /*
+    Called for STOP. Returns true if it started a CGB speed switch,
+    which the CPU idles through; otherwise the CPU sleeps until
+    `wakes_from_stop`.
+    */
+    fn stop(&mut self) -> bool;
+    fn wakes_from_stop(&self) -> bool;
+}
+
+/*
+Everything the CPU can see through its 16 bit address space.
+`read_byte` and `write_byte` are the CPU's view of memory and
+honor bus contention; `tick` advances the hardware one M-cycle.
+*/
+pub struct MemoryBus {
+    pub cartridge: Option<Cartridge>,
+    model: Model,
+    /* A CGB running a CGB game. CGB only registers are hidden otherwise. */
+    cgb_mode: bool,
+    wram: [u8; WRAM_BANK_SIZE * WRAM_BANKS],
+    /* Bank mapped at 0xD000-0xDFFF; always 1 outside CGB mode. */
+    wram_bank: usize,
+    hram: [u8; HRAM_SIZE],
+    io: [u8; IO_SIZE],
+    pub interrupt_enable: u8,
+    pub interrupt_flag: u8,
+    oam_dma: OamDma,
+    vram_dma: VramDma,
+    pub joypad: Joypad,
+    pub serial: Serial,
+    double_speed: bool,
+    speed_switch_armed: bool,
+    /* Make LY read 0x90, as Gameboy Doctor logs are recorded with. */
+    ly_stub: bool,
+    pub timer: Timer,
+    pub ppu: Ppu,
+    pub apu: Apu,
+    cycles: u64,
+}
+
+impl MemoryBus {
+    pub fn new(cartridge: Option<Cartridge>, model: Model) -> MemoryBus {
+        let cgb_mode = model == Model::Cgb
+            && cartridge
+                .as_ref()
+                .is_some_and(|cartridge| cartridge.header.supports_cgb());
+
+        MemoryBus {
+            cartridge,
+            model,
+            cgb_mode,
+            wram: [0; WRAM_BANK_SIZE * WRAM_BANKS],
+            wram_bank: 1,
+            hram: [0; HRAM_SIZE],
+            io: [0xFF; IO_SIZE],
+            interrupt_enable: 0x00,
+            interrupt_flag: 0x01,
+            oam_dma: OamDma::new(),
+            vram_dma: VramDma::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(cgb_mode),
+            double_speed: false,
+            speed_switch_armed: false,
+            ly_stub: false,
+            timer: Timer::new(),
+            ppu: Ppu::new(model, cgb_mode),
+            apu: Apu::new(),
+            cycles: 0,
+        }
+    }
+
+    /*
+    Advance every component on the bus by one M-cycle. In double
+    speed an M-cycle is only 2 dots long: the timer and OAM DMA
+    keep pace with the CPU while the PPU, APU and cartridge clock do not.
+    */
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+
+        if self.timer.tick() {
+            self.request_interrupt(TIMER_INTERRUPT_BIT);
+        }
+
+        if self.serial.tick() {
+            self.request_interrupt(SERIAL_INTERRUPT_BIT);
+        }
+
+        if let Some((source, index)) = self.oam_dma.tick() {
+            let value = self.read_unrestricted(source);
+            self.oam_dma.set_last_byte(value);
+            self.ppu.write_oam_dma(index, value);
+        }
+
+        let dots = if self.double_speed { 2 } else { 4 };
+        self.interrupt_flag |= self.ppu.tick(dots);
+
+        /* The frame sequencer follows DIV bit 4, or bit 5 in double speed, so it stays at 512 Hz. */
+        let sequencer_bit = if self.double_speed { 13 } else { 12 };
+        self.apu.tick(dots, (self.timer.divider() >> sequencer_bit) & 0x1 != 0);
+
+        if !self.double_speed || self.cycles.is_multiple_of(2) {
+            if let Some(cartridge) = &mut self.cartridge {
+                cartridge.tick(1);
+            }
+        }
+
+        if self.ppu.take_hblank_started() && self.vram_dma.is_hblank_active() {
+            self.run_vram_dma_block();
+        }
+    }
+
+    /*
+    Copy one 16 byte VRAM DMA block. The CPU is stalled while it
+    runs: two bytes move per normal speed M-cycle, so the copy
+    takes 8 M-cycles, or 16 in double speed. Returns false once
+    there is nothing left to copy.
+    */
+    fn run_vram_dma_block(&mut self) -> bool {
+        let (source, destination) = match self.vram_dma.next_block() {
+            Some(block) => block,
+            None => return false,
+        };
+
+        let bytes_per_cycle = if self.double_speed { 1 } else { 2 };
+        for offset in 0..VRAM_DMA_BLOCK_SIZE {
+            let value = self.read_unrestricted(source.wrapping_add(offset));
+            self.ppu.write_vram(destination + offset, value);
+            if (offset + 1) % bytes_per_cycle == 0 {
+                self.tick();
+            }
+        }
+        true
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    /* Toggle CPU speed as STOP does with a switch armed. The divider is reset in the process. */
+    pub fn switch_speed(&mut self) {
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        self.timer.write(DIV_ADDRESS, 0);
+    }
+
+    /* Have LY always read 0x90 so traces line up with Gameboy Doctor's reference logs. */
+    pub fn set_ly_stub(&mut self, ly_stub: bool) {
+        self.ly_stub = ly_stub;
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn is_cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    /* M-cycles elapsed since power on. */
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn request_interrupt(&mut self, bit: u8) {
+        self.interrupt_flag |= 1 << bit;
+    }
+
+    /*
+    A CPU read. During OAM DMA, OAM reads back 0xFF and reading from
+    the bus the DMA is using returns whatever byte the DMA just fetched.
+    The other bus still works: a DMA from cartridge or work RAM leaves
+    VRAM readable and the reverse. This follows the hardware and is
+    deliberately looser than the usual "only HRAM is accessible" rule,
+    which describes the common case of copying from work RAM while the
+    code runs from ROM on the same external bus.
+    */
+    pub fn read_byte(&self, address: u16) -> u8 {
+        if self.oam_dma.is_active() {
+            match address {
+                0xFE00..=0xFEFF => return 0xFF,
+                _ if bus_region(address) == bus_region(self.oam_dma.source()) => {
+                    return self.oam_dma.last_byte();
+                }
+                _ => {}
+            }
+        }
+
+        self.read_unrestricted(address)
+    }
+
+    /* A CPU write. Writes the DMA is contending with are dropped. */
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        if self.oam_dma.is_active() {
+            match address {
+                0xFE00..=0xFEFF => return,
+                _ if bus_region(address) == bus_region(self.oam_dma.source()) => return,
+                _ => {}
+            }
+        }
+
+        self.write_unrestricted(address, value);
+    }
+
+    /* A read as seen by the DMA engines and debuggers, ignoring contention. */
+    pub fn read_unrestricted(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => match &self.cartridge {
+                Some(cartridge) => cartridge.read(address),
+                None => 0xFF,
+            },
+            0x8000..=0x9FFF => self.ppu.read_vram(address),
+            0xC000..=0xFDFF => self.wram[self.wram_offset(address)],
+            0xFE00..=0xFE9F => self.ppu.read_oam(address),
+            0xFEA0..=0xFEFF => 0xFF,
+            0xFF00..=0xFF7F => self.read_io(address),
+            0xFF80..=0xFFFE => self.hram[address as usize - 0xFF80],
+            INTERRUPT_ENABLE_ADDRESS => self.interrupt_enable,
+        }
+    }
+
+    pub fn write_unrestricted(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => {
+                if let Some(cartridge) = &mut self.cartridge {
+                    cartridge.write(address, value);
+                }
+            }
+            0x8000..=0x9FFF => self.ppu.write_vram(address, value),
+            0xC000..=0xFDFF => self.wram[self.wram_offset(address)] = value,
+            0xFE00..=0xFE9F => self.ppu.write_oam(address, value),
+            0xFEA0..=0xFEFF => {}
+            0xFF00..=0xFF7F => self.write_io(address, value),
+            0xFF80..=0xFFFE => self.hram[address as usize - 0xFF80] = value,
+            INTERRUPT_ENABLE_ADDRESS => self.interrupt_enable = value,
+        }
+    }
+
+    /* Offset into work RAM for 0xC000-0xDFFF and its echo at 0xE000-0xFDFF. */
+    fn wram_offset(&self, address: u16) -> usize {
+        let offset = (address as usize - 0xC000) & 0x1FFF;
+        if offset < WRAM_BANK_SIZE {
+            offset
+        } else {
+            self.wram_bank * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+        }
+    }
+
+    fn read_io(&self, address: u16) -> u8 {
+        match address {
+            JOYPAD_ADDRESS => self.joypad.read(),
+            SB_ADDRESS | SC_ADDRESS => self.serial.read(address),
+            0xFF04..=0xFF07 => self.timer.read(address),
+            INTERRUPT_FLAG_ADDRESS => self.interrupt_flag | 0xE0,
+            OAM_DMA_ADDRESS => self.oam_dma.read_register(),
+            NR10_ADDRESS..=WAVE_RAM_END => self.apu.read(address),
+            LY_ADDRESS if self.ly_stub => 0x90,
+            0xFF40..=0xFF4B | VBK_ADDRESS | BCPS_ADDRESS..=OCPD_ADDRESS => self.ppu.read_register(address),
+            KEY1_ADDRESS if self.cgb_mode => {
+                let speed = if self.double_speed { KEY1_DOUBLE_SPEED } else { 0 };
+                let armed = if self.speed_switch_armed { KEY1_SWITCH_ARMED } else { 0 };
+                0x7E | speed | armed
+            }
+            KEY1_ADDRESS => 0xFF,
+            HDMA1_ADDRESS..=HDMA5_ADDRESS if self.cgb_mode => self.vram_dma.read_register(address),
+            HDMA1_ADDRESS..=HDMA5_ADDRESS => 0xFF,
+            SVBK_ADDRESS if self.cgb_mode => 0xF8 | self.wram_bank as u8,
+            SVBK_ADDRESS => 0xFF,
+            _ => self.io[address as usize - 0xFF00],
+        }
+    }
+
+    fn write_io(&mut self, address: u16, value: u8) {
+        match address {
+            JOYPAD_ADDRESS => {
+                if self.joypad.write(value) {
+                    self.request_interrupt(JOYPAD_INTERRUPT_BIT);
+                }
+            }
+            SB_ADDRESS | SC_ADDRESS => self.serial.write(address, value),
+            0xFF04..=0xFF07 => self.timer.write(address, value),
+            INTERRUPT_FLAG_ADDRESS => self.interrupt_flag = value & 0x1F,
+            OAM_DMA_ADDRESS => self.oam_dma.write_register(value),
+            NR10_ADDRESS..=WAVE_RAM_END => self.apu.write(address, value),
+            0xFF40..=0xFF4B | VBK_ADDRESS | BCPS_ADDRESS..=OCPD_ADDRESS => self.ppu.write_register(address, value),
+            KEY1_ADDRESS if self.cgb_mode => self.speed_switch_armed = value & KEY1_SWITCH_ARMED != 0,
+            KEY1_ADDRESS => {}
+            HDMA1_ADDRESS..=HDMA5_ADDRESS if self.cgb_mode => {
+                self.vram_dma.write_register(address, value);
+                if self.vram_dma.take_general_pending() {
+                    while self.run_vram_dma_block() {}
+                } else if address == HDMA5_ADDRESS && self.vram_dma.is_hblank_active() && self.ppu.mode() == Mode::HBlank {
+                    /*
+                    Started during HBlank, or with the LCD off where STAT also
+                    reads mode 0, the first block goes right away. In modes 2,
+                    3 and VBlank it waits for the next HBlank.
+                    */
+                    self.run_vram_dma_block();
+                }
+            }
+            HDMA1_ADDRESS..=HDMA5_ADDRESS => {}
+            /* Bank 0 cannot be mapped twice; selecting it gives bank 1. */
+            SVBK_ADDRESS if self.cgb_mode => self.wram_bank = (value as usize & 0x07).max(1),
+            SVBK_ADDRESS => {}
+            _ => self.io[address as usize - 0xFF00] = value,
+        }
+    }
+}
+
+impl Bus for MemoryBus {
+    fn tick(&mut self) {
+        MemoryBus::tick(self);
+    }
+
+    fn read_byte(&mut self, address: u16) -> u8 {
+        MemoryBus::read_byte(self, address)
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        MemoryBus::write_byte(self, address, value);
+    }
+
+    fn peek_byte(&self, address: u16) -> u8 {
+        self.read_unrestricted(address)
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.interrupt_enable & self.interrupt_flag & 0x1F
+    }
+
+    fn acknowledge_interrupt(&mut self, bit: u8) {
+        self.interrupt_flag &= !(1 << bit);
+    }
+
+    /* Without a speed switch armed, STOP resets DIV. */
+    fn stop(&mut self) -> bool {
+        if self.speed_switch_armed {
+            self.switch_speed();
+            true
+        } else {
+            self.timer.write(DIV_ADDRESS, 0);
+            false
+        }
+    }
+
+    fn wakes_from_stop(&self) -> bool {
+        self.joypad.wakes_from_stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bus, MemoryBus, KEY1_ADDRESS, SVBK_ADDRESS};
+    use crate::cartridge::Cartridge;
+    use crate::dma::{HDMA1_ADDRESS, HDMA2_ADDRESS, HDMA3_ADDRESS, HDMA4_ADDRESS, HDMA5_ADDRESS};
+    use crate::model::Model;
+    use crate::ppu::{Mode, LCDC_ADDRESS};
+
+    /* A CGB running a ROM-only cartridge that declares CGB support. */
+    fn cgb_bus() -> MemoryBus {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = 0x80;
+        MemoryBus::new(Some(Cartridge::new(rom).unwrap()), Model::Cgb)
+    }
+
+    #[test]
+    fn svbk_banks_the_upper_work_ram() {
+        let mut bus = cgb_bus();
+        for bank in 1..8 {
+            bus.write_byte(SVBK_ADDRESS, bank);
+            bus.write_byte(0xD000, 0x10 + bank);
+        }
+        bus.write_byte(0xC000, 0xC0);
+
+        bus.write_byte(SVBK_ADDRESS, 0x03);
+        assert_eq!(bus.read_byte(SVBK_ADDRESS), 0xFB);
+        assert_eq!(bus.read_byte(0xD000), 0x13);
+        assert_eq!(bus.read_byte(0xF000), 0x13, "echo RAM follows the bank");
+
+        /* Bank 0 and the unused upper bits both end up on bank 1. */
+        bus.write_byte(SVBK_ADDRESS, 0x00);
+        assert_eq!(bus.read_byte(SVBK_ADDRESS), 0xF9);
+        assert_eq!(bus.read_byte(0xD000), 0x11);
+        bus.write_byte(SVBK_ADDRESS, 0x0F);
+        assert_eq!(bus.read_byte(0xD000), 0x17);
+        bus.write_byte(SVBK_ADDRESS, 0x08);
+        assert_eq!(bus.read_byte(0xD000), 0x11);
+
+        assert_eq!(bus.read_byte(0xC000), 0xC0);
+    }
+
+    #[test]
+    fn svbk_is_absent_outside_cgb_mode() {
+        let mut bus = MemoryBus::new(None, Model::Cgb);
+        bus.write_byte(0xD000, 0x11);
+        bus.write_byte(SVBK_ADDRESS, 0x02);
+        assert_eq!(bus.read_byte(SVBK_ADDRESS), 0xFF);
+        assert_eq!(bus.read_byte(0xD000), 0x11);
+    }
+
+    /* Fill work RAM from 0xC000 with a counting pattern and point the VRAM DMA from there to 0x8000. */
+    fn prepare_vram_dma(bus: &mut MemoryBus) {
+        for offset in 0..0x80u16 {
+            bus.write_byte(0xC000 + offset, offset as u8 + 1);
+        }
+        bus.write_byte(HDMA1_ADDRESS, 0xC0);
+        bus.write_byte(HDMA2_ADDRESS, 0x00);
+        bus.write_byte(HDMA3_ADDRESS, 0x00);
+        bus.write_byte(HDMA4_ADDRESS, 0x00);
+    }
+
+    fn copied_blocks(bus: &MemoryBus) -> u16 {
+        (0..0x80u16).filter(|&offset| bus.read_byte(0x8000 + offset) != 0).count() as u16 / 16
+    }
+
+    fn tick_until_mode(bus: &mut MemoryBus, mode: Mode) {
+        while bus.ppu.mode() != mode {
+            bus.tick();
+        }
+    }
+
+    #[test]
+    fn general_dma_copies_everything_at_once() {
+        let mut bus = cgb_bus();
+        bus.write_byte(LCDC_ADDRESS, 0x00);
+        prepare_vram_dma(&mut bus);
+
+        let start = bus.cycles();
+        bus.write_byte(HDMA5_ADDRESS, 0x02);
+        assert_eq!(bus.cycles() - start, 3 * 8, "8 M-cycles per block");
+        assert_eq!(copied_blocks(&bus), 3);
+        assert_eq!(bus.read_byte(0x8000), 0x01);
+        assert_eq!(bus.read_byte(0x802F), 0x30);
+        assert_eq!(bus.read_byte(HDMA5_ADDRESS), 0xFF);
+
+        /* Double speed moves half as many bytes per M-cycle. */
+        bus.switch_speed();
+        let start = bus.cycles();
+        bus.write_byte(HDMA5_ADDRESS, 0x00);
+        assert_eq!(bus.cycles() - start, 16);
+    }
+
+    #[test]
+    fn hblank_dma_copies_one_block_per_hblank_until_cancelled() {
+        let mut bus = cgb_bus();
+        prepare_vram_dma(&mut bus);
+        tick_until_mode(&mut bus, Mode::OamSearch);
+
+        bus.write_byte(HDMA5_ADDRESS, 0x83);
+        assert_eq!(bus.read_byte(HDMA5_ADDRESS), 0x03, "bit 7 reads clear while running");
+        tick_until_mode(&mut bus, Mode::HBlank);
+        assert_eq!(copied_blocks(&bus), 1);
+        assert_eq!(bus.read_byte(HDMA5_ADDRESS), 0x02);
+
+        tick_until_mode(&mut bus, Mode::OamSearch);
+        tick_until_mode(&mut bus, Mode::HBlank);
+        assert_eq!(copied_blocks(&bus), 2);
+
+        bus.write_byte(HDMA5_ADDRESS, 0x00);
+        assert_eq!(bus.read_byte(HDMA5_ADDRESS), 0x81, "cancelled with two blocks left");
+        tick_until_mode(&mut bus, Mode::OamSearch);
+        tick_until_mode(&mut bus, Mode::HBlank);
+        assert_eq!(copied_blocks(&bus), 2);
+    }
+
+    #[test]
+    fn hblank_dma_with_the_lcd_off_starts_right_away() {
+        let mut bus = cgb_bus();
+        bus.write_byte(LCDC_ADDRESS, 0x00);
+        prepare_vram_dma(&mut bus);
+
+        bus.write_byte(HDMA5_ADDRESS, 0x81);
+        assert_eq!(copied_blocks(&bus), 1);
+        assert_eq!(bus.read_byte(HDMA5_ADDRESS), 0x00);
+    }
+
+    #[test]
+    fn stop_toggles_double_speed_only_when_armed() {
+        let mut bus = cgb_bus();
+        assert_eq!(bus.read_byte(KEY1_ADDRESS), 0x7E);
+        assert!(!Bus::stop(&mut bus));
+        assert!(!bus.is_double_speed());
+
+        bus.write_byte(KEY1_ADDRESS, 0x01);
+        assert_eq!(bus.read_byte(KEY1_ADDRESS), 0x7F);
+        assert!(Bus::stop(&mut bus));
+        assert!(bus.is_double_speed());
+        assert_eq!(bus.read_byte(KEY1_ADDRESS), 0xFE, "switching disarms");
+
+        bus.write_byte(KEY1_ADDRESS, 0x01);
+        assert!(Bus::stop(&mut bus));
+        assert!(!bus.is_double_speed());
+    }
+}
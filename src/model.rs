@@ -0,0 +1,33 @@
+use std::str::FromStr;
+
+use crate::cartridge::Header;
+
+/* Which console is being emulated. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Model {
+    Dmg,
+    Cgb,
+}
+
+impl Model {
+    /* The console a cartridge would normally be played on: a CGB for anything that declares CGB support. */
+    pub fn for_header(header: &Header) -> Model {
+        if header.supports_cgb() {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        }
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Model, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "dmg" => Ok(Model::Dmg),
+            "cgb" => Ok(Model::Cgb),
+            _ => Err(format!("unknown model '{}'", name)),
+        }
+    }
+}
@@ -0,0 +1,560 @@
+use std::fmt;
+
+use crate::checksum::crc32;
+
+/* The largest cartridge ROM, 512 banks of 16 KiB. UPS and BPS targets beyond it are rejected. */
+const MAX_TARGET_SIZE: usize = 8 << 20;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    UnknownFormat,
+    Truncated(PatchFormat),
+    InvalidCommand(PatchFormat),
+    SourceSizeMismatch { expected: usize, actual: usize },
+    SourceChecksumMismatch { expected: u32, actual: u32 },
+    TargetChecksumMismatch { expected: u32, actual: u32 },
+    PatchChecksumMismatch { expected: u32, actual: u32 },
+    TargetTooLarge(usize),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::Truncated(format) => write!(f, "{:?} patch ends unexpectedly", format),
+            PatchError::InvalidCommand(format) => write!(f, "{:?} patch contains an invalid command", format),
+            PatchError::SourceSizeMismatch { expected, actual } => write!(
+                f,
+                "patch expects a {} byte ROM but the ROM is {} bytes",
+                expected, actual
+            ),
+            PatchError::SourceChecksumMismatch { expected, actual } => write!(
+                f,
+                "patch expects a ROM with CRC32 {:08X} but the ROM has {:08X}",
+                expected, actual
+            ),
+            PatchError::TargetChecksumMismatch { expected, actual } => write!(
+                f,
+                "patched ROM has CRC32 {:08X}, patch expects {:08X}",
+                actual, expected
+            ),
+            PatchError::PatchChecksumMismatch { expected, actual } => write!(
+                f,
+                "patch file is corrupt: CRC32 {:08X}, expected {:08X}",
+                actual, expected
+            ),
+            PatchError::TargetTooLarge(size) => {
+                write!(f, "patch would produce a {} byte ROM, larger than any cartridge", size)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+impl PatchFormat {
+    /* Identify a patch by its magic bytes. */
+    pub fn detect(patch: &[u8]) -> Option<PatchFormat> {
+        if patch.starts_with(b"PATCH") {
+            Some(PatchFormat::Ips)
+        } else if patch.starts_with(b"UPS1") {
+            Some(PatchFormat::Ups)
+        } else if patch.starts_with(b"BPS1") {
+            Some(PatchFormat::Bps)
+        } else {
+            None
+        }
+    }
+}
+
+/* Apply an IPS, UPS or BPS patch to a ROM image, returning the patched image. */
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match PatchFormat::detect(patch) {
+        Some(PatchFormat::Ips) => apply_ips(rom, patch),
+        Some(PatchFormat::Ups) => apply_ups(rom, patch),
+        Some(PatchFormat::Bps) => apply_bps(rom, patch),
+        None => Err(PatchError::UnknownFormat),
+    }
+}
+
+/*
+Sequential reader over patch bytes. Every read reports
+truncation against the format being decoded.
+*/
+struct PatchReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    format: PatchFormat,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], start: usize, format: PatchFormat) -> PatchReader<'a> {
+        PatchReader { data, position: start, format }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, PatchError> {
+        let byte = *self.data.get(self.position).ok_or(PatchError::Truncated(self.format))?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], PatchError> {
+        let end = self.position.checked_add(length).ok_or(PatchError::Truncated(self.format))?;
+        let bytes = self.data.get(self.position..end).ok_or(PatchError::Truncated(self.format))?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn read_be(&mut self, length: usize) -> Result<usize, PatchError> {
+        let bytes = self.read_bytes(length)?;
+        Ok(bytes.iter().fold(0, |value, &byte| (value << 8) | byte as usize))
+    }
+
+    /* Variable length integer shared by UPS and BPS. */
+    fn read_number(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.read_byte()?;
+            value = (byte as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|part| value.checked_add(part))
+                .ok_or(PatchError::InvalidCommand(self.format))?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or(PatchError::InvalidCommand(self.format))?;
+            value = value.checked_add(shift).ok_or(PatchError::InvalidCommand(self.format))?;
+        }
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut output = rom.to_vec();
+    let mut reader = PatchReader::new(patch, 5, PatchFormat::Ips);
+
+    loop {
+        if reader.data.get(reader.position..reader.position + 3) == Some(b"EOF") {
+            reader.position += 3;
+            break;
+        }
+
+        let offset = reader.read_be(3)?;
+        let size = reader.read_be(2)?;
+
+        /* A zero size marks a run length encoded record. */
+        let (size, run_value) = if size == 0 {
+            (reader.read_be(2)?, Some(reader.read_byte()?))
+        } else {
+            (size, None)
+        };
+
+        if output.len() < offset + size {
+            output.resize(offset + size, 0);
+        }
+        match run_value {
+            Some(value) => output[offset..offset + size].fill(value),
+            None => output[offset..offset + size].copy_from_slice(reader.read_bytes(size)?),
+        }
+    }
+
+    /* Optional truncation extension after the EOF marker. */
+    if reader.data.len() - reader.position == 3 {
+        let length = reader.read_be(3)?;
+        output.truncate(length);
+    }
+
+    Ok(output)
+}
+
+/*
+UPS and BPS end with the source, target and patch CRC32s.
+The patch CRC is verified here; the other two are returned.
+*/
+fn read_footer(patch: &[u8], format: PatchFormat) -> Result<(u32, u32), PatchError> {
+    if patch.len() < 16 {
+        return Err(PatchError::Truncated(format));
+    }
+
+    let footer = &patch[patch.len() - 12..];
+    let word = |index: usize| {
+        u32::from_le_bytes([footer[index], footer[index + 1], footer[index + 2], footer[index + 3]])
+    };
+
+    let patch_crc = crc32(&patch[..patch.len() - 4]);
+    if patch_crc != word(8) {
+        return Err(PatchError::PatchChecksumMismatch { expected: word(8), actual: patch_crc });
+    }
+
+    Ok((word(0), word(4)))
+}
+
+fn check_source(rom: &[u8], expected_size: usize, expected_crc: u32) -> Result<(), PatchError> {
+    if rom.len() != expected_size {
+        return Err(PatchError::SourceSizeMismatch { expected: expected_size, actual: rom.len() });
+    }
+
+    let actual = crc32(rom);
+    if actual != expected_crc {
+        return Err(PatchError::SourceChecksumMismatch { expected: expected_crc, actual });
+    }
+    Ok(())
+}
+
+fn check_target_size(size: usize) -> Result<(), PatchError> {
+    if size > MAX_TARGET_SIZE {
+        return Err(PatchError::TargetTooLarge(size));
+    }
+    Ok(())
+}
+
+fn check_target(output: &[u8], expected_crc: u32) -> Result<(), PatchError> {
+    let actual = crc32(output);
+    if actual != expected_crc {
+        return Err(PatchError::TargetChecksumMismatch { expected: expected_crc, actual });
+    }
+    Ok(())
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (source_crc, target_crc) = read_footer(patch, PatchFormat::Ups)?;
+    let end = patch.len() - 12;
+    let mut reader = PatchReader::new(&patch[..end], 4, PatchFormat::Ups);
+
+    let source_size = reader.read_number()?;
+    let target_size = reader.read_number()?;
+    check_source(rom, source_size, source_crc)?;
+    check_target_size(target_size)?;
+
+    let invalid = || PatchError::InvalidCommand(PatchFormat::Ups);
+    let mut output = rom.to_vec();
+    output.resize(target_size.max(source_size), 0);
+
+    /* Hunks skip ahead then XOR bytes into place until a zero byte. */
+    let mut position: usize = 0;
+    while reader.position < end {
+        position = position.checked_add(reader.read_number()?).ok_or_else(invalid)?;
+        loop {
+            let byte = reader.read_byte()?;
+            if byte == 0 {
+                position = position.checked_add(1).ok_or_else(invalid)?;
+                break;
+            }
+            if position >= output.len() {
+                return Err(invalid());
+            }
+            output[position] ^= byte;
+            position += 1;
+        }
+    }
+
+    output.truncate(target_size);
+    check_target(&output, target_crc)?;
+    Ok(output)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (source_crc, target_crc) = read_footer(patch, PatchFormat::Bps)?;
+    let end = patch.len() - 12;
+    let mut reader = PatchReader::new(&patch[..end], 4, PatchFormat::Bps);
+
+    let source_size = reader.read_number()?;
+    let target_size = reader.read_number()?;
+    let metadata_size = reader.read_number()?;
+    reader.read_bytes(metadata_size)?;
+    check_source(rom, source_size, source_crc)?;
+    check_target_size(target_size)?;
+
+    let invalid = || PatchError::InvalidCommand(PatchFormat::Bps);
+    let mut output: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+
+    while reader.position < end {
+        let data = reader.read_number()?;
+        let length = (data >> 2) + 1;
+        /* Checked up front so a TargetCopy cannot run away before the size is looked at. */
+        if length > target_size - output.len() {
+            return Err(invalid());
+        }
+
+        match data & 0x03 {
+            /* SourceRead: copy from the same position in the source. */
+            0 => {
+                let start = output.len();
+                let end = start.checked_add(length).ok_or_else(invalid)?;
+                let bytes = rom.get(start..end).ok_or_else(invalid)?;
+                output.extend_from_slice(bytes);
+            }
+            /* TargetRead: literal bytes stored in the patch. */
+            1 => {
+                output.extend_from_slice(reader.read_bytes(length)?);
+            }
+            /* SourceCopy and TargetCopy: copy from a relative offset. */
+            command => {
+                let offset = reader.read_number()?;
+                let base = if command == 2 { &mut source_offset } else { &mut target_offset };
+                *base = if offset & 1 != 0 {
+                    base.checked_sub(offset >> 1)
+                } else {
+                    base.checked_add(offset >> 1)
+                }
+                .ok_or_else(invalid)?;
+
+                for _ in 0..length {
+                    let byte = if command == 2 {
+                        rom.get(source_offset).copied()
+                    } else {
+                        output.get(target_offset).copied()
+                    }
+                    .ok_or_else(invalid)?;
+                    output.push(byte);
+
+                    if command == 2 {
+                        source_offset += 1;
+                    } else {
+                        target_offset += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    if output.len() != target_size {
+        return Err(invalid());
+    }
+    check_target(&output, target_crc)?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, PatchError, PatchFormat, MAX_TARGET_SIZE};
+    use crate::checksum::crc32;
+
+    fn push_number(patch: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                patch.push(byte | 0x80);
+                return;
+            }
+            patch.push(byte);
+            value -= 1;
+        }
+    }
+
+    /* Append the source, target and patch CRC32s that close a UPS or BPS patch. */
+    fn push_footer(patch: &mut Vec<u8>, source: &[u8], target: &[u8]) {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let patch_crc = crc32(patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+    }
+
+    /* UPS patch turning `source` into `target`, one hunk per changed byte. Changes must not be adjacent. */
+    fn ups_patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = b"UPS1".to_vec();
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, target.len());
+        let mut position = 0;
+        for (index, &to) in target.iter().enumerate() {
+            let from = source.get(index).copied().unwrap_or(0);
+            if from != to {
+                push_number(&mut patch, index - position);
+                patch.extend_from_slice(&[from ^ to, 0]);
+                position = index + 2;
+            }
+        }
+        push_footer(&mut patch, source, target);
+        patch
+    }
+
+    /* BPS patch that copies the first half of `source` and stores the rest of `target` literally. */
+    fn bps_patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let copied = source.len() / 2;
+        let mut patch = b"BPS1".to_vec();
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, target.len());
+        push_number(&mut patch, 0);
+        push_number(&mut patch, (copied - 1) << 2);
+        push_number(&mut patch, ((target.len() - copied - 1) << 2) | 1);
+        patch.extend_from_slice(&target[copied..]);
+        push_footer(&mut patch, source, target);
+        patch
+    }
+
+    #[test]
+    fn ips_copies_records_and_fills_runs() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0x11, 0x22]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x03, 0xAA]);
+        patch.extend_from_slice(b"EOF");
+
+        let output = apply(&[0; 8], &patch).unwrap();
+        assert_eq!(output, [0x00, 0x11, 0x22, 0x00, 0xAA, 0xAA, 0xAA, 0x00]);
+    }
+
+    #[test]
+    fn ips_grows_the_rom_for_records_past_the_end() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x02, 0x55]);
+        patch.extend_from_slice(b"EOF");
+
+        let output = apply(&[1; 4], &patch).unwrap();
+        assert_eq!(output, [1, 1, 1, 1, 0, 0, 0x55, 0x55]);
+    }
+
+    #[test]
+    fn ips_truncates_to_the_length_after_eof() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x01, 0x99]);
+        patch.extend_from_slice(b"EOF");
+        patch.extend_from_slice(&[0x00, 0x00, 0x03]);
+
+        let output = apply(&[7; 8], &patch).unwrap();
+        assert_eq!(output, [0x99, 7, 7]);
+    }
+
+    #[test]
+    fn ips_without_eof_is_truncated() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x04, 0x99]);
+
+        assert_eq!(apply(&[0; 8], &patch), Err(PatchError::Truncated(PatchFormat::Ips)));
+    }
+
+    #[test]
+    fn ups_applies_hunks() {
+        let source = [1, 2, 3, 4, 5, 6];
+        let target = [1, 9, 3, 4, 8, 6, 0, 7];
+
+        assert_eq!(apply(&source, &ups_patch(&source, &target)).unwrap(), target);
+    }
+
+    #[test]
+    fn ups_rejects_checksum_mismatches() {
+        let source = [1, 2, 3, 4];
+        let target = [1, 2, 5, 4];
+        let patch = ups_patch(&source, &target);
+
+        let mut corrupt = patch.clone();
+        corrupt[6] ^= 0x01;
+        assert!(matches!(apply(&source, &corrupt), Err(PatchError::PatchChecksumMismatch { .. })));
+
+        assert!(matches!(apply(&[1, 2, 3, 5], &patch), Err(PatchError::SourceChecksumMismatch { .. })));
+
+        /* Claim a different target but keep the patch CRC valid. */
+        let mut wrong_target = ups_patch(&source, &target);
+        wrong_target.truncate(wrong_target.len() - 8);
+        wrong_target.extend_from_slice(&crc32(&[0; 4]).to_le_bytes());
+        let patch_crc = crc32(&wrong_target);
+        wrong_target.extend_from_slice(&patch_crc.to_le_bytes());
+        assert!(matches!(apply(&source, &wrong_target), Err(PatchError::TargetChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn bps_copies_source_and_target_reads() {
+        let source = [1, 2, 3, 4, 5, 6];
+        let target = [1, 2, 3, 9, 9, 9, 9];
+
+        assert_eq!(apply(&source, &bps_patch(&source, &target)).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_rejects_checksum_mismatches() {
+        let source = [1, 2, 3, 4];
+        let target = [1, 2, 7, 8];
+        let patch = bps_patch(&source, &target);
+
+        let mut corrupt = patch.clone();
+        let literal = patch.len() - 14;
+        corrupt[literal] ^= 0x01;
+        assert!(matches!(apply(&source, &corrupt), Err(PatchError::PatchChecksumMismatch { .. })));
+
+        assert!(matches!(apply(&[1, 2, 3, 5], &patch), Err(PatchError::SourceChecksumMismatch { .. })));
+        assert_eq!(
+            apply(&[1, 2, 3], &patch),
+            Err(PatchError::SourceSizeMismatch { expected: 4, actual: 3 })
+        );
+
+        let mut wrong_target = patch[..patch.len() - 8].to_vec();
+        wrong_target.extend_from_slice(&crc32(&[0; 4]).to_le_bytes());
+        let patch_crc = crc32(&wrong_target);
+        wrong_target.extend_from_slice(&patch_crc.to_le_bytes());
+        assert!(matches!(apply(&source, &wrong_target), Err(PatchError::TargetChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn ups_rejects_a_target_larger_than_any_cartridge() {
+        let source = [1, 2, 3, 4];
+        let mut patch = b"UPS1".to_vec();
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, usize::MAX >> 1);
+        push_footer(&mut patch, &source, &source);
+
+        assert_eq!(apply(&source, &patch), Err(PatchError::TargetTooLarge(usize::MAX >> 1)));
+    }
+
+    #[test]
+    fn ups_rejects_hunks_that_overflow_the_position() {
+        let source = [1, 2, 3, 4];
+        let mut patch = b"UPS1".to_vec();
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, source.len());
+        for _ in 0..2 {
+            push_number(&mut patch, usize::MAX >> 1);
+            patch.push(0);
+        }
+        push_footer(&mut patch, &source, &source);
+
+        assert_eq!(apply(&source, &patch), Err(PatchError::InvalidCommand(PatchFormat::Ups)));
+    }
+
+    #[test]
+    fn bps_rejects_a_target_larger_than_any_cartridge() {
+        let source = [1, 2, 3, 4];
+        let mut patch = b"BPS1".to_vec();
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, MAX_TARGET_SIZE + 1);
+        push_number(&mut patch, 0);
+        push_footer(&mut patch, &source, &source);
+
+        assert_eq!(apply(&source, &patch), Err(PatchError::TargetTooLarge(MAX_TARGET_SIZE + 1)));
+    }
+
+    #[test]
+    fn bps_rejects_commands_that_run_past_the_target() {
+        let source = [1, 2, 3, 4];
+        let target = [1, 1, 1, 1];
+
+        /* One literal byte, then a TargetCopy of it repeated about 2^61 times. */
+        let mut patch = b"BPS1".to_vec();
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, target.len());
+        push_number(&mut patch, 0);
+        push_number(&mut patch, 1);
+        patch.push(1);
+        push_number(&mut patch, ((usize::MAX >> 3) << 2) | 3);
+        push_number(&mut patch, 0);
+        push_footer(&mut patch, &source, &target);
+        assert_eq!(apply(&source, &patch), Err(PatchError::InvalidCommand(PatchFormat::Bps)));
+
+        /* A SourceRead whose end overflows. */
+        let mut patch = b"BPS1".to_vec();
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, target.len());
+        push_number(&mut patch, 0);
+        push_number(&mut patch, usize::MAX & !0x03);
+        push_footer(&mut patch, &source, &target);
+        assert_eq!(apply(&source, &patch), Err(PatchError::InvalidCommand(PatchFormat::Bps)));
+    }
+}
@@ -0,0 +1,274 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::checksum::{adler32, crc32_update};
+use crate::inflate::{inflate, InflateError};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+const COLOR_TYPE_GRAY: u8 = 0;
+const COLOR_TYPE_RGB: u8 = 2;
+const COLOR_TYPE_INDEXED: u8 = 3;
+const COLOR_TYPE_GRAY_ALPHA: u8 = 4;
+const COLOR_TYPE_RGBA: u8 = 6;
+
+const FILTER_NONE: u8 = 0;
+const FILTER_SUB: u8 = 1;
+const FILTER_UP: u8 = 2;
+const FILTER_AVERAGE: u8 = 3;
+const FILTER_PAETH: u8 = 4;
+
+/* zlib compression method 8 is deflate, the only one PNG allows. */
+const ZLIB_METHOD_DEFLATE: u8 = 8;
+
+/* Longest block deflate can store uncompressed. */
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/*
+Encode 0xRRGGBB pixels, row by row, as an 8 bit RGB PNG. Nothing
+here needs small files, so the image data goes into stored deflate
+blocks rather than being compressed.
+*/
+pub fn encode_png(width: usize, height: usize, pixels: &[u32]) -> Vec<u8> {
+    assert_eq!(pixels.len(), width * height, "pixel count does not match image size");
+
+    let mut raw = Vec::with_capacity(height * (width * 3 + 1));
+    for row in pixels.chunks(width.max(1)).take(height) {
+        raw.push(FILTER_NONE);
+        for &pixel in row {
+            raw.extend_from_slice(&pixel.to_be_bytes()[1..]);
+        }
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, COLOR_TYPE_RGB, 0, 0, 0]);
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+pub fn write_png<P: AsRef<Path>>(path: P, width: usize, height: usize, pixels: &[u32]) -> io::Result<()> {
+    fs::write(path, encode_png(width, height, pixels))
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32_update(crc32_update(0, kind), data);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/* A zlib stream holding `data` in stored blocks. */
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+        stream.push(last as u8);
+        stream.extend_from_slice(&length.to_le_bytes());
+        stream.extend_from_slice(&(!length).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+#[derive(Debug)]
+pub enum PngError {
+    Io(io::Error),
+    InvalidSignature,
+    Truncated,
+    Unsupported(String),
+    Inflate(InflateError),
+}
+
+impl fmt::Display for PngError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PngError::Io(err) => write!(f, "{}", err),
+            PngError::InvalidSignature => write!(f, "not a PNG file"),
+            PngError::Truncated => write!(f, "PNG data ends early"),
+            PngError::Unsupported(what) => write!(f, "unsupported PNG: {}", what),
+            PngError::Inflate(err) => write!(f, "corrupt PNG image data: {:?}", err),
+        }
+    }
+}
+
+impl std::error::Error for PngError {}
+
+impl From<io::Error> for PngError {
+    fn from(err: io::Error) -> Self {
+        PngError::Io(err)
+    }
+}
+
+impl From<InflateError> for PngError {
+    fn from(err: InflateError) -> Self {
+        PngError::Inflate(err)
+    }
+}
+
+/* A decoded image as 0xRRGGBB pixels, row by row. */
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>,
+}
+
+pub fn read_png<P: AsRef<Path>>(path: P) -> Result<Image, PngError> {
+    decode_png(&fs::read(path)?)
+}
+
+/*
+Decode a non-interlaced PNG of any colour type, enough to read
+reference screenshots. Alpha is dropped and 16 bit samples are cut
+down to their high byte.
+*/
+pub fn decode_png(data: &[u8]) -> Result<Image, PngError> {
+    if data.len() < SIGNATURE.len() || data[..SIGNATURE.len()] != SIGNATURE {
+        return Err(PngError::InvalidSignature);
+    }
+
+    let mut header = None;
+    let mut palette = Vec::new();
+    let mut compressed = Vec::new();
+    let mut offset = SIGNATURE.len();
+    while offset + 8 <= data.len() {
+        let length = u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]) as usize;
+        let kind = &data[offset + 4..offset + 8];
+        let body = data.get(offset + 8..offset + 8 + length).ok_or(PngError::Truncated)?;
+        match kind {
+            b"IHDR" if body.len() >= 13 => header = Some(body.to_vec()),
+            b"PLTE" => {
+                palette = body
+                    .chunks_exact(3)
+                    .map(|rgb| u32::from_be_bytes([0, rgb[0], rgb[1], rgb[2]]))
+                    .collect();
+            }
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {}
+        }
+        offset += length + 12;
+    }
+
+    let header = header.ok_or(PngError::Truncated)?;
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let depth = header[8] as usize;
+    let color_type = header[9];
+    if header[12] != 0 {
+        return Err(PngError::Unsupported("interlaced image".to_string()));
+    }
+
+    let channels = match color_type {
+        COLOR_TYPE_GRAY | COLOR_TYPE_INDEXED => 1,
+        COLOR_TYPE_GRAY_ALPHA => 2,
+        COLOR_TYPE_RGB => 3,
+        COLOR_TYPE_RGBA => 4,
+        _ => return Err(PngError::Unsupported(format!("colour type {}", color_type))),
+    };
+    let depth_allowed = match color_type {
+        COLOR_TYPE_GRAY => matches!(depth, 1 | 2 | 4 | 8 | 16),
+        COLOR_TYPE_INDEXED => matches!(depth, 1 | 2 | 4 | 8),
+        _ => matches!(depth, 8 | 16),
+    };
+    if !depth_allowed {
+        return Err(PngError::Unsupported(format!("bit depth {} for colour type {}", depth, color_type)));
+    }
+
+    if compressed.len() < 2 || compressed[0] & 0x0F != ZLIB_METHOD_DEFLATE {
+        return Err(PngError::Unsupported("image data is not zlib deflate".to_string()));
+    }
+    let (raw, _) = inflate(&compressed[2..])?;
+
+    let bits_per_pixel = channels * depth;
+    let row_bytes = (width * bits_per_pixel).div_ceil(8);
+    let filter_distance = bits_per_pixel.div_ceil(8);
+    if raw.len() < height * (row_bytes + 1) {
+        return Err(PngError::Truncated);
+    }
+
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut previous = vec![0u8; row_bytes];
+    let mut row = vec![0u8; row_bytes];
+    for y in 0..height {
+        let start = y * (row_bytes + 1);
+        let filter = raw[start];
+        row.copy_from_slice(&raw[start + 1..start + 1 + row_bytes]);
+        unfilter(filter, &mut row, &previous, filter_distance)?;
+
+        for x in 0..width {
+            let sample = |channel: usize| -> u32 {
+                match depth {
+                    8 => row[x * channels + channel] as u32,
+                    16 => row[(x * channels + channel) * 2] as u32,
+                    _ => {
+                        let bit = x * depth;
+                        let shift = 8 - depth - bit % 8;
+                        ((row[bit / 8] >> shift) as u32) & ((1 << depth) - 1)
+                    }
+                }
+            };
+            let gray = |value: u32| {
+                let level = if depth < 8 { value * 255 / ((1 << depth) - 1) } else { value };
+                (level << 16) | (level << 8) | level
+            };
+            pixels.push(match color_type {
+                COLOR_TYPE_GRAY | COLOR_TYPE_GRAY_ALPHA => gray(sample(0)),
+                COLOR_TYPE_INDEXED => *palette
+                    .get(sample(0) as usize)
+                    .ok_or_else(|| PngError::Unsupported("palette index out of range".to_string()))?,
+                _ => (sample(0) << 16) | (sample(1) << 8) | sample(2),
+            });
+        }
+        std::mem::swap(&mut previous, &mut row);
+    }
+
+    Ok(Image { width, height, pixels })
+}
+
+/* Undo one row's filter in place. `distance` is the byte distance to the corresponding byte of the pixel to the left. */
+fn unfilter(filter: u8, row: &mut [u8], previous: &[u8], distance: usize) -> Result<(), PngError> {
+    for index in 0..row.len() {
+        let left = if index >= distance { row[index - distance] } else { 0 };
+        let up = previous[index];
+        let up_left = if index >= distance { previous[index - distance] } else { 0 };
+        let predicted = match filter {
+            FILTER_NONE => 0,
+            FILTER_SUB => left,
+            FILTER_UP => up,
+            FILTER_AVERAGE => ((left as u16 + up as u16) / 2) as u8,
+            FILTER_PAETH => paeth(left, up, up_left),
+            _ => return Err(PngError::Unsupported(format!("filter type {}", filter))),
+        };
+        row[index] = row[index].wrapping_add(predicted);
+    }
+    Ok(())
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let to_left = (estimate - left as i16).abs();
+    let to_up = (estimate - up as i16).abs();
+    let to_up_left = (estimate - up_left as i16).abs();
+    if to_left <= to_up && to_left <= to_up_left {
+        left
+    } else if to_up <= to_up_left {
+        up
+    } else {
+        up_left
+    }
+}
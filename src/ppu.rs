@@ -0,0 +1,1008 @@
+use crate::dma::OAM_SIZE;
+use crate::model::Model;
+
+mod fifo;
+mod palette;
+
+use fifo::PixelFifo;
+use palette::PaletteRam;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+pub const VBLANK_INTERRUPT_BIT: u8 = 0;
+pub const STAT_INTERRUPT_BIT: u8 = 1;
+
+const VRAM_SIZE: usize = 0x2000;
+const VRAM_BANKS: usize = 2;
+
+const DOTS_PER_LINE: u32 = 456;
+const OAM_SEARCH_DOTS: u32 = 80;
+const PIXEL_TRANSFER_DOTS: u32 = 172;
+const LINES_PER_FRAME: u8 = 154;
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+pub const LCDC_ADDRESS: u16 = 0xFF40;
+pub const STAT_ADDRESS: u16 = 0xFF41;
+pub const SCY_ADDRESS: u16 = 0xFF42;
+pub const SCX_ADDRESS: u16 = 0xFF43;
+pub const LY_ADDRESS: u16 = 0xFF44;
+pub const LYC_ADDRESS: u16 = 0xFF45;
+pub const BGP_ADDRESS: u16 = 0xFF47;
+pub const OBP0_ADDRESS: u16 = 0xFF48;
+pub const OBP1_ADDRESS: u16 = 0xFF49;
+pub const WY_ADDRESS: u16 = 0xFF4A;
+pub const WX_ADDRESS: u16 = 0xFF4B;
+pub const VBK_ADDRESS: u16 = 0xFF4F;
+pub const BCPS_ADDRESS: u16 = 0xFF68;
+pub const BCPD_ADDRESS: u16 = 0xFF69;
+pub const OCPS_ADDRESS: u16 = 0xFF6A;
+pub const OCPD_ADDRESS: u16 = 0xFF6B;
+
+const LCDC_BG_ENABLE: u8 = 1 << 0;
+const LCDC_OBJ_ENABLE: u8 = 1 << 1;
+const LCDC_OBJ_TALL: u8 = 1 << 2;
+const LCDC_BG_MAP: u8 = 1 << 3;
+const LCDC_TILE_DATA: u8 = 1 << 4;
+const LCDC_WINDOW_ENABLE: u8 = 1 << 5;
+const LCDC_WINDOW_MAP: u8 = 1 << 6;
+pub const LCDC_LCD_ENABLE: u8 = 1 << 7;
+
+const STAT_COINCIDENCE: u8 = 1 << 2;
+const STAT_HBLANK_SOURCE: u8 = 1 << 3;
+const STAT_VBLANK_SOURCE: u8 = 1 << 4;
+const STAT_OAM_SOURCE: u8 = 1 << 5;
+const STAT_LYC_SOURCE: u8 = 1 << 6;
+const STAT_SOURCES: u8 = STAT_HBLANK_SOURCE | STAT_VBLANK_SOURCE | STAT_OAM_SOURCE | STAT_LYC_SOURCE;
+
+/* Bits shared by CGB BG map attributes and sprite attributes. */
+const ATTR_CGB_PALETTE: u8 = 0x07;
+const ATTR_VRAM_BANK: u8 = 1 << 3;
+
+const BG_ATTR_X_FLIP: u8 = 1 << 5;
+const BG_ATTR_Y_FLIP: u8 = 1 << 6;
+const BG_ATTR_PRIORITY: u8 = 1 << 7;
+
+const OBJ_ATTR_PALETTE: u8 = 1 << 4;
+const OBJ_ATTR_X_FLIP: u8 = 1 << 5;
+const OBJ_ATTR_Y_FLIP: u8 = 1 << 6;
+const OBJ_ATTR_BEHIND_BG: u8 = 1 << 7;
+
+/* Shades 0-3 of the DMG LCD as 0xRRGGBB. */
+pub const DMG_SHADES: [u32; 4] = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
+
+/* Colours the CGB boot ROM gives DMG games it has no specific palette for. */
+const COMPATIBILITY_BG_COLORS: [u32; 4] = [0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000];
+const COMPATIBILITY_OBJ_COLORS: [u32; 4] = [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000];
+
+/*
+How mode 3 is emulated. Scanline draws each line in one go and
+is cheap; PixelFifo models the fetcher and FIFOs dot by dot so
+mode 3 length and mid-line register writes behave like hardware.
+*/
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RenderMode {
+    Scanline,
+    PixelFifo,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamSearch = 2,
+    PixelTransfer = 3,
+}
+
+/* One OAM entry selected for the current line. */
+#[derive(Copy, Clone)]
+struct Sprite {
+    y: i16,
+    x: i16,
+    tile: u8,
+    attributes: u8,
+}
+
+/* VRAM offset of the bank selected by a BG map or sprite attribute byte. */
+fn bank_offset(attributes: u8) -> usize {
+    if attributes & ATTR_VRAM_BANK != 0 {
+        VRAM_SIZE
+    } else {
+        0
+    }
+}
+
+/*
+Picture processing unit. Owns VRAM and OAM, steps through the
+OAM search / pixel transfer / HBlank / VBlank modes each line and
+draws a whole scanline into the framebuffer as mode 3 begins.
+
+On a CGB the output always goes through palette RAM. In CGB mode
+(a CGB game) the second VRAM bank holds BG map attributes; DMG
+games run in compatibility mode, where BGP/OBP0/OBP1 pick colours
+out of the palettes the boot ROM loaded.
+*/
+pub struct Ppu {
+    model: Model,
+    cgb_mode: bool,
+    vram: [u8; VRAM_SIZE * VRAM_BANKS],
+    vram_bank: usize,
+    oam: [u8; OAM_SIZE],
+    lcdc: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    bg_palettes: PaletteRam,
+    obj_palettes: PaletteRam,
+    mode: Mode,
+    dot: u32,
+    window_line: u8,
+    wy_triggered: bool,
+    line_sprites: Vec<Sprite>,
+    render_mode: RenderMode,
+    fifo: PixelFifo,
+    framebuffer: Vec<u32>,
+    frame_ready: bool,
+    hblank_started: bool,
+    stat_line: bool,
+    interrupts: u8,
+    /* The first line after the LCD is switched on skips mode 2. */
+    lcd_starting: bool,
+    /* The first frame after the LCD is switched on is not shown. */
+    blank_frame: bool,
+}
+
+impl Ppu {
+    pub fn new(model: Model, cgb_mode: bool) -> Ppu {
+        let mut bg_palettes = PaletteRam::new();
+        let mut obj_palettes = PaletteRam::new();
+        if model == Model::Cgb && !cgb_mode {
+            bg_palettes.set_colors(0, COMPATIBILITY_BG_COLORS);
+            obj_palettes.set_colors(0, COMPATIBILITY_OBJ_COLORS);
+            obj_palettes.set_colors(1, COMPATIBILITY_OBJ_COLORS);
+        }
+
+        Ppu {
+            model,
+            cgb_mode,
+            vram: [0; VRAM_SIZE * VRAM_BANKS],
+            vram_bank: 0,
+            oam: [0; OAM_SIZE],
+            lcdc: 0x91,
+            stat: 0x00,
+            scy: 0x00,
+            scx: 0x00,
+            ly: 0x00,
+            lyc: 0x00,
+            bgp: 0xFC,
+            obp0: 0xFF,
+            obp1: 0xFF,
+            wy: 0x00,
+            wx: 0x00,
+            bg_palettes,
+            obj_palettes,
+            mode: Mode::OamSearch,
+            dot: 0,
+            window_line: 0,
+            wy_triggered: false,
+            line_sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
+            render_mode: RenderMode::Scanline,
+            fifo: PixelFifo::new(),
+            framebuffer: vec![DMG_SHADES[0]; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_ready: false,
+            hblank_started: false,
+            stat_line: false,
+            interrupts: 0,
+            lcd_starting: false,
+            blank_frame: false,
+        }
+    }
+
+    /*
+    Row major pixels, one 0xRRGGBB value each. Lines are drawn in
+    place, so the picture is complete while the PPU is in VBlank.
+    */
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.framebuffer
+    }
+
+    /*
+    Tile numbers of the background map LCDC selects, 32 rows of 32.
+    Always from bank 0 and regardless of the mode 3 lock, for tools
+    that want to know what is on screen without disturbing anything.
+    */
+    pub fn background_map(&self) -> &[u8] {
+        let base = self.map_base(LCDC_BG_MAP);
+        &self.vram[base..base + 0x400]
+    }
+
+    /* True once per frame, when the PPU enters VBlank. Reading clears it. */
+    pub fn take_frame_ready(&mut self) -> bool {
+        let ready = self.frame_ready;
+        self.frame_ready = false;
+        ready
+    }
+
+    /* True once per visible line, when mode 3 ends. Reading clears it. HBlank DMA waits on this. */
+    pub fn take_hblank_started(&mut self) -> bool {
+        let started = self.hblank_started;
+        self.hblank_started = false;
+        started
+    }
+
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = render_mode;
+    }
+
+    /* The mode as reported in STAT: HBlank while the LCD is off or still starting up. */
+    pub fn mode(&self) -> Mode {
+        if !self.is_lcd_enabled() || self.lcd_starting {
+            Mode::HBlank
+        } else {
+            self.mode
+        }
+    }
+
+    fn is_lcd_enabled(&self) -> bool {
+        self.lcdc & LCDC_LCD_ENABLE != 0
+    }
+
+    /*
+    LY as the CPU sees it. On line 153 LY already reads 0 a few
+    dots in, and the LYC comparison uses that value too.
+    */
+    fn visible_ly(&self) -> u8 {
+        if self.ly == LINES_PER_FRAME - 1 && self.dot >= 4 {
+            0
+        } else {
+            self.ly
+        }
+    }
+
+    fn coincidence(&self) -> bool {
+        self.visible_ly() == self.lyc
+    }
+
+    /*
+    All enabled STAT sources are OR'ed into one interrupt line and
+    only a rising edge requests the interrupt, so a source becoming
+    active while another already holds the line high is swallowed.
+    */
+    fn stat_line_for(&self, stat: u8) -> bool {
+        if !self.is_lcd_enabled() {
+            return false;
+        }
+
+        let mode_source = match self.mode() {
+            Mode::HBlank => stat & STAT_HBLANK_SOURCE != 0,
+            /* The OAM source also fires as VBlank starts on line 144. */
+            Mode::VBlank => {
+                stat & STAT_VBLANK_SOURCE != 0
+                    || (self.ly == SCREEN_HEIGHT as u8 && self.dot == 0 && stat & STAT_OAM_SOURCE != 0)
+            }
+            Mode::OamSearch => stat & STAT_OAM_SOURCE != 0,
+            Mode::PixelTransfer => false,
+        };
+        mode_source || (stat & STAT_LYC_SOURCE != 0 && self.coincidence())
+    }
+
+    fn update_stat_line(&mut self) {
+        let line = self.stat_line_for(self.stat);
+        if line && !self.stat_line {
+            self.interrupts |= 1 << STAT_INTERRUPT_BIT;
+        }
+        self.stat_line = line;
+    }
+
+    /* Advance by a number of dots (T-cycles). Returns IF bits to request. */
+    pub fn tick(&mut self, dots: u32) -> u8 {
+        if !self.is_lcd_enabled() {
+            return std::mem::take(&mut self.interrupts);
+        }
+
+        for _ in 0..dots {
+            self.dot += 1;
+
+            match self.mode {
+                Mode::OamSearch if self.dot == OAM_SEARCH_DOTS => {
+                    self.mode = Mode::PixelTransfer;
+                    self.lcd_starting = false;
+                    match self.render_mode {
+                        RenderMode::Scanline => self.render_scanline(),
+                        RenderMode::PixelFifo => self.start_fifo_line(),
+                    }
+                }
+                Mode::PixelTransfer => {
+                    let finished = match self.render_mode {
+                        RenderMode::Scanline => self.dot == OAM_SEARCH_DOTS + PIXEL_TRANSFER_DOTS,
+                        RenderMode::PixelFifo => self.fifo_dot(),
+                    };
+                    if finished {
+                        self.mode = Mode::HBlank;
+                        self.hblank_started = true;
+                        if self.render_mode == RenderMode::PixelFifo {
+                            self.finish_fifo_line();
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            if self.dot == DOTS_PER_LINE {
+                self.dot = 0;
+                self.ly += 1;
+
+                if self.ly == SCREEN_HEIGHT as u8 {
+                    self.mode = Mode::VBlank;
+                    if self.blank_frame {
+                        self.framebuffer.fill(DMG_SHADES[0]);
+                        self.blank_frame = false;
+                    }
+                    self.frame_ready = true;
+                    self.interrupts |= 1 << VBLANK_INTERRUPT_BIT;
+                } else if self.ly == LINES_PER_FRAME {
+                    self.ly = 0;
+                    self.window_line = 0;
+                    self.wy_triggered = false;
+                }
+
+                if self.ly < SCREEN_HEIGHT as u8 {
+                    self.start_line();
+                }
+            }
+
+            self.update_stat_line();
+        }
+
+        std::mem::take(&mut self.interrupts)
+    }
+
+    fn sprite_height(&self) -> i16 {
+        if self.lcdc & LCDC_OBJ_TALL != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    /* Enter mode 2 for a visible line. The window becomes eligible once LY has matched WY this frame. */
+    fn start_line(&mut self) {
+        self.mode = Mode::OamSearch;
+        if self.ly == self.wy {
+            self.wy_triggered = true;
+        }
+        self.search_oam();
+    }
+
+    /* Pick the first ten sprites in OAM order that overlap this line. */
+    fn search_oam(&mut self) {
+        self.line_sprites.clear();
+        let height = self.sprite_height();
+        let line = self.ly as i16;
+
+        for entry in self.oam.chunks(4) {
+            let y = entry[0] as i16 - 16;
+            if line >= y && line < y + height {
+                self.line_sprites.push(Sprite {
+                    y,
+                    x: entry[1] as i16 - 8,
+                    tile: entry[2],
+                    attributes: entry[3],
+                });
+                if self.line_sprites.len() == MAX_SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+    }
+
+    /*
+    Colour index (0-3) and CGB attributes of background pixel (x, y),
+    fetched through the LCDC addressing mode. Outside CGB mode the
+    attributes are always 0.
+    */
+    fn background_tile_pixel(&self, map_base: usize, x: u8, y: u8) -> (u8, u8) {
+        let map_index = map_base + (y as usize / 8) * 32 + x as usize / 8;
+        let attributes = if self.cgb_mode { self.vram[VRAM_SIZE + map_index] } else { 0 };
+        let tile_address = self.tile_data_address(self.vram[map_index]) + bank_offset(attributes);
+
+        let column = if attributes & BG_ATTR_X_FLIP != 0 { 7 - x % 8 } else { x % 8 };
+        let row = if attributes & BG_ATTR_Y_FLIP != 0 { 7 - y % 8 } else { y % 8 };
+        (self.tile_pixel(tile_address, column, row), attributes)
+    }
+
+    /* In CGB mode LCDC bit 0 no longer hides the background; it only takes away its priority over sprites. */
+    fn background_enabled(&self) -> bool {
+        self.cgb_mode || self.lcdc & LCDC_BG_ENABLE != 0
+    }
+
+    /* VRAM offset of a background/window tile: unsigned from 0x8000 or signed around 0x9000. */
+    fn tile_data_address(&self, tile_number: u8) -> usize {
+        if self.lcdc & LCDC_TILE_DATA != 0 {
+            tile_number as usize * 16
+        } else {
+            (0x1000 + (tile_number as i8 as i32) * 16) as usize
+        }
+    }
+
+    fn tile_pixel(&self, tile_address: usize, x: u8, y: u8) -> u8 {
+        let low = self.vram[tile_address + y as usize * 2];
+        let high = self.vram[tile_address + y as usize * 2 + 1];
+        let bit = 7 - x;
+        (((high >> bit) & 0x1) << 1) | ((low >> bit) & 0x1)
+    }
+
+    fn map_base(&self, select_bit: u8) -> usize {
+        if self.lcdc & select_bit != 0 {
+            0x1C00
+        } else {
+            0x1800
+        }
+    }
+
+    fn render_scanline(&mut self) {
+        let line = self.ly;
+        let mut background = [(0u8, 0u8); SCREEN_WIDTH];
+
+        let window_x = self.wx as i16 - 7;
+        let window_visible = self.lcdc & LCDC_WINDOW_ENABLE != 0
+            && self.wy_triggered
+            && self.wx <= 166;
+
+        if self.background_enabled() {
+            let background_map = self.map_base(LCDC_BG_MAP);
+            let window_map = self.map_base(LCDC_WINDOW_MAP);
+
+            for (x, pixel) in background.iter_mut().enumerate() {
+                *pixel = if window_visible && x as i16 >= window_x {
+                    let window_column = (x as i16 - window_x) as u8;
+                    self.background_tile_pixel(window_map, window_column, self.window_line)
+                } else {
+                    let column = self.scx.wrapping_add(x as u8);
+                    let row = self.scy.wrapping_add(line);
+                    self.background_tile_pixel(background_map, column, row)
+                };
+            }
+
+            if window_visible {
+                self.window_line += 1;
+            }
+        }
+
+        let row_start = line as usize * SCREEN_WIDTH;
+        for (x, &(color, attributes)) in background.iter().enumerate() {
+            self.framebuffer[row_start + x] = self.background_color(attributes, color);
+        }
+
+        if self.lcdc & LCDC_OBJ_ENABLE != 0 {
+            self.render_sprites(&background);
+        }
+    }
+
+    /*
+    DMG sprite priority: the sprite with the smaller X wins, and
+    on equal X the one earlier in OAM. In CGB mode only the OAM
+    position counts. The winning opaque pixel is decided before BG
+    priority, so a sprite hidden behind the background still masks
+    lower priority sprites under it.
+    */
+    fn render_sprites(&mut self, background: &[(u8, u8); SCREEN_WIDTH]) {
+        let mut sprites = self.line_sprites.clone();
+        if !self.cgb_mode {
+            sprites.sort_by_key(|sprite| sprite.x);
+        }
+
+        let line = self.ly as i16;
+        let height = self.sprite_height();
+        let row_start = line as usize * SCREEN_WIDTH;
+        let mut claimed = [false; SCREEN_WIDTH];
+
+        for sprite in sprites {
+            let mut row = line - sprite.y;
+            if sprite.attributes & OBJ_ATTR_Y_FLIP != 0 {
+                row = height - 1 - row;
+            }
+            let tile = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+            let mut tile_address = tile as usize * 16 + (row as usize / 8) * 16;
+            if self.cgb_mode {
+                tile_address += bank_offset(sprite.attributes);
+            }
+
+            for column in 0..8 {
+                let x = sprite.x + column;
+                if !(0..SCREEN_WIDTH as i16).contains(&x) {
+                    continue;
+                }
+
+                let tile_column = if sprite.attributes & OBJ_ATTR_X_FLIP != 0 { 7 - column } else { column };
+                let color = self.tile_pixel(tile_address, tile_column as u8, (row % 8) as u8);
+                if color == 0 || claimed[x as usize] {
+                    continue;
+                }
+                claimed[x as usize] = true;
+                let (background_color, background_attributes) = background[x as usize];
+                if self.sprite_hidden(sprite.attributes, background_color, background_attributes) {
+                    continue;
+                }
+
+                self.framebuffer[row_start + x as usize] = self.sprite_color(sprite.attributes, color);
+            }
+        }
+    }
+
+    /*
+    Whether an opaque sprite pixel loses to the background under it.
+    Background colour 0 never wins. In CGB mode clearing LCDC bit 0
+    puts every sprite on top, otherwise either the sprite's or the
+    BG map entry's priority bit hands the pixel to the background.
+    */
+    fn sprite_hidden(&self, sprite_attributes: u8, background_color: u8, background_attributes: u8) -> bool {
+        if background_color == 0 {
+            return false;
+        }
+        if self.cgb_mode {
+            self.lcdc & LCDC_BG_ENABLE != 0
+                && (sprite_attributes & OBJ_ATTR_BEHIND_BG != 0 || background_attributes & BG_ATTR_PRIORITY != 0)
+        } else {
+            sprite_attributes & OBJ_ATTR_BEHIND_BG != 0
+        }
+    }
+
+    fn palette_shade(palette: u8, color: u8) -> u8 {
+        (palette >> (color * 2)) & 0x03
+    }
+
+    /* Final 0xRRGGBB of a background pixel. */
+    fn background_color(&self, attributes: u8, color: u8) -> u32 {
+        if self.cgb_mode {
+            self.bg_palettes.color(attributes & ATTR_CGB_PALETTE, color)
+        } else if self.model == Model::Cgb {
+            self.bg_palettes.color(0, Ppu::palette_shade(self.bgp, color))
+        } else {
+            DMG_SHADES[Ppu::palette_shade(self.bgp, color) as usize]
+        }
+    }
+
+    /* Final 0xRRGGBB of an opaque sprite pixel. */
+    fn sprite_color(&self, attributes: u8, color: u8) -> u32 {
+        if self.cgb_mode {
+            return self.obj_palettes.color(attributes & ATTR_CGB_PALETTE, color);
+        }
+
+        let (palette, index) = if attributes & OBJ_ATTR_PALETTE != 0 { (self.obp1, 1) } else { (self.obp0, 0) };
+        let shade = Ppu::palette_shade(palette, color);
+        if self.model == Model::Cgb {
+            self.obj_palettes.color(index, shade)
+        } else {
+            DMG_SHADES[shade as usize]
+        }
+    }
+
+    /* The PPU holds VRAM and palette RAM during mode 3 and OAM during modes 2 and 3. */
+    fn is_vram_locked(&self) -> bool {
+        self.mode() == Mode::PixelTransfer
+    }
+
+    fn is_oam_locked(&self) -> bool {
+        matches!(self.mode(), Mode::OamSearch | Mode::PixelTransfer)
+    }
+
+    pub fn read_vram(&self, address: u16) -> u8 {
+        if self.is_vram_locked() {
+            return 0xFF;
+        }
+        self.vram[self.vram_bank * VRAM_SIZE + address as usize - 0x8000]
+    }
+
+    pub fn write_vram(&mut self, address: u16, value: u8) {
+        if !self.is_vram_locked() {
+            self.vram[self.vram_bank * VRAM_SIZE + address as usize - 0x8000] = value;
+        }
+    }
+
+    pub fn read_oam(&self, address: u16) -> u8 {
+        if self.is_oam_locked() {
+            return 0xFF;
+        }
+        self.oam[address as usize - 0xFE00]
+    }
+
+    pub fn write_oam(&mut self, address: u16, value: u8) {
+        if !self.is_oam_locked() {
+            self.oam[address as usize - 0xFE00] = value;
+        }
+    }
+
+    /* OAM DMA writes by index and is never blocked. */
+    pub fn write_oam_dma(&mut self, index: usize, value: u8) {
+        self.oam[index] = value;
+    }
+
+    /*
+    Turning the LCD off stops the PPU at LY 0 in mode 0 and blanks
+    the screen. Turning it back on restarts line 0 without an OAM
+    search, and the frame drawn after that is not displayed.
+    */
+    fn write_lcdc(&mut self, value: u8) {
+        let was_enabled = self.is_lcd_enabled();
+        self.lcdc = value;
+
+        if was_enabled && !self.is_lcd_enabled() {
+            self.ly = 0;
+            self.dot = 0;
+            self.mode = Mode::HBlank;
+            self.window_line = 0;
+            self.wy_triggered = false;
+            self.stat_line = false;
+            self.framebuffer.fill(DMG_SHADES[0]);
+        } else if !was_enabled && self.is_lcd_enabled() {
+            self.start_line();
+            self.line_sprites.clear();
+            self.lcd_starting = true;
+            self.blank_frame = true;
+            self.update_stat_line();
+        }
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            LCDC_ADDRESS => self.lcdc,
+            STAT_ADDRESS => {
+                let coincidence = if self.coincidence() { STAT_COINCIDENCE } else { 0 };
+                0x80 | self.stat | coincidence | self.mode() as u8
+            }
+            SCY_ADDRESS => self.scy,
+            SCX_ADDRESS => self.scx,
+            LY_ADDRESS => self.visible_ly(),
+            LYC_ADDRESS => self.lyc,
+            BGP_ADDRESS => self.bgp,
+            OBP0_ADDRESS => self.obp0,
+            OBP1_ADDRESS => self.obp1,
+            WY_ADDRESS => self.wy,
+            WX_ADDRESS => self.wx,
+            VBK_ADDRESS if self.cgb_mode => 0xFE | self.vram_bank as u8,
+            BCPS_ADDRESS if self.cgb_mode => self.bg_palettes.read_spec(),
+            BCPD_ADDRESS if self.cgb_mode => self.bg_palettes.read_data(self.is_vram_locked()),
+            OCPS_ADDRESS if self.cgb_mode => self.obj_palettes.read_spec(),
+            OCPD_ADDRESS if self.cgb_mode => self.obj_palettes.read_data(self.is_vram_locked()),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            LCDC_ADDRESS => self.write_lcdc(value),
+            STAT_ADDRESS => {
+                /*
+                DMG quirk: for one cycle the write acts as if the HBlank,
+                VBlank and LYC sources were all enabled. The CGB fixed it.
+                */
+                let all_sources = STAT_HBLANK_SOURCE | STAT_VBLANK_SOURCE | STAT_LYC_SOURCE;
+                if self.model == Model::Dmg && self.stat_line_for(all_sources) && !self.stat_line {
+                    self.interrupts |= 1 << STAT_INTERRUPT_BIT;
+                    self.stat_line = true;
+                }
+                self.stat = value & STAT_SOURCES;
+                self.update_stat_line();
+            }
+            SCY_ADDRESS => self.scy = value,
+            SCX_ADDRESS => self.scx = value,
+            LY_ADDRESS => {}
+            LYC_ADDRESS => {
+                self.lyc = value;
+                self.update_stat_line();
+            }
+            BGP_ADDRESS => self.bgp = value,
+            OBP0_ADDRESS => self.obp0 = value,
+            OBP1_ADDRESS => self.obp1 = value,
+            WY_ADDRESS => self.wy = value,
+            WX_ADDRESS => self.wx = value,
+            VBK_ADDRESS if self.cgb_mode => self.vram_bank = (value & 0x01) as usize,
+            BCPS_ADDRESS if self.cgb_mode => self.bg_palettes.write_spec(value),
+            BCPD_ADDRESS if self.cgb_mode => {
+                let locked = self.is_vram_locked();
+                self.bg_palettes.write_data(value, locked);
+            }
+            OCPS_ADDRESS if self.cgb_mode => self.obj_palettes.write_spec(value),
+            OCPD_ADDRESS if self.cgb_mode => {
+                let locked = self.is_vram_locked();
+                self.obj_palettes.write_data(value, locked);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Mode, Ppu, ATTR_VRAM_BANK, BGP_ADDRESS, BG_ATTR_PRIORITY, BG_ATTR_X_FLIP, BG_ATTR_Y_FLIP, DMG_SHADES,
+        DOTS_PER_LINE, LCDC_ADDRESS, LCDC_BG_ENABLE, LCDC_LCD_ENABLE, LCDC_OBJ_ENABLE, LCDC_TILE_DATA,
+        LCDC_WINDOW_ENABLE, LCDC_WINDOW_MAP, LINES_PER_FRAME, LYC_ADDRESS, LY_ADDRESS, OAM_SEARCH_DOTS,
+        OBJ_ATTR_BEHIND_BG, OBJ_ATTR_PALETTE, OBP0_ADDRESS, OBP1_ADDRESS, PIXEL_TRANSFER_DOTS, SCREEN_HEIGHT,
+        SCREEN_WIDTH, SCX_ADDRESS, STAT_ADDRESS, STAT_COINCIDENCE, STAT_HBLANK_SOURCE, STAT_INTERRUPT_BIT,
+        STAT_LYC_SOURCE, VBK_ADDRESS, WX_ADDRESS, WY_ADDRESS,
+    };
+    use crate::model::Model;
+
+    const WHITE: u32 = DMG_SHADES[0];
+    const DARK: u32 = DMG_SHADES[2];
+    const BLACK: u32 = DMG_SHADES[3];
+
+    const LCDC_DEFAULT: u8 = LCDC_LCD_ENABLE | LCDC_TILE_DATA | LCDC_OBJ_ENABLE | LCDC_BG_ENABLE;
+
+    /* A DMG PPU with identity BG/OBP0 palettes and OBP1 mapping colour 3 to the dark grey. */
+    fn dmg_ppu() -> Ppu {
+        let mut ppu = Ppu::new(Model::Dmg, false);
+        ppu.write_register(LCDC_ADDRESS, LCDC_DEFAULT);
+        ppu.write_register(BGP_ADDRESS, 0xE4);
+        ppu.write_register(OBP0_ADDRESS, 0xE4);
+        ppu.write_register(OBP1_ADDRESS, 0x90);
+        ppu
+    }
+
+    /* Fill rows of a tile in the unsigned 0x8000 area with a single colour. */
+    fn fill_tile(ppu: &mut Ppu, tile: u8, rows: std::ops::Range<u16>, color: u8) {
+        for row in rows {
+            let address = 0x8000 + tile as u16 * 16 + row * 2;
+            ppu.write_vram(address, if color & 1 != 0 { 0xFF } else { 0x00 });
+            ppu.write_vram(address + 1, if color & 2 != 0 { 0xFF } else { 0x00 });
+        }
+    }
+
+    fn place_sprite(ppu: &mut Ppu, index: usize, line: u8, x: i16, tile: u8, attributes: u8) {
+        let entry = [line + 16, (x + 8) as u8, tile, attributes];
+        for (offset, &value) in entry.iter().enumerate() {
+            ppu.write_oam_dma(index * 4 + offset, value);
+        }
+    }
+
+    /* Run the OAM search and scanline renderer for one line and return its pixels. */
+    fn render_line(ppu: &mut Ppu, line: u8) -> Vec<u32> {
+        ppu.ly = line;
+        ppu.start_line();
+        ppu.render_scanline();
+        let start = line as usize * SCREEN_WIDTH;
+        ppu.framebuffer()[start..start + SCREEN_WIDTH].to_vec()
+    }
+
+    #[test]
+    fn background_follows_scx() {
+        let mut ppu = dmg_ppu();
+        fill_tile(&mut ppu, 1, 0..8, 3);
+        ppu.write_vram(0x9801, 1);
+        ppu.write_register(SCX_ADDRESS, 4);
+
+        let pixels = render_line(&mut ppu, 0);
+        assert_eq!(pixels[3], WHITE);
+        assert_eq!(&pixels[4..12], &[BLACK; 8]);
+        assert_eq!(pixels[12], WHITE);
+    }
+
+    #[test]
+    fn behind_bg_sprites_only_show_over_colour_zero() {
+        let mut ppu = dmg_ppu();
+        fill_tile(&mut ppu, 1, 0..8, 1);
+        fill_tile(&mut ppu, 2, 0..8, 3);
+        ppu.write_vram(0x9800, 1);
+        place_sprite(&mut ppu, 0, 0, 4, 2, OBJ_ATTR_BEHIND_BG);
+
+        let pixels = render_line(&mut ppu, 0);
+        assert_eq!(pixels[4], DMG_SHADES[1], "BG colour 1 covers the sprite");
+        assert_eq!(pixels[8], BLACK, "BG colour 0 does not");
+    }
+
+    #[test]
+    fn smaller_x_wins_on_the_dmg() {
+        let mut ppu = dmg_ppu();
+        fill_tile(&mut ppu, 2, 0..8, 3);
+        place_sprite(&mut ppu, 0, 0, 4, 2, OBJ_ATTR_PALETTE);
+        place_sprite(&mut ppu, 1, 0, 0, 2, 0);
+        /* Same X as the first sprite but later in OAM. */
+        place_sprite(&mut ppu, 2, 0, 4, 2, 0);
+
+        let pixels = render_line(&mut ppu, 0);
+        assert_eq!(&pixels[0..8], &[BLACK; 8]);
+        assert_eq!(&pixels[8..12], &[DARK; 4]);
+    }
+
+    #[test]
+    fn only_ten_sprites_per_line() {
+        let mut ppu = dmg_ppu();
+        fill_tile(&mut ppu, 2, 0..8, 3);
+        /* Off this line, so it takes no slot. */
+        place_sprite(&mut ppu, 0, 20, 0, 2, 0);
+        for index in 1..=11 {
+            place_sprite(&mut ppu, index, 0, (index as i16 - 1) * 8, 2, 0);
+        }
+
+        let pixels = render_line(&mut ppu, 0);
+        assert_eq!(pixels[72], BLACK);
+        assert_eq!(pixels[80], WHITE, "the eleventh sprite is dropped");
+    }
+
+    #[test]
+    fn window_line_counter_skips_lines_without_the_window() {
+        let mut ppu = dmg_ppu();
+        /* Tile 0 has only its second row set; both maps are all tile 0. */
+        fill_tile(&mut ppu, 0, 1..2, 3);
+        ppu.write_register(LCDC_ADDRESS, LCDC_DEFAULT | LCDC_WINDOW_ENABLE | LCDC_WINDOW_MAP);
+        ppu.write_register(WY_ADDRESS, 0);
+        ppu.write_register(WX_ADDRESS, 7);
+
+        assert_eq!(render_line(&mut ppu, 0)[0], WHITE);
+
+        ppu.write_register(LCDC_ADDRESS, LCDC_DEFAULT);
+        assert_eq!(render_line(&mut ppu, 1)[0], BLACK, "background row 1");
+        assert_eq!(render_line(&mut ppu, 2)[0], WHITE, "background row 2");
+
+        /* The window picks up at its own row 1, not at LY. */
+        ppu.write_register(LCDC_ADDRESS, LCDC_DEFAULT | LCDC_WINDOW_ENABLE | LCDC_WINDOW_MAP);
+        assert_eq!(render_line(&mut ppu, 3)[0], BLACK);
+        assert_eq!(ppu.window_line, 2);
+    }
+
+    /* Tick dot by dot and count the STAT interrupts requested. */
+    fn stat_interrupts(ppu: &mut Ppu, dots: u32) -> u32 {
+        (0..dots).filter(|_| ppu.tick(1) & (1 << STAT_INTERRUPT_BIT) != 0).count() as u32
+    }
+
+    #[test]
+    fn a_source_rising_while_another_holds_the_line_is_swallowed() {
+        let mut ppu = dmg_ppu();
+        ppu.write_register(LYC_ADDRESS, 0);
+        ppu.write_register(STAT_ADDRESS, STAT_LYC_SOURCE | STAT_HBLANK_SOURCE);
+        ppu.tick(0);
+
+        /* LY=LYC holds the line high all through line 0, so its HBlank raises nothing. */
+        assert_eq!(stat_interrupts(&mut ppu, DOTS_PER_LINE - 1), 0);
+
+        /* On line 1 the line drops during mode 2 and HBlank raises it again. */
+        assert_eq!(stat_interrupts(&mut ppu, DOTS_PER_LINE), 1);
+    }
+
+    #[test]
+    fn lyc_match_sets_the_coincidence_flag_and_interrupts_once() {
+        let mut ppu = dmg_ppu();
+        ppu.write_register(LYC_ADDRESS, 5);
+        ppu.write_register(STAT_ADDRESS, STAT_LYC_SOURCE);
+        ppu.tick(0);
+
+        assert_eq!(stat_interrupts(&mut ppu, 5 * DOTS_PER_LINE - 1), 0);
+        assert_eq!(ppu.read_register(STAT_ADDRESS) & STAT_COINCIDENCE, 0);
+
+        assert_eq!(stat_interrupts(&mut ppu, 1), 1);
+        assert_eq!(ppu.read_register(LY_ADDRESS), 5);
+        assert_ne!(ppu.read_register(STAT_ADDRESS) & STAT_COINCIDENCE, 0);
+
+        assert_eq!(stat_interrupts(&mut ppu, DOTS_PER_LINE - 1), 0);
+        assert_eq!(stat_interrupts(&mut ppu, 1), 0);
+        assert_eq!(ppu.read_register(STAT_ADDRESS) & STAT_COINCIDENCE, 0);
+    }
+
+    #[test]
+    fn disabling_the_lcd_resets_ly_and_the_mode() {
+        let mut ppu = dmg_ppu();
+        ppu.tick(10 * DOTS_PER_LINE + 100);
+        assert_eq!(ppu.read_register(LY_ADDRESS), 10);
+
+        ppu.write_register(LCDC_ADDRESS, LCDC_DEFAULT & !LCDC_LCD_ENABLE);
+        ppu.tick(DOTS_PER_LINE);
+        assert_eq!(ppu.read_register(LY_ADDRESS), 0);
+        assert_eq!(ppu.read_register(STAT_ADDRESS) & 0x03, 0);
+        assert!(ppu.framebuffer().iter().all(|&pixel| pixel == WHITE));
+    }
+
+    #[test]
+    fn first_frame_after_enabling_the_lcd_is_blank() {
+        let mut ppu = dmg_ppu();
+        ppu.write_register(LCDC_ADDRESS, LCDC_DEFAULT & !LCDC_LCD_ENABLE);
+        fill_tile(&mut ppu, 0, 0..8, 3);
+        ppu.write_register(LCDC_ADDRESS, LCDC_DEFAULT);
+
+        let frame = LINES_PER_FRAME as u32 * DOTS_PER_LINE;
+        ppu.tick(SCREEN_HEIGHT as u32 * DOTS_PER_LINE);
+        assert!(ppu.take_frame_ready());
+        assert!(ppu.framebuffer().iter().all(|&pixel| pixel == WHITE));
+
+        ppu.tick(frame);
+        assert!(ppu.take_frame_ready());
+        assert!(ppu.framebuffer().iter().all(|&pixel| pixel == BLACK));
+    }
+
+    #[test]
+    fn vram_is_locked_during_mode_3() {
+        let mut ppu = dmg_ppu();
+        ppu.write_vram(0x8000, 0x12);
+
+        ppu.tick(OAM_SEARCH_DOTS);
+        assert_eq!(ppu.mode(), Mode::PixelTransfer);
+        assert_eq!(ppu.read_vram(0x8000), 0xFF);
+        assert_eq!(ppu.read_oam(0xFE00), 0xFF);
+        ppu.write_vram(0x8000, 0x34);
+
+        ppu.tick(PIXEL_TRANSFER_DOTS);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        assert_eq!(ppu.read_vram(0x8000), 0x12, "the mode 3 write was dropped");
+    }
+
+    const RED: u32 = 0xFF0000;
+    const BLUE: u32 = 0x0000FF;
+
+    /* A PPU running a CGB game, BG palette 0 going white, red, green, black and OBJ palette 0 ending in blue. */
+    fn cgb_ppu() -> Ppu {
+        let mut ppu = Ppu::new(Model::Cgb, true);
+        ppu.write_register(LCDC_ADDRESS, LCDC_DEFAULT);
+        ppu.bg_palettes.set_colors(0, [WHITE, RED, 0x00FF00, BLACK]);
+        ppu.obj_palettes.set_colors(0, [WHITE, WHITE, WHITE, BLUE]);
+        ppu
+    }
+
+    fn set_map_attributes(ppu: &mut Ppu, address: u16, attributes: u8) {
+        ppu.write_register(VBK_ADDRESS, 1);
+        ppu.write_vram(address, attributes);
+        ppu.write_register(VBK_ADDRESS, 0);
+    }
+
+    #[test]
+    fn vbk_selects_the_vram_bank() {
+        let mut ppu = cgb_ppu();
+        ppu.write_vram(0x8000, 0x11);
+        ppu.write_register(VBK_ADDRESS, 0xFF);
+        assert_eq!(ppu.read_register(VBK_ADDRESS), 0xFF);
+        assert_eq!(ppu.read_vram(0x8000), 0x00);
+        ppu.write_vram(0x8000, 0x22);
+
+        ppu.write_register(VBK_ADDRESS, 0x00);
+        assert_eq!(ppu.read_register(VBK_ADDRESS), 0xFE);
+        assert_eq!(ppu.read_vram(0x8000), 0x11);
+    }
+
+    #[test]
+    fn bg_attributes_flip_and_bank_the_tile() {
+        let mut ppu = cgb_ppu();
+        /* Only the top left pixel of tile 1 in bank 1 is set. */
+        ppu.write_register(VBK_ADDRESS, 1);
+        ppu.write_vram(0x8010, 0x80);
+        ppu.write_vram(0x8011, 0x80);
+        ppu.write_register(VBK_ADDRESS, 0);
+        ppu.write_vram(0x9800, 1);
+        ppu.write_vram(0x9801, 1);
+        set_map_attributes(&mut ppu, 0x9800, ATTR_VRAM_BANK | BG_ATTR_X_FLIP);
+        set_map_attributes(&mut ppu, 0x9801, ATTR_VRAM_BANK | BG_ATTR_Y_FLIP);
+
+        let top = render_line(&mut ppu, 0);
+        assert_eq!(top[0], WHITE);
+        assert_eq!(top[7], BLACK);
+        assert_eq!(top[8], WHITE);
+
+        let bottom = render_line(&mut ppu, 7);
+        assert_eq!(bottom[7], WHITE);
+        assert_eq!(bottom[8], BLACK);
+    }
+
+    #[test]
+    fn bg_priority_attribute_covers_sprites_unless_lcdc_bit_0_is_clear() {
+        let mut ppu = cgb_ppu();
+        ppu.write_vram(0x8000, 0xFF);
+        fill_tile(&mut ppu, 2, 0..8, 3);
+        set_map_attributes(&mut ppu, 0x9800, BG_ATTR_PRIORITY);
+        place_sprite(&mut ppu, 0, 0, 4, 2, 0);
+
+        let pixels = render_line(&mut ppu, 0);
+        assert_eq!(&pixels[4..8], &[RED; 4]);
+        assert_eq!(&pixels[8..12], &[BLUE; 4]);
+
+        ppu.write_register(LCDC_ADDRESS, LCDC_DEFAULT & !LCDC_BG_ENABLE);
+        let pixels = render_line(&mut ppu, 0);
+        assert_eq!(&pixels[0..4], &[RED; 4], "the background is still drawn");
+        assert_eq!(&pixels[4..12], &[BLUE; 8]);
+    }
+}
@@ -0,0 +1,421 @@
+use std::collections::VecDeque;
+
+use super::{
+    bank_offset, Ppu, Sprite, BG_ATTR_X_FLIP, BG_ATTR_Y_FLIP, LCDC_BG_MAP, LCDC_OBJ_ENABLE, LCDC_WINDOW_ENABLE,
+    LCDC_WINDOW_MAP, OBJ_ATTR_X_FLIP, OBJ_ATTR_Y_FLIP, SCREEN_WIDTH, VRAM_SIZE,
+};
+
+/* Dots spent on the throwaway first tile fetch at the start of every line. */
+const STARTUP_DOTS: u8 = 6;
+
+/* Dots the sprite fetcher needs once the background fetcher is out of the way. */
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+#[derive(Copy, Clone, PartialEq)]
+enum FetcherStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+#[derive(Copy, Clone)]
+struct BackgroundPixel {
+    color: u8,
+    attributes: u8,
+}
+
+#[derive(Copy, Clone)]
+struct SpritePixel {
+    color: u8,
+    attributes: u8,
+    /* Position in `line_sprites`, which is OAM order; decides overlaps in CGB mode. */
+    oam_index: usize,
+}
+
+/*
+State of the background fetcher and the two pixel FIFOs for the
+line being drawn. Every step is one dot, so the length of mode 3
+comes out of the work done rather than a fixed constant.
+*/
+pub struct PixelFifo {
+    background: VecDeque<BackgroundPixel>,
+    sprites: VecDeque<SpritePixel>,
+    step: FetcherStep,
+    step_dots: u8,
+    tile_x: u8,
+    tile_number: u8,
+    tile_attributes: u8,
+    data_low: u8,
+    data_high: u8,
+    fetching_window: bool,
+    startup_dots: u8,
+    discard: u8,
+    lcd_x: u8,
+    fetched_sprites: [bool; 10],
+    sprite_fetch_dots: Option<u8>,
+    window_drawn: bool,
+}
+
+impl PixelFifo {
+    pub fn new() -> PixelFifo {
+        PixelFifo {
+            background: VecDeque::with_capacity(16),
+            sprites: VecDeque::with_capacity(8),
+            step: FetcherStep::Tile,
+            step_dots: 0,
+            tile_x: 0,
+            tile_number: 0,
+            tile_attributes: 0,
+            data_low: 0,
+            data_high: 0,
+            fetching_window: false,
+            startup_dots: STARTUP_DOTS,
+            discard: 0,
+            lcd_x: 0,
+            fetched_sprites: [false; 10],
+            sprite_fetch_dots: None,
+            window_drawn: false,
+        }
+    }
+
+    fn reset_fetcher(&mut self) {
+        self.step = FetcherStep::Tile;
+        self.step_dots = 0;
+        self.tile_x = 0;
+    }
+}
+
+impl Ppu {
+    /* Prepare the FIFOs at the start of mode 3. SCX's low bits are discarded as pixels shift out. */
+    pub(super) fn start_fifo_line(&mut self) {
+        self.fifo = PixelFifo::new();
+        self.fifo.discard = self.scx % 8;
+    }
+
+    /* Run one dot of mode 3. Returns true once all 160 pixels of the line are out. */
+    pub(super) fn fifo_dot(&mut self) -> bool {
+        if self.fifo.startup_dots > 0 {
+            self.fifo.startup_dots -= 1;
+            return false;
+        }
+
+        if !self.fifo.fetching_window && self.window_starts_here() {
+            self.fifo.fetching_window = true;
+            self.fifo.window_drawn = true;
+            self.fifo.background.clear();
+            self.fifo.reset_fetcher();
+            /* With WX below 7 the window starts partly off the left edge. */
+            self.fifo.discard = 7u8.saturating_sub(self.wx);
+        }
+
+        if let Some(dots) = self.fifo.sprite_fetch_dots {
+            if dots > 1 {
+                self.fifo.sprite_fetch_dots = Some(dots - 1);
+            } else {
+                self.fifo.sprite_fetch_dots = None;
+                self.fetch_sprite();
+            }
+            return false;
+        }
+
+        let sprite_waiting = self.next_sprite_at_x().is_some();
+        if sprite_waiting && self.fifo.step == FetcherStep::Push && !self.fifo.background.is_empty() {
+            /* The background fetcher is idle with pixels ready, so the sprite fetch can start. */
+            self.fifo.sprite_fetch_dots = Some(SPRITE_FETCH_DOTS);
+            return false;
+        }
+
+        self.step_fetcher();
+
+        if sprite_waiting || self.fifo.background.is_empty() {
+            return false;
+        }
+
+        self.shift_pixel();
+        self.fifo.lcd_x as usize == SCREEN_WIDTH
+    }
+
+    /* Called when mode 3 ends so the window line counter only advances on lines that drew it. */
+    pub(super) fn finish_fifo_line(&mut self) {
+        if self.fifo.window_drawn {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
+    }
+
+    fn window_starts_here(&self) -> bool {
+        self.lcdc & LCDC_WINDOW_ENABLE != 0
+            && self.background_enabled()
+            && self.wy_triggered
+            && self.wx <= 166
+            && self.fifo.lcd_x as u16 + 7 >= self.wx as u16
+    }
+
+    /* Index into `line_sprites` of a sprite that starts at the current pixel and has not been fetched. */
+    fn next_sprite_at_x(&self) -> Option<usize> {
+        if self.lcdc & LCDC_OBJ_ENABLE == 0 {
+            return None;
+        }
+
+        let x = self.fifo.lcd_x as i16;
+        self.line_sprites.iter().enumerate().position(|(index, sprite)| {
+            !self.fifo.fetched_sprites[index] && (sprite.x == x || (x == 0 && sprite.x < 0 && sprite.x > -8))
+        })
+    }
+
+    fn step_fetcher(&mut self) {
+        if self.fifo.step == FetcherStep::Push {
+            if self.fifo.background.is_empty() {
+                self.push_tile();
+                self.fifo.step = FetcherStep::Tile;
+                self.fifo.step_dots = 0;
+            }
+            return;
+        }
+
+        /* Tile, DataLow and DataHigh each take two dots; the fetch happens on the second. */
+        self.fifo.step_dots += 1;
+        if self.fifo.step_dots < 2 {
+            return;
+        }
+        self.fifo.step_dots = 0;
+
+        match self.fifo.step {
+            FetcherStep::Tile => {
+                let map_address = self.fetcher_map_address();
+                self.fifo.tile_number = self.vram[map_address];
+                self.fifo.tile_attributes = if self.cgb_mode { self.vram[VRAM_SIZE + map_address] } else { 0 };
+                self.fifo.step = FetcherStep::DataLow;
+            }
+            FetcherStep::DataLow => {
+                self.fifo.data_low = self.vram[self.fetcher_tile_row_address()];
+                self.fifo.step = FetcherStep::DataHigh;
+            }
+            FetcherStep::DataHigh => {
+                self.fifo.data_high = self.vram[self.fetcher_tile_row_address() + 1];
+                self.fifo.step = FetcherStep::Push;
+            }
+            FetcherStep::Push => {}
+        }
+    }
+
+    fn fetcher_map_address(&self) -> usize {
+        if self.fifo.fetching_window {
+            let base = self.map_base(LCDC_WINDOW_MAP);
+            base + (self.window_line as usize / 8) * 32 + (self.fifo.tile_x as usize & 0x1F)
+        } else {
+            let base = self.map_base(LCDC_BG_MAP);
+            let row = self.ly.wrapping_add(self.scy) as usize / 8;
+            let column = (self.scx as usize / 8 + self.fifo.tile_x as usize) & 0x1F;
+            base + row * 32 + column
+        }
+    }
+
+    fn fetcher_tile_row_address(&self) -> usize {
+        let mut row = if self.fifo.fetching_window {
+            self.window_line % 8
+        } else {
+            self.ly.wrapping_add(self.scy) % 8
+        };
+        let attributes = self.fifo.tile_attributes;
+        if attributes & BG_ATTR_Y_FLIP != 0 {
+            row = 7 - row;
+        }
+        self.tile_data_address(self.fifo.tile_number) + bank_offset(attributes) + row as usize * 2
+    }
+
+    fn push_tile(&mut self) {
+        let attributes = self.fifo.tile_attributes;
+        for column in 0..8 {
+            let bit = if attributes & BG_ATTR_X_FLIP != 0 { column } else { 7 - column };
+            let color = (((self.fifo.data_high >> bit) & 0x1) << 1) | ((self.fifo.data_low >> bit) & 0x1);
+            self.fifo.background.push_back(BackgroundPixel { color, attributes });
+        }
+        self.fifo.tile_x = self.fifo.tile_x.wrapping_add(1);
+    }
+
+    /*
+    Fetch the pending sprite's row and merge it into the sprite FIFO.
+    On the DMG pixels already in the FIFO belong to higher priority
+    sprites and are only replaced where they are transparent; in CGB
+    mode a sprite earlier in OAM also takes over opaque pixels.
+    */
+    fn fetch_sprite(&mut self) {
+        let index = match self.next_sprite_at_x() {
+            Some(index) => index,
+            None => return,
+        };
+        self.fifo.fetched_sprites[index] = true;
+        let sprite: Sprite = self.line_sprites[index];
+
+        let height = self.sprite_height();
+        let mut row = self.ly as i16 - sprite.y;
+        if sprite.attributes & OBJ_ATTR_Y_FLIP != 0 {
+            row = height - 1 - row;
+        }
+        let tile = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+        let mut address = tile as usize * 16 + row as usize * 2;
+        if self.cgb_mode {
+            address += bank_offset(sprite.attributes);
+        }
+        let low = self.vram[address];
+        let high = self.vram[address + 1];
+
+        /* Sprites hanging off the left edge lose their first columns. */
+        let skip = if sprite.x < 0 { (-sprite.x) as usize } else { 0 };
+
+        while self.fifo.sprites.len() < 8 {
+            self.fifo.sprites.push_back(SpritePixel {
+                color: 0,
+                attributes: 0,
+                oam_index: usize::MAX,
+            });
+        }
+
+        for column in skip..8 {
+            let bit = if sprite.attributes & OBJ_ATTR_X_FLIP != 0 { column } else { 7 - column };
+            let color = (((high >> bit) & 0x1) << 1) | ((low >> bit) & 0x1);
+            let slot = &mut self.fifo.sprites[column - skip];
+            let replace = if self.cgb_mode {
+                color != 0 && (slot.color == 0 || index < slot.oam_index)
+            } else {
+                slot.color == 0
+            };
+            if replace {
+                *slot = SpritePixel {
+                    color,
+                    attributes: sprite.attributes,
+                    oam_index: index,
+                };
+            }
+        }
+    }
+
+    /* Pop one pixel from each FIFO, mix them and write the result to the framebuffer. */
+    fn shift_pixel(&mut self) {
+        let background = match self.fifo.background.pop_front() {
+            Some(pixel) => pixel,
+            None => return,
+        };
+
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+        let sprite = self.fifo.sprites.pop_front();
+
+        let background_color = if self.background_enabled() { background.color } else { 0 };
+        let mut output = self.background_color(background.attributes, background_color);
+
+        if let Some(sprite) = sprite {
+            let hidden = self.sprite_hidden(sprite.attributes, background_color, background.attributes);
+            if sprite.color != 0 && self.lcdc & LCDC_OBJ_ENABLE != 0 && !hidden {
+                output = self.sprite_color(sprite.attributes, sprite.color);
+            }
+        }
+
+        let index = self.ly as usize * SCREEN_WIDTH + self.fifo.lcd_x as usize;
+        self.framebuffer[index] = output;
+        self.fifo.lcd_x += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        Ppu, BGP_ADDRESS, LCDC_ADDRESS, LCDC_WINDOW_ENABLE, OBP0_ADDRESS, OBP1_ADDRESS, PIXEL_TRANSFER_DOTS, SCREEN_WIDTH, SCX_ADDRESS,
+        SCY_ADDRESS, WX_ADDRESS, WY_ADDRESS,
+    };
+    use super::SPRITE_FETCH_DOTS;
+    use crate::model::Model;
+
+    /* LCD, BG and sprites on, unsigned tile data. */
+    const LCDC_DEFAULT: u8 = 0x93;
+
+    fn dmg_ppu() -> Ppu {
+        let mut ppu = Ppu::new(Model::Dmg, false);
+        ppu.write_register(LCDC_ADDRESS, LCDC_DEFAULT);
+        ppu.write_register(BGP_ADDRESS, 0xE4);
+        ppu.write_register(OBP0_ADDRESS, 0xE4);
+        ppu.write_register(OBP1_ADDRESS, 0x1B);
+        ppu
+    }
+
+    fn place_sprite(ppu: &mut Ppu, index: usize, y: u8, x: u8, tile: u8, attributes: u8) {
+        for (offset, &value) in [y, x, tile, attributes].iter().enumerate() {
+            ppu.write_oam_dma(index * 4 + offset, value);
+        }
+    }
+
+    /* Run mode 3 of one line through the FIFO and return how many dots it took. */
+    fn fifo_line(ppu: &mut Ppu, line: u8) -> u32 {
+        ppu.ly = line;
+        ppu.start_line();
+        ppu.start_fifo_line();
+        let mut dots = 1;
+        while !ppu.fifo_dot() {
+            dots += 1;
+        }
+        ppu.finish_fifo_line();
+        dots
+    }
+
+    /* Tiles with a different pattern per row and a map, window and sprites that use them. */
+    fn busy_scene() -> Ppu {
+        let mut ppu = dmg_ppu();
+        for address in 0x8000..0x8400u16 {
+            ppu.write_vram(address, (address as u8).wrapping_mul(37) ^ (address >> 4) as u8);
+        }
+        for index in 0..0x800u16 {
+            ppu.write_vram(0x9800 + index, (index % 61) as u8);
+        }
+        ppu.write_register(SCX_ADDRESS, 13);
+        ppu.write_register(SCY_ADDRESS, 5);
+        ppu.write_register(WY_ADDRESS, 2);
+        ppu.write_register(WX_ADDRESS, 90);
+        ppu.write_register(LCDC_ADDRESS, LCDC_DEFAULT | LCDC_WINDOW_ENABLE);
+        place_sprite(&mut ppu, 0, 16, 20, 3, 0x00);
+        place_sprite(&mut ppu, 1, 18, 24, 5, 0x10);
+        place_sprite(&mut ppu, 2, 14, 90, 7, 0x20);
+        place_sprite(&mut ppu, 3, 20, 3, 9, 0x80);
+        ppu
+    }
+
+    #[test]
+    fn mode_3_grows_with_scx_and_sprites() {
+        let mut ppu = dmg_ppu();
+        let plain = fifo_line(&mut ppu, 0);
+        assert_eq!(plain, PIXEL_TRANSFER_DOTS);
+
+        ppu.write_register(SCX_ADDRESS, 3);
+        assert_eq!(fifo_line(&mut ppu, 0), plain + 3);
+
+        ppu.write_register(SCX_ADDRESS, 0);
+        place_sprite(&mut ppu, 0, 16, 40, 0, 0);
+        let aligned = fifo_line(&mut ppu, 0) - plain;
+        place_sprite(&mut ppu, 0, 16, 47, 0, 0);
+        let unaligned = fifo_line(&mut ppu, 0) - plain;
+        assert!(unaligned >= SPRITE_FETCH_DOTS as u32);
+        /* At the start of a tile the sprite also waits for the background fetch to finish. */
+        assert!(aligned > unaligned, "{} vs {}", aligned, unaligned);
+
+        place_sprite(&mut ppu, 1, 16, 87, 0, 0);
+        assert_eq!(fifo_line(&mut ppu, 0) - plain, 2 * unaligned);
+    }
+
+    #[test]
+    fn both_render_modes_draw_the_same_lines() {
+        let mut scanline = busy_scene();
+        let mut fifo = busy_scene();
+
+        for line in 0..8 {
+            scanline.ly = line;
+            scanline.start_line();
+            scanline.render_scanline();
+            fifo_line(&mut fifo, line);
+
+            let row = line as usize * SCREEN_WIDTH..(line as usize + 1) * SCREEN_WIDTH;
+            assert_eq!(scanline.framebuffer()[row.clone()], fifo.framebuffer()[row], "line {}", line);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+/* Eight palettes of four colours, two bytes each. */
+const PALETTE_RAM_SIZE: usize = 64;
+
+const SPEC_AUTO_INCREMENT: u8 = 1 << 7;
+const SPEC_INDEX_MASK: u8 = 0x3F;
+
+/*
+CGB palette memory behind one index/data register pair
+(BCPS/BCPD or OCPS/OCPD). Colours are stored as little endian
+RGB555; writing the data register can advance the index.
+*/
+pub struct PaletteRam {
+    data: [u8; PALETTE_RAM_SIZE],
+    index: u8,
+    auto_increment: bool,
+}
+
+impl PaletteRam {
+    pub fn new() -> PaletteRam {
+        PaletteRam {
+            data: [0xFF; PALETTE_RAM_SIZE],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    pub fn read_spec(&self) -> u8 {
+        let auto_increment = if self.auto_increment { SPEC_AUTO_INCREMENT } else { 0 };
+        0x40 | auto_increment | self.index
+    }
+
+    pub fn write_spec(&mut self, value: u8) {
+        self.index = value & SPEC_INDEX_MASK;
+        self.auto_increment = value & SPEC_AUTO_INCREMENT != 0;
+    }
+
+    /* Palette memory is unreachable while the PPU is drawing. */
+    pub fn read_data(&self, locked: bool) -> u8 {
+        if locked {
+            0xFF
+        } else {
+            self.data[self.index as usize]
+        }
+    }
+
+    /* A blocked write is dropped, but the index still advances. */
+    pub fn write_data(&mut self, value: u8, locked: bool) {
+        if !locked {
+            self.data[self.index as usize] = value;
+        }
+        if self.auto_increment {
+            self.index = (self.index + 1) & SPEC_INDEX_MASK;
+        }
+    }
+
+    /* Colour 0-3 of a palette as 0xRRGGBB, each 5 bit channel scaled up to 8 bits. */
+    pub fn color(&self, palette: u8, color: u8) -> u32 {
+        let offset = (palette as usize & 0x07) * 8 + color as usize * 2;
+        let rgb555 = u16::from_le_bytes([self.data[offset], self.data[offset + 1]]) as u32;
+        let channel = |shift: u32| {
+            let value = (rgb555 >> shift) & 0x1F;
+            (value << 3) | (value >> 2)
+        };
+        (channel(0) << 16) | (channel(5) << 8) | channel(10)
+    }
+
+    /* Load a palette from 0xRRGGBB colours, as the CGB boot ROM does for DMG games. */
+    pub fn set_colors(&mut self, palette: u8, colors: [u32; 4]) {
+        for (color, rgb) in colors.iter().enumerate() {
+            let channel = |shift: u32| (rgb >> shift) as u16 & 0xFF;
+            let rgb555 = (channel(16) >> 3) | ((channel(8) >> 3) << 5) | ((channel(0) >> 3) << 10);
+            let offset = (palette as usize & 0x07) * 8 + color * 2;
+            self.data[offset..offset + 2].copy_from_slice(&rgb555.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PaletteRam, SPEC_AUTO_INCREMENT};
+
+    #[test]
+    fn data_writes_advance_the_index_when_auto_increment_is_set() {
+        let mut palettes = PaletteRam::new();
+        palettes.write_spec(SPEC_AUTO_INCREMENT | 0x3E);
+        palettes.write_data(0x1F, false);
+        palettes.write_data(0x00, false);
+        assert_eq!(palettes.read_spec(), 0xC0, "the index wraps from 0x3F to 0");
+        palettes.write_data(0xE0, false);
+        palettes.write_data(0x03, false);
+
+        assert_eq!(palettes.color(7, 3), 0xFF0000);
+        assert_eq!(palettes.color(0, 0), 0x00FF00);
+
+        /* Reads never advance the index. */
+        palettes.write_spec(0x3F);
+        assert_eq!(palettes.read_data(false), 0x00);
+        assert_eq!(palettes.read_spec(), 0x7F);
+    }
+
+    #[test]
+    fn locked_writes_are_dropped_but_still_advance() {
+        let mut palettes = PaletteRam::new();
+        palettes.write_spec(SPEC_AUTO_INCREMENT);
+        palettes.write_data(0x12, true);
+        palettes.write_data(0x34, false);
+
+        palettes.write_spec(0x00);
+        assert_eq!(palettes.read_data(false), 0xFF);
+        assert_eq!(palettes.read_data(true), 0xFF);
+        palettes.write_spec(0x01);
+        assert_eq!(palettes.read_data(false), 0x34);
+
+        palettes.write_data(0x56, false);
+        assert_eq!(palettes.read_spec() & 0x3F, 0x01, "no auto increment without bit 7");
+    }
+}
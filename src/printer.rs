@@ -0,0 +1,399 @@
+use std::path::{Path, PathBuf};
+
+use crate::png::write_png;
+use crate::serial::SerialLink;
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+/* Answered in the first byte after the checksum to say a printer is attached. */
+const DEVICE_ID: u8 = 0x81;
+
+const STATUS_CHECKSUM_ERROR: u8 = 1 << 0;
+const STATUS_PRINTING: u8 = 1 << 1;
+const STATUS_IMAGE_FULL: u8 = 1 << 2;
+const STATUS_UNPROCESSED: u8 = 1 << 3;
+
+/* The printer's 8 KiB image buffer. */
+const BUFFER_SIZE: usize = 0x2000;
+
+/* Status inquiries answered with "printing" after a print command, so games see the job run. */
+const PRINTING_INQUIRIES: u8 = 4;
+
+const PRINT_WIDTH: usize = 160;
+const TILES_PER_ROW: usize = PRINT_WIDTH / 8;
+const BYTES_PER_TILE_ROW: usize = TILES_PER_ROW * 16;
+
+/* Paper fed per unit of margin, in pixel rows. */
+const MARGIN_ROWS: usize = 16;
+
+/* Printed shades from white to black. */
+const SHADES: [u32; 4] = [0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000];
+
+/* A palette byte of 0 is taken as the usual identity palette. */
+const DEFAULT_PALETTE: u8 = 0xE4;
+
+#[derive(Copy, Clone, PartialEq)]
+enum PacketState {
+    Magic,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    DeviceId,
+    Status,
+}
+
+/*
+A Game Boy Printer on the link port. The game clocks every byte;
+the printer answers 0x00 until the two bytes after a packet, where
+it sends its device ID and then its status.
+
+Prints are laid out top to bottom on a page, each preceded by its
+top margin. A print with a bottom margin feeds the paper out, and
+the page is saved as a PNG in the output directory; a page still
+on the printer is saved when it is dropped.
+*/
+pub struct Printer {
+    directory: PathBuf,
+    state: PacketState,
+    magic_index: usize,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    sum: u16,
+    checksum: u16,
+    status: u8,
+    printing_inquiries: u8,
+    buffer: Vec<u8>,
+    /* Shades of the page being printed, PRINT_WIDTH to a row. */
+    page: Vec<u8>,
+    pages_saved: usize,
+}
+
+impl Printer {
+    pub fn new<P: AsRef<Path>>(directory: P) -> Printer {
+        Printer {
+            directory: directory.as_ref().to_path_buf(),
+            state: PacketState::Magic,
+            magic_index: 0,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            sum: 0,
+            checksum: 0,
+            status: 0,
+            printing_inquiries: 0,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            page: Vec::new(),
+            pages_saved: 0,
+        }
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = self.status;
+        if self.printing_inquiries > 0 {
+            status |= STATUS_PRINTING | STATUS_IMAGE_FULL;
+        }
+        if !self.buffer.is_empty() {
+            status |= STATUS_UNPROCESSED;
+        }
+        status
+    }
+
+    fn receive(&mut self, byte: u8) {
+        /* The checksum covers everything from the command byte to the end of the data. */
+        if matches!(
+            self.state,
+            PacketState::Command | PacketState::Compression | PacketState::LengthLow | PacketState::LengthHigh | PacketState::Data
+        ) {
+            self.sum = self.sum.wrapping_add(byte as u16);
+        }
+
+        self.state = match self.state {
+            PacketState::Magic => {
+                if byte == MAGIC[self.magic_index] {
+                    self.magic_index += 1;
+                } else {
+                    self.magic_index = (byte == MAGIC[0]) as usize;
+                }
+                if self.magic_index < MAGIC.len() {
+                    PacketState::Magic
+                } else {
+                    self.magic_index = 0;
+                    self.sum = 0;
+                    self.data.clear();
+                    PacketState::Command
+                }
+            }
+            PacketState::Command => {
+                self.command = byte;
+                PacketState::Compression
+            }
+            PacketState::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                PacketState::LengthLow
+            }
+            PacketState::LengthLow => {
+                self.length = byte as u16;
+                PacketState::LengthHigh
+            }
+            PacketState::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                if self.length == 0 {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::Data => {
+                self.data.push(byte);
+                if self.data.len() < self.length as usize {
+                    PacketState::Data
+                } else {
+                    PacketState::ChecksumLow
+                }
+            }
+            PacketState::ChecksumLow => {
+                self.checksum = byte as u16;
+                PacketState::ChecksumHigh
+            }
+            PacketState::ChecksumHigh => {
+                self.checksum |= (byte as u16) << 8;
+                PacketState::DeviceId
+            }
+            PacketState::DeviceId => {
+                self.run_command();
+                PacketState::Status
+            }
+            PacketState::Status => PacketState::Magic,
+        };
+    }
+
+    /* Act on a complete packet, in time for the status byte that follows it. */
+    fn run_command(&mut self) {
+        if self.checksum != self.sum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.printing_inquiries = 0;
+            }
+            COMMAND_DATA => {
+                let data = if self.compressed { decompress(&self.data) } else { std::mem::take(&mut self.data) };
+                let room = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..data.len().min(room)]);
+            }
+            COMMAND_PRINT if self.data.len() >= 4 => {
+                let margins = self.data[1];
+                let palette = self.data[2];
+                self.print(margins >> 4, margins & 0x0F, palette);
+                self.printing_inquiries = PRINTING_INQUIRIES;
+            }
+            COMMAND_STATUS => {
+                self.printing_inquiries = self.printing_inquiries.saturating_sub(1);
+            }
+            _ => {}
+        }
+    }
+
+    /* Decode the buffered 2bpp tile rows onto the page through `palette`. */
+    fn print(&mut self, top_margin: u8, bottom_margin: u8, palette: u8) {
+        let palette = if palette == 0 { DEFAULT_PALETTE } else { palette };
+
+        self.feed(top_margin);
+        for tile_row in self.buffer.chunks_exact(BYTES_PER_TILE_ROW) {
+            for y in 0..8 {
+                for x in 0..PRINT_WIDTH {
+                    let tile = &tile_row[(x / 8) * 16..];
+                    let bit = 7 - (x % 8);
+                    let low = (tile[y * 2] >> bit) & 0x1;
+                    let high = (tile[y * 2 + 1] >> bit) & 0x1;
+                    let color = (high << 1) | low;
+                    self.page.push((palette >> (color * 2)) & 0x3);
+                }
+            }
+        }
+        self.buffer.clear();
+
+        if bottom_margin > 0 {
+            self.feed(bottom_margin);
+            self.save_page();
+        }
+    }
+
+    fn feed(&mut self, margin: u8) {
+        let rows = margin as usize * MARGIN_ROWS;
+        self.page.resize(self.page.len() + rows * PRINT_WIDTH, 0);
+    }
+
+    fn save_page(&mut self) {
+        if self.page.is_empty() {
+            return;
+        }
+
+        self.pages_saved += 1;
+        let path = self.directory.join(format!("print-{:03}.png", self.pages_saved));
+        let pixels: Vec<u32> = self.page.iter().map(|&shade| SHADES[shade as usize]).collect();
+        if let Err(err) = write_png(&path, PRINT_WIDTH, pixels.len() / PRINT_WIDTH, &pixels) {
+            eprintln!("Failed to write {}: {}", path.display(), err);
+        }
+        self.page.clear();
+    }
+}
+
+impl SerialLink for Printer {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        let response = match self.state {
+            PacketState::DeviceId => DEVICE_ID,
+            PacketState::Status => self.status(),
+            _ => 0x00,
+        };
+        self.receive(outgoing);
+        response
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.save_page();
+    }
+}
+
+/*
+Data packets may be run-length encoded: a control byte with bit 7
+set repeats the next byte (control & 0x7F) + 2 times, otherwise the
+next (control + 1) bytes are copied as they are.
+*/
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut index = 0;
+    while index < data.len() {
+        let control = data[index];
+        index += 1;
+        if control & 0x80 != 0 {
+            let count = (control & 0x7F) as usize + 2;
+            if let Some(&byte) = data.get(index) {
+                output.resize(output.len() + count, byte);
+            }
+            index += 1;
+        } else {
+            let end = (index + control as usize + 1).min(data.len());
+            output.extend_from_slice(&data[index..end]);
+            index = end;
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::{decompress, Printer, BUFFER_SIZE, BYTES_PER_TILE_ROW, DEVICE_ID, MARGIN_ROWS, PRINT_WIDTH};
+    use crate::png::read_png;
+    use crate::serial::SerialLink;
+
+    /* A whole packet with its checksum, plus the two bytes clocked to read the device ID and status. */
+    fn packet(command: u8, compressed: bool, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0x88, 0x33, command, compressed as u8];
+        bytes.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(data);
+        let sum = bytes[2..].iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        bytes.extend_from_slice(&sum.to_le_bytes());
+        bytes.extend_from_slice(&[0, 0]);
+        bytes
+    }
+
+    /* Clock `bytes` into the printer and return its last two answers: device ID and status. */
+    fn send(printer: &mut Printer, bytes: &[u8]) -> (u8, u8) {
+        let responses: Vec<u8> = bytes.iter().map(|&byte| printer.transfer(byte)).collect();
+        (responses[responses.len() - 2], responses[responses.len() - 1])
+    }
+
+    #[test]
+    fn answers_packets_with_device_id_and_status() {
+        let mut printer = Printer::new(env::temp_dir());
+
+        /* Noise before the magic bytes is skipped, including a lone first magic byte. */
+        let mut bytes = vec![0x12, 0x88, 0x88];
+        bytes.extend_from_slice(&packet(0x01, false, &[])[1..]);
+        assert_eq!(send(&mut printer, &bytes), (DEVICE_ID, 0x00));
+
+        assert_eq!(send(&mut printer, &packet(0x04, false, &[0x55; 0x280])), (DEVICE_ID, 0x08));
+        assert_eq!(printer.buffer.len(), 0x280);
+
+        assert_eq!(send(&mut printer, &packet(0x0F, false, &[])), (DEVICE_ID, 0x08));
+    }
+
+    #[test]
+    fn rejects_packets_with_a_bad_checksum() {
+        let mut printer = Printer::new(env::temp_dir());
+        let mut bytes = packet(0x04, false, &[0x55; 16]);
+        let checksum = bytes.len() - 4;
+        bytes[checksum] ^= 0x01;
+
+        assert_eq!(send(&mut printer, &bytes), (DEVICE_ID, 0x01));
+        assert!(printer.buffer.is_empty());
+
+        /* The next good packet clears the error. */
+        assert_eq!(send(&mut printer, &packet(0x0F, false, &[])), (DEVICE_ID, 0x00));
+    }
+
+    #[test]
+    fn decompresses_runs_and_literals() {
+        assert_eq!(decompress(&[0x02, 1, 2, 3, 0x81, 9, 0x00, 4]), [1, 2, 3, 9, 9, 9, 4]);
+        assert_eq!(decompress(&[0xFF, 7]), [7; 129]);
+        /* Truncated input keeps what was there. */
+        assert_eq!(decompress(&[0x03, 1, 2]), [1, 2]);
+
+        let mut printer = Printer::new(env::temp_dir());
+        send(&mut printer, &packet(0x04, true, &[0xFE, 0xAA, 0x01, 1, 2]));
+        assert_eq!(printer.buffer.len(), 130);
+        assert_eq!(printer.buffer[128..], [1, 2]);
+    }
+
+    #[test]
+    fn data_beyond_the_buffer_is_dropped() {
+        let mut printer = Printer::new(env::temp_dir());
+        for _ in 0..(BUFFER_SIZE / 0x280 + 1) {
+            send(&mut printer, &packet(0x04, false, &[0x55; 0x280]));
+        }
+        assert_eq!(printer.buffer.len(), BUFFER_SIZE);
+    }
+
+    #[test]
+    fn print_with_a_bottom_margin_saves_a_page() {
+        let directory = env::temp_dir().join(format!("printer-test-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let mut printer = Printer::new(&directory);
+
+        send(&mut printer, &packet(0x01, false, &[]));
+        send(&mut printer, &packet(0x04, false, &[0xFF; BYTES_PER_TILE_ROW]));
+        let (_, status) = send(&mut printer, &packet(0x02, false, &[0x01, 0x01, 0xE4, 0x40]));
+        assert_eq!(status & 0x06, 0x06, "printing and image full after a print");
+
+        let page = read_png(directory.join("print-001.png")).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(page.width, PRINT_WIDTH);
+        assert_eq!(page.height, 8 + MARGIN_ROWS);
+        assert!(page.pixels[..8 * PRINT_WIDTH].iter().all(|&pixel| pixel & 0xFFFFFF == 0));
+        assert!(page.pixels[8 * PRINT_WIDTH..].iter().all(|&pixel| pixel & 0xFFFFFF == 0xFFFFFF));
+    }
+}
@@ -0,0 +1,288 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub const SB_ADDRESS: u16 = 0xFF01;
+pub const SC_ADDRESS: u16 = 0xFF02;
+
+pub const SERIAL_INTERRUPT_BIT: u8 = 3;
+
+const SC_TRANSFER_START: u8 = 1 << 7;
+const SC_FAST_CLOCK: u8 = 1 << 1;
+const SC_INTERNAL_CLOCK: u8 = 1 << 0;
+
+/* M-cycles per bit with the internal clock: 8192 Hz, or 262144 Hz with the CGB fast clock. */
+const NORMAL_BIT_CYCLES: u32 = 128;
+const FAST_BIT_CYCLES: u32 = 4;
+
+/*
+Whatever is plugged into the link port. Bytes are exchanged
+whole: the side driving the clock calls `transfer` once its eight
+bits have been shifted, and the other side learns of the transfer
+through `poll_external`.
+*/
+pub trait SerialLink {
+    /* We drive the clock: send `outgoing` and return the partner's byte. */
+    fn transfer(&mut self, outgoing: u8) -> u8;
+
+    /*
+    The partner drives the clock. Called every M-cycle while a
+    transfer is waiting on it, with the byte we would send; returns
+    the partner's byte once it has clocked a whole one through.
+    */
+    fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+        let _ = outgoing;
+        None
+    }
+
+    /*
+    SC was written, which stops or restarts any transfer that was
+    waiting on the partner's clock. Drop the byte offered for it so
+    the partner cannot clock it in later.
+    */
+    fn cancel_external(&mut self) {}
+}
+
+/* Nothing attached: the input line floats high, and no external clock ever arrives. */
+pub struct DisconnectedLink;
+
+impl SerialLink for DisconnectedLink {
+    fn transfer(&mut self, _outgoing: u8) -> u8 {
+        0xFF
+    }
+}
+
+pub type SerialLog = Rc<RefCell<Vec<u8>>>;
+
+/*
+A disconnected partner that records every byte sent to it. Test
+ROMs such as Blargg's print their results this way.
+*/
+pub struct CaptureLink {
+    log: SerialLog,
+}
+
+impl CaptureLink {
+    pub fn new() -> CaptureLink {
+        CaptureLink {
+            log: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+
+    /* Handle to the captured bytes that stays usable after the link is handed to the emulator. */
+    pub fn log(&self) -> SerialLog {
+        Rc::clone(&self.log)
+    }
+}
+
+impl SerialLink for CaptureLink {
+    fn transfer(&mut self, outgoing: u8) -> u8 {
+        self.log.borrow_mut().push(outgoing);
+        0xFF
+    }
+}
+
+/*
+SB/SC. Setting SC bit 7 starts a transfer: with the internal clock
+it completes after eight bit periods, with the external clock it
+waits for the partner. Either way SB ends up holding the partner's
+byte and the serial interrupt is requested.
+*/
+pub struct Serial {
+    data: u8,
+    control: u8,
+    cgb_mode: bool,
+    cycles_left: u32,
+    link: Box<dyn SerialLink>,
+}
+
+impl Serial {
+    pub fn new(cgb_mode: bool) -> Serial {
+        Serial {
+            data: 0x00,
+            control: 0x00,
+            cgb_mode,
+            cycles_left: 0,
+            link: Box::new(DisconnectedLink),
+        }
+    }
+
+    pub fn set_link(&mut self, link: Box<dyn SerialLink>) {
+        self.link = link;
+    }
+
+    /* Only CGB mode has the fast clock bit; unused bits read as 1. */
+    fn control_mask(&self) -> u8 {
+        if self.cgb_mode {
+            SC_TRANSFER_START | SC_FAST_CLOCK | SC_INTERNAL_CLOCK
+        } else {
+            SC_TRANSFER_START | SC_INTERNAL_CLOCK
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            SB_ADDRESS => self.data,
+            SC_ADDRESS => self.control | !self.control_mask(),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            SB_ADDRESS => self.data = value,
+            SC_ADDRESS => {
+                self.link.cancel_external();
+                self.control = value & self.control_mask();
+                if self.control & SC_TRANSFER_START != 0 && self.control & SC_INTERNAL_CLOCK != 0 {
+                    let bit_cycles = if self.control & SC_FAST_CLOCK != 0 { FAST_BIT_CYCLES } else { NORMAL_BIT_CYCLES };
+                    self.cycles_left = bit_cycles * 8;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /* Advance one M-cycle. Returns true when a transfer completed and the serial interrupt should be requested. */
+    pub fn tick(&mut self) -> bool {
+        if self.control & SC_TRANSFER_START == 0 {
+            return false;
+        }
+
+        let incoming = if self.control & SC_INTERNAL_CLOCK != 0 {
+            self.cycles_left -= 1;
+            if self.cycles_left > 0 {
+                return false;
+            }
+            self.link.transfer(self.data)
+        } else {
+            match self.link.poll_external(self.data) {
+                Some(incoming) => incoming,
+                None => return false,
+            }
+        };
+
+        self.data = incoming;
+        self.control &= !SC_TRANSFER_START;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::{CaptureLink, Serial, SerialLink, SB_ADDRESS, SC_ADDRESS, SERIAL_INTERRUPT_BIT};
+    use crate::memory_bus::MemoryBus;
+    use crate::model::Model;
+
+    /* Ticks until the transfer in progress completes. */
+    fn ticks_to_complete(serial: &mut Serial) -> u32 {
+        let mut ticks = 1;
+        while !serial.tick() {
+            ticks += 1;
+            assert!(ticks <= 2048, "transfer never completed");
+        }
+        ticks
+    }
+
+    #[test]
+    fn internal_clock_sends_after_eight_bit_periods() {
+        let capture = CaptureLink::new();
+        let log = capture.log();
+        let mut serial = Serial::new(false);
+        serial.set_link(Box::new(capture));
+
+        serial.write(SB_ADDRESS, 0x42);
+        serial.write(SC_ADDRESS, 0x81);
+        assert_eq!(serial.read(SC_ADDRESS), 0xFF);
+        assert_eq!(ticks_to_complete(&mut serial), 8 * 128);
+        assert_eq!(*log.borrow(), [0x42]);
+        assert_eq!(serial.read(SB_ADDRESS), 0xFF);
+        assert_eq!(serial.read(SC_ADDRESS), 0x7F);
+        assert!(!serial.tick());
+    }
+
+    #[test]
+    fn fast_clock_only_exists_in_cgb_mode() {
+        let mut serial = Serial::new(true);
+        serial.write(SC_ADDRESS, 0x83);
+        assert_eq!(serial.read(SC_ADDRESS), 0xFF);
+        assert_eq!(ticks_to_complete(&mut serial), 8 * 4);
+
+        let mut serial = Serial::new(false);
+        serial.write(SC_ADDRESS, 0x83);
+        assert_eq!(serial.read(SC_ADDRESS), 0xFF, "bit 1 reads as 1 either way");
+        assert_eq!(ticks_to_complete(&mut serial), 8 * 128);
+    }
+
+    #[test]
+    fn completion_requests_the_serial_interrupt() {
+        let mut bus = MemoryBus::new(None, Model::Dmg);
+        bus.interrupt_flag = 0;
+        bus.write_byte(SC_ADDRESS, 0x81);
+        for _ in 0..8 * 128 - 1 {
+            bus.tick();
+        }
+        assert_eq!(bus.interrupt_flag & (1 << SERIAL_INTERRUPT_BIT), 0);
+        bus.tick();
+        assert_ne!(bus.interrupt_flag & (1 << SERIAL_INTERRUPT_BIT), 0);
+    }
+
+    /* A partner that clocks a byte in once it has been polled `delay` times. */
+    #[derive(Default)]
+    struct ScriptedPartner {
+        delay: u32,
+        polls: u32,
+        sent: Vec<u8>,
+        cancels: u32,
+    }
+
+    struct ScriptedLink(Rc<RefCell<ScriptedPartner>>);
+
+    impl SerialLink for ScriptedLink {
+        fn transfer(&mut self, _outgoing: u8) -> u8 {
+            panic!("the partner drives the clock");
+        }
+
+        fn poll_external(&mut self, outgoing: u8) -> Option<u8> {
+            let mut partner = self.0.borrow_mut();
+            partner.polls += 1;
+            if partner.polls < partner.delay {
+                return None;
+            }
+            partner.polls = 0;
+            partner.sent.push(outgoing);
+            Some(0xA5)
+        }
+
+        fn cancel_external(&mut self) {
+            self.0.borrow_mut().cancels += 1;
+        }
+    }
+
+    #[test]
+    fn external_clock_waits_for_the_partner() {
+        let partner = Rc::new(RefCell::new(ScriptedPartner {
+            delay: 10,
+            ..ScriptedPartner::default()
+        }));
+        let mut serial = Serial::new(false);
+        serial.set_link(Box::new(ScriptedLink(Rc::clone(&partner))));
+
+        /* Without SC bit 7 the partner is not even asked. */
+        serial.write(SB_ADDRESS, 0x3C);
+        serial.write(SC_ADDRESS, 0x00);
+        for _ in 0..20 {
+            assert!(!serial.tick());
+        }
+        assert_eq!(partner.borrow().polls, 0);
+
+        serial.write(SC_ADDRESS, 0x80);
+        assert_eq!(ticks_to_complete(&mut serial), 10);
+        assert_eq!(partner.borrow().sent, [0x3C]);
+        assert_eq!(partner.borrow().cancels, 2, "every SC write cancels");
+        assert_eq!(serial.read(SB_ADDRESS), 0xA5);
+        assert_eq!(serial.read(SC_ADDRESS) & 0x80, 0);
+    }
+}
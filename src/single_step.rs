@@ -0,0 +1,407 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::json::{self, Value};
+use crate::memory_bus::{Bus, INTERRUPT_ENABLE_ADDRESS, INTERRUPT_FLAG_ADDRESS};
+use crate::CPU::{FlagsRegister, Registers, CPU};
+
+const REGISTER_NAMES: [&str; 8] = ["a", "b", "c", "d", "e", "f", "h", "l"];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Access {
+    Read,
+    Write,
+}
+
+/* What the CPU did with the bus during one M-cycle that touched it. */
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct BusCycle {
+    address: u16,
+    value: u8,
+    access: Access,
+}
+
+fn describe(cycle: Option<BusCycle>) -> String {
+    match cycle {
+        Some(BusCycle {
+            address,
+            value,
+            access: Access::Read,
+        }) => format!("read 0x{:02X} from 0x{:04X}", value, address),
+        Some(BusCycle {
+            address,
+            value,
+            access: Access::Write,
+        }) => format!("write 0x{:02X} to 0x{:04X}", value, address),
+        None => "no access".to_string(),
+    }
+}
+
+/*
+64 KiB of plain RAM with nothing mapped into it, as the tests
+assume. Every M-cycle is logged, with the access made in it if any.
+*/
+struct FlatBus {
+    memory: Vec<u8>,
+    cycles: Vec<Option<BusCycle>>,
+}
+
+impl FlatBus {
+    fn log(&mut self, address: u16, value: u8, access: Access) {
+        if let Some(cycle) = self.cycles.last_mut() {
+            *cycle = Some(BusCycle { address, value, access });
+        }
+    }
+}
+
+impl Bus for FlatBus {
+    fn tick(&mut self) {
+        self.cycles.push(None);
+    }
+
+    fn read_byte(&mut self, address: u16) -> u8 {
+        let value = self.memory[address as usize];
+        self.log(address, value, Access::Read);
+        value
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+        self.log(address, value, Access::Write);
+    }
+
+    fn peek_byte(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.memory[INTERRUPT_ENABLE_ADDRESS as usize] & self.memory[INTERRUPT_FLAG_ADDRESS as usize] & 0x1F
+    }
+
+    fn acknowledge_interrupt(&mut self, bit: u8) {
+        self.memory[INTERRUPT_FLAG_ADDRESS as usize] &= !(1 << bit);
+    }
+
+    fn stop(&mut self) -> bool {
+        false
+    }
+
+    fn wakes_from_stop(&self) -> bool {
+        true
+    }
+}
+
+/* The CPU and memory state before or after a test. */
+struct State {
+    registers: [u8; 8],
+    pc: u16,
+    sp: u16,
+    ime: Option<bool>,
+    ie: Option<u8>,
+    ram: Vec<(u16, u8)>,
+}
+
+struct TestCase {
+    name: String,
+    initial: State,
+    expected: State,
+    cycles: Vec<Option<BusCycle>>,
+}
+
+fn field(value: &Value, key: &str, max: u64) -> Result<u64, String> {
+    value
+        .get(key)
+        .and_then(Value::as_u64)
+        .filter(|&number| number <= max)
+        .ok_or_else(|| format!("missing or invalid '{}'", key))
+}
+
+fn optional_field(value: &Value, key: &str, max: u64) -> Result<Option<u64>, String> {
+    match value.get(key) {
+        Some(_) => field(value, key, max).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn parse_state(value: &Value) -> Result<State, String> {
+    let mut registers = [0; 8];
+    for (register, name) in registers.iter_mut().zip(REGISTER_NAMES) {
+        *register = field(value, name, 0xFF)? as u8;
+    }
+
+    let ram = value
+        .get("ram")
+        .and_then(Value::as_array)
+        .ok_or("missing 'ram'")?
+        .iter()
+        .map(|entry| match entry.as_array() {
+            Some([address, byte]) => match (address.as_u64(), byte.as_u64()) {
+                (Some(address), Some(byte)) if address <= 0xFFFF && byte <= 0xFF => Ok((address as u16, byte as u8)),
+                _ => Err("invalid 'ram' entry".to_string()),
+            },
+            _ => Err("invalid 'ram' entry".to_string()),
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Ok(State {
+        registers,
+        pc: field(value, "pc", 0xFFFF)? as u16,
+        sp: field(value, "sp", 0xFFFF)? as u16,
+        ime: optional_field(value, "ime", 1)?.map(|ime| ime != 0),
+        ie: optional_field(value, "ie", 0xFF)?.map(|ie| ie as u8),
+        ram,
+    })
+}
+
+/* Entries are [address, value, activity] or null; activity is e.g. "r-m" for a read and "-wm" for a write. */
+fn parse_cycle(value: &Value) -> Result<Option<BusCycle>, String> {
+    let entry = match value {
+        Value::Null => return Ok(None),
+        Value::Array(entry) if entry.len() == 3 => entry,
+        _ => return Err("invalid 'cycles' entry".to_string()),
+    };
+
+    let activity = entry[2].as_str().unwrap_or("");
+    let access = if activity.contains('w') {
+        Access::Write
+    } else if activity.contains('r') {
+        Access::Read
+    } else {
+        return Ok(None);
+    };
+
+    match (entry[0].as_u64(), entry[1].as_u64()) {
+        (Some(address), Some(byte)) if address <= 0xFFFF && byte <= 0xFF => Ok(Some(BusCycle {
+            address: address as u16,
+            value: byte as u8,
+            access,
+        })),
+        _ => Err("invalid 'cycles' entry".to_string()),
+    }
+}
+
+fn parse_case(value: &Value) -> Result<TestCase, String> {
+    let name = value.get("name").and_then(Value::as_str).unwrap_or("unnamed").to_string();
+    let context = |err: String| format!("case '{}': {}", name, err);
+
+    let initial = parse_state(value.get("initial").ok_or("missing 'initial'")?).map_err(context)?;
+    let expected = parse_state(value.get("final").ok_or("missing 'final'")?).map_err(context)?;
+    let cycles = value
+        .get("cycles")
+        .and_then(Value::as_array)
+        .ok_or_else(|| context("missing 'cycles'".to_string()))?
+        .iter()
+        .map(parse_cycle)
+        .collect::<Result<Vec<_>, String>>()
+        .map_err(context)?;
+
+    Ok(TestCase {
+        name,
+        initial,
+        expected,
+        cycles,
+    })
+}
+
+/* Set up the initial state, run one step and describe the first difference from the final state. */
+fn run_case(case: &TestCase) -> Result<(), String> {
+    let mut bus = FlatBus {
+        memory: vec![0; 0x10000],
+        cycles: Vec::new(),
+    };
+    if let Some(ie) = case.initial.ie {
+        bus.memory[INTERRUPT_ENABLE_ADDRESS as usize] = ie;
+    }
+    for &(address, value) in &case.initial.ram {
+        bus.memory[address as usize] = value;
+    }
+
+    let [a, b, c, d, e, f, h, l] = case.initial.registers;
+    let registers = Registers {
+        a,
+        b,
+        c,
+        d,
+        e,
+        f: FlagsRegister::from(f),
+        h,
+        l,
+    };
+    let mut cpu = CPU::with_state(bus, registers, case.initial.pc, case.initial.sp);
+    cpu.set_ime(case.initial.ime.unwrap_or(false));
+
+    cpu.step();
+
+    let registers = &cpu.registers;
+    let actual = [
+        registers.a,
+        registers.b,
+        registers.c,
+        registers.d,
+        registers.e,
+        u8::from(registers.f),
+        registers.h,
+        registers.l,
+    ];
+    for ((name, actual), expected) in REGISTER_NAMES.iter().zip(actual).zip(case.expected.registers) {
+        if actual != expected {
+            return Err(format!("{} is 0x{:02X}, expected 0x{:02X}", name.to_uppercase(), actual, expected));
+        }
+    }
+    if cpu.pc != case.expected.pc {
+        return Err(format!("PC is 0x{:04X}, expected 0x{:04X}", cpu.pc, case.expected.pc));
+    }
+    if cpu.sp != case.expected.sp {
+        return Err(format!("SP is 0x{:04X}, expected 0x{:04X}", cpu.sp, case.expected.sp));
+    }
+    if let Some(ime) = case.expected.ime {
+        if cpu.ime() != ime {
+            return Err(format!("IME is {}, expected {}", cpu.ime() as u8, ime as u8));
+        }
+    }
+    if let Some(ie) = case.expected.ie {
+        let actual = cpu.bus.memory[INTERRUPT_ENABLE_ADDRESS as usize];
+        if actual != ie {
+            return Err(format!("IE is 0x{:02X}, expected 0x{:02X}", actual, ie));
+        }
+    }
+
+    for &(address, expected) in &case.expected.ram {
+        let actual = cpu.bus.memory[address as usize];
+        if actual != expected {
+            return Err(format!("memory at 0x{:04X} is 0x{:02X}, expected 0x{:02X}", address, actual, expected));
+        }
+    }
+
+    for (index, (&actual, &expected)) in cpu.bus.cycles.iter().zip(&case.cycles).enumerate() {
+        if actual != expected {
+            return Err(format!("M-cycle {}: {}, expected {}", index + 1, describe(actual), describe(expected)));
+        }
+    }
+    if cpu.bus.cycles.len() != case.cycles.len() {
+        return Err(format!("took {} M-cycles, expected {}", cpu.bus.cycles.len(), case.cycles.len()));
+    }
+    Ok(())
+}
+
+pub struct OpcodeResult {
+    /* The test file, named after its opcode, e.g. "cb 37.json". */
+    pub file: String,
+    pub cases: usize,
+    pub failed: usize,
+    /* The first failing case and how it differed, or why the file could not be run. */
+    pub first_failure: Option<String>,
+}
+
+impl OpcodeResult {
+    pub fn passed(&self) -> bool {
+        self.first_failure.is_none()
+    }
+}
+
+fn run_file(path: &Path) -> OpcodeResult {
+    let file = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut result = OpcodeResult {
+        file,
+        cases: 0,
+        failed: 0,
+        first_failure: None,
+    };
+
+    let cases = fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| json::parse(&text).map_err(|err| err.to_string()))
+        .and_then(|value| {
+            value
+                .as_array()
+                .ok_or_else(|| "expected an array of test cases".to_string())?
+                .iter()
+                .map(parse_case)
+                .collect::<Result<Vec<_>, String>>()
+        });
+    let cases = match cases {
+        Ok(cases) => cases,
+        Err(err) => {
+            result.first_failure = Some(err);
+            return result;
+        }
+    };
+
+    result.cases = cases.len();
+    for case in &cases {
+        if let Err(mismatch) = run_case(case) {
+            result.failed += 1;
+            if result.first_failure.is_none() {
+                result.first_failure = Some(format!("'{}': {}", case.name, mismatch));
+            }
+        }
+    }
+    result
+}
+
+/* Run every .json file in `directory`, one opcode per file. */
+pub fn run_directory(directory: &Path) -> io::Result<Vec<OpcodeResult>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(directory)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<_>>()?;
+    files.retain(|path| path.extension().is_some_and(|extension| extension == "json"));
+    files.sort();
+
+    Ok(files.iter().map(|path| run_file(path)).collect())
+}
+
+/* One row per opcode with its first mismatch, plus totals. */
+pub fn format_report(results: &[OpcodeResult]) -> String {
+    let file_width = results.iter().map(|result| result.file.len()).max().unwrap_or(0).max("FILE".len());
+
+    let mut report = format!("{:<6}  {:<width$}  {:>5}  FIRST MISMATCH\n", "RESULT", "FILE", "CASES", width = file_width);
+    for result in results {
+        let detail = match &result.first_failure {
+            Some(failure) if result.failed > 0 => format!("{} failed, first {}", result.failed, failure),
+            Some(failure) => failure.clone(),
+            None => String::new(),
+        };
+        let row = format!(
+            "{:<6}  {:<width$}  {:>5}  {}",
+            if result.passed() { "pass" } else { "FAIL" },
+            result.file,
+            result.cases,
+            detail,
+            width = file_width,
+        );
+        report += row.trim_end();
+        report.push('\n');
+    }
+
+    let passed = results.iter().filter(|result| result.passed()).count();
+    report += &format!("{} of {} opcodes passed", passed, results.len());
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+
+    use super::{format_report, run_directory};
+
+    /*
+    The JSON tests are not distributed with the emulator. Point
+    SM83_TESTS at a local copy of the directory of per-opcode files,
+    or put one in ./sm83-tests; without either there is nothing to run.
+    */
+    #[test]
+    fn sm83_single_step() {
+        let directory = env::var_os("SM83_TESTS").map(PathBuf::from).unwrap_or_else(|| PathBuf::from("sm83-tests"));
+        if !directory.is_dir() {
+            eprintln!("no SM83 tests at {}, skipping", directory.display());
+            return;
+        }
+
+        let results = run_directory(&directory).expect("failed to read SM83 test directory");
+        let report = format_report(&results);
+        println!("{}", report);
+        assert!(results.iter().all(|result| result.passed()), "SM83 tests failed:\n{}", report);
+    }
+}
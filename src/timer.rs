@@ -0,0 +1,225 @@
+pub const DIV_ADDRESS: u16 = 0xFF04;
+pub const TIMA_ADDRESS: u16 = 0xFF05;
+pub const TMA_ADDRESS: u16 = 0xFF06;
+pub const TAC_ADDRESS: u16 = 0xFF07;
+
+pub const TIMER_INTERRUPT_BIT: u8 = 2;
+
+/* Bit of the internal divider watched by TIMA for each TAC clock select. */
+const TAC_DIVIDER_BITS: [u16; 4] = [9, 3, 5, 7];
+
+/*
+DIV/TIMA/TMA/TAC. The divider is a free running 16 bit counter
+advanced every T-cycle; DIV is its upper byte. TIMA counts falling
+edges of (selected divider bit AND timer enable), so anything that
+drops that signal - resetting DIV, changing TAC - can tick TIMA.
+*/
+pub struct Timer {
+    divider: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    /* Set for the M-cycle after TIMA overflowed, before TMA is reloaded. */
+    overflow_pending: bool,
+    /* Set for the M-cycle in which TIMA was just reloaded from TMA. */
+    reloading: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            divider: 0xABCC,
+            tima: 0x00,
+            tma: 0x00,
+            tac: 0xF8,
+            overflow_pending: false,
+            reloading: false,
+        }
+    }
+
+    /* The full 16 bit internal counter; the APU's frame sequencer also runs off it. */
+    pub fn divider(&self) -> u16 {
+        self.divider
+    }
+
+    fn timer_signal(&self, divider: u16) -> bool {
+        let bit = TAC_DIVIDER_BITS[(self.tac & 0x03) as usize];
+        self.tac & 0x04 != 0 && (divider >> bit) & 0x1 != 0
+    }
+
+    fn increment_tima(&mut self) {
+        let (new_value, did_overflow) = self.tima.overflowing_add(1);
+        self.tima = new_value;
+        if did_overflow {
+            self.overflow_pending = true;
+        }
+    }
+
+    /*
+    Advance one M-cycle. Returns true when the timer interrupt
+    should be requested. After an overflow TIMA reads 0x00 for one
+    M-cycle before it is reloaded from TMA and the interrupt fires.
+    */
+    pub fn tick(&mut self) -> bool {
+        self.reloading = false;
+        let mut interrupt = false;
+
+        if self.overflow_pending {
+            self.overflow_pending = false;
+            self.tima = self.tma;
+            self.reloading = true;
+            interrupt = true;
+        }
+
+        let previous = self.timer_signal(self.divider);
+        self.divider = self.divider.wrapping_add(4);
+        if previous && !self.timer_signal(self.divider) {
+            self.increment_tima();
+        }
+
+        interrupt
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            DIV_ADDRESS => (self.divider >> 8) as u8,
+            TIMA_ADDRESS => self.tima,
+            TMA_ADDRESS => self.tma,
+            TAC_ADDRESS => self.tac | 0xF8,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            DIV_ADDRESS => {
+                let previous = self.timer_signal(self.divider);
+                self.divider = 0;
+                if previous {
+                    self.increment_tima();
+                }
+            }
+            /* A write during the delay cancels the reload; a write during the reload is ignored. */
+            TIMA_ADDRESS if !self.reloading => {
+                self.tima = value;
+                self.overflow_pending = false;
+            }
+            TMA_ADDRESS => {
+                self.tma = value;
+                /* TIMA is still latching TMA in the reload cycle, so it sees the new value. */
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
+            TAC_ADDRESS => {
+                let previous = self.timer_signal(self.divider);
+                self.tac = value;
+                if previous && !self.timer_signal(self.divider) {
+                    self.increment_tima();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Timer, DIV_ADDRESS, TAC_ADDRESS, TIMA_ADDRESS, TMA_ADDRESS};
+
+    /* A timer with a zeroed divider, counting every 4 M-cycles (divider bit 3). */
+    fn fast_timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.write(TAC_ADDRESS, 0x05);
+        timer.write(DIV_ADDRESS, 0);
+        timer.write(TIMA_ADDRESS, 0);
+        timer
+    }
+
+    /* Tick until TIMA has just overflowed and reads 0x00 while the reload is pending. */
+    fn overflowed_timer() -> Timer {
+        let mut timer = fast_timer();
+        timer.write(TMA_ADDRESS, 0x80);
+        timer.write(TIMA_ADDRESS, 0xFF);
+        for _ in 0..4 {
+            assert!(!timer.tick());
+        }
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x00);
+        timer
+    }
+
+    #[test]
+    fn tima_counts_falling_edges_of_the_selected_bit() {
+        let mut timer = fast_timer();
+        for _ in 0..3 {
+            timer.tick();
+        }
+        assert_eq!(timer.read(TIMA_ADDRESS), 0);
+        timer.tick();
+        assert_eq!(timer.read(TIMA_ADDRESS), 1);
+    }
+
+    #[test]
+    fn div_write_on_a_high_bit_increments_tima() {
+        let mut timer = fast_timer();
+        timer.tick();
+        timer.write(DIV_ADDRESS, 0);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0, "bit 3 was still low");
+
+        timer.tick();
+        timer.tick();
+        timer.write(DIV_ADDRESS, 0);
+        assert_eq!(timer.read(TIMA_ADDRESS), 1);
+        assert_eq!(timer.read(DIV_ADDRESS), 0);
+    }
+
+    #[test]
+    fn tac_change_that_drops_the_signal_increments_tima() {
+        let mut timer = fast_timer();
+        timer.tick();
+        timer.tick();
+
+        /* Selecting divider bit 9, which is low, is a falling edge. */
+        timer.write(TAC_ADDRESS, 0x04);
+        assert_eq!(timer.read(TIMA_ADDRESS), 1);
+
+        /* So is disabling the timer while the selected bit is high. */
+        timer.write(TAC_ADDRESS, 0x05);
+        timer.write(TAC_ADDRESS, 0x01);
+        assert_eq!(timer.read(TIMA_ADDRESS), 2);
+
+        /* Enabling it never is. */
+        timer.write(TAC_ADDRESS, 0x05);
+        assert_eq!(timer.read(TIMA_ADDRESS), 2);
+    }
+
+    #[test]
+    fn overflow_reloads_tma_one_cycle_late() {
+        let mut timer = overflowed_timer();
+        assert!(timer.tick());
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x80);
+        assert!(!timer.tick());
+    }
+
+    #[test]
+    fn tima_write_during_the_delay_cancels_the_reload() {
+        let mut timer = overflowed_timer();
+        timer.write(TIMA_ADDRESS, 0x42);
+        assert!(!timer.tick());
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x42);
+    }
+
+    #[test]
+    fn writes_during_the_reload_cycle() {
+        let mut timer = overflowed_timer();
+        assert!(timer.tick());
+
+        /* TIMA writes are ignored while TMA is being latched... */
+        timer.write(TIMA_ADDRESS, 0x42);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x80);
+
+        /* ...and TMA writes go straight through to TIMA. */
+        timer.write(TMA_ADDRESS, 0x90);
+        assert_eq!(timer.read(TIMA_ADDRESS), 0x90);
+    }
+}
@@ -0,0 +1,198 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::apu::AudioSink;
+
+const HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const BYTES_PER_FRAME: u32 = (CHANNELS * BITS_PER_SAMPLE / 8) as u32;
+
+/*
+Audio sink that records 16 bit stereo PCM to a .wav file, or to
+anything else that can seek back to patch the header. The
+header's sizes are filled in by `flush`, which also runs on drop.
+Write errors are held until the next flush reports them.
+*/
+pub struct WavWriter<W: Write + Seek = BufWriter<File>> {
+    writer: W,
+    sample_rate: u32,
+    frames: u32,
+    error: Option<io::Error>,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<WavWriter> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(writer: W, sample_rate: u32) -> io::Result<WavWriter<W>> {
+        let mut wav = WavWriter {
+            writer,
+            sample_rate,
+            frames: 0,
+            error: None,
+        };
+        wav.write_header()?;
+        Ok(wav)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let data_size = self.frames * BYTES_PER_FRAME;
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_SIZE - 8 + data_size).to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&CHANNELS.to_le_bytes());
+        header.extend_from_slice(&self.sample_rate.to_le_bytes());
+        header.extend_from_slice(&(self.sample_rate * BYTES_PER_FRAME).to_le_bytes());
+        header.extend_from_slice(&(BYTES_PER_FRAME as u16).to_le_bytes());
+        header.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&data_size.to_le_bytes());
+
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavWriter<W> {
+    fn push_sample(&mut self, left: f32, right: f32) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let mut frame = [0u8; BYTES_PER_FRAME as usize];
+        for (bytes, sample) in frame.chunks_mut(2).zip([left, right]) {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            bytes.copy_from_slice(&value.to_le_bytes());
+        }
+
+        match self.writer.write_all(&frame) {
+            Ok(()) => self.frames += 1,
+            Err(err) => self.error = Some(err),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.write_header()?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            eprintln!("Failed to write WAV file: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Cursor, Seek, SeekFrom, Write};
+    use std::rc::Rc;
+
+    use super::WavWriter;
+    use crate::apu::{Apu, AudioSink, Channel, APU_SAMPLE_RATE};
+
+    /* An in-memory file the test can still read after handing it to the APU. */
+    #[derive(Clone)]
+    struct SharedBuffer(Rc<RefCell<Cursor<Vec<u8>>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for SharedBuffer {
+        fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+            self.0.borrow_mut().seek(position)
+        }
+    }
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn header_describes_the_samples_written() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44_100).unwrap();
+        wav.push_sample(1.0, -1.0);
+        wav.push_sample(0.0, 0.0);
+        wav.push_sample(0.5, 2.0);
+        wav.flush().unwrap();
+        let bytes = wav.writer.get_ref().clone();
+
+        assert_eq!(bytes.len(), 44 + 12);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), 36 + 12);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&bytes, 16), 16);
+        assert_eq!(u16_at(&bytes, 20), 1, "PCM");
+        assert_eq!(u16_at(&bytes, 22), 2, "stereo");
+        assert_eq!(u32_at(&bytes, 24), 44_100);
+        assert_eq!(u32_at(&bytes, 28), 44_100 * 4);
+        assert_eq!(u16_at(&bytes, 32), 4);
+        assert_eq!(u16_at(&bytes, 34), 16);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 12);
+
+        let samples: Vec<i16> = bytes[44..].chunks(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+        assert_eq!(samples, [i16::MAX, -i16::MAX, 0, 0, i16::MAX / 2, i16::MAX]);
+    }
+
+    /* Record a short burst of square channel 1 from the APU's mix and return the PCM data. */
+    fn record_square_1(configure: impl Fn(&mut Apu)) -> Vec<u8> {
+        let buffer = SharedBuffer(Rc::new(RefCell::new(Cursor::new(Vec::new()))));
+        let mut apu = Apu::new();
+        apu.set_sink(Box::new(WavWriter::new(buffer.clone(), APU_SAMPLE_RATE).unwrap()));
+        configure(&mut apu);
+
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF13, 0x00);
+        apu.write(0xFF14, 0x87);
+        apu.tick(8192, false);
+        apu.flush_sinks().unwrap();
+
+        let bytes = buffer.0.borrow().get_ref().clone();
+        assert_eq!(u32_at(&bytes, 40), 2048 * 4);
+        bytes[44..].to_vec()
+    }
+
+    fn is_silent(data: &[u8]) -> bool {
+        data.iter().all(|&byte| byte == 0)
+    }
+
+    #[test]
+    fn solo_and_mute_decide_what_reaches_the_mix() {
+        assert!(!is_silent(&record_square_1(|_| {})));
+        assert!(is_silent(&record_square_1(|apu| apu.set_solo(Some(Channel::Square2)))));
+        assert!(is_silent(&record_square_1(|apu| apu.set_muted(Channel::Square1, true))));
+        assert!(!is_silent(&record_square_1(|apu| {
+            apu.set_muted(Channel::Square1, true);
+            apu.set_solo(Some(Channel::Square1));
+        })));
+    }
+}